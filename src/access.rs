@@ -0,0 +1,48 @@
+//! Per-handle access checks for the IOCTLs user mode clients send.
+//!
+//! Monitoring (reading strokes, attributes, statistics and logs) needs a handle opened for reading,
+//! injection (writing strokes, swallowing them through capture filters, changing driver settings)
+//! one opened for writing.
+
+use wdk_sys::{ACCESS_MASK, FILE_READ_DATA, FILE_WRITE_DATA, STATUS_ACCESS_DENIED, STATUS_SUCCESS, WDFDEVICE, WDFFILEOBJECT, WDFREQUEST};
+use crate::{FileContext, log_debug};
+use crate::control::on_file_cleanup;
+use crate::framework::{Error, ErrorCode, FileObject, FileObjectConfig, Request, Result};
+
+pub const MONITOR_ACCESS: ACCESS_MASK = FILE_READ_DATA;
+pub const INJECT_ACCESS: ACCESS_MASK = FILE_WRITE_DATA;
+
+/// File object configuration recording the access each handle was opened with, and dropping the
+/// filters a handle set once it's closed.
+pub(crate) fn file_object_config() -> FileObjectConfig {
+    let mut config = FileObjectConfig::new(Some(on_file_create));
+    config
+        .with_context::<FileContext>()
+        .with_cleanup(Some(on_file_cleanup));
+    config
+}
+
+extern "C" fn on_file_create(_device: WDFDEVICE, request: WDFREQUEST, file_object: WDFFILEOBJECT) {
+    let mut request = Request::new(unsafe { request.as_mut().expect("Request is null") });
+    let granted_access = request.granted_access();
+
+    if let Some(context) = FileObject::new(file_object).context_mut::<FileContext>() {
+        context.granted_access = granted_access;
+    }
+
+    log_debug!("Handle opened with access {granted_access:#X}");
+    request.complete(STATUS_SUCCESS);
+}
+
+/// Fails unless the handle the request was sent on was opened with all of `required`.
+pub(crate) fn check_access(request: &mut Request, required: ACCESS_MASK) -> Result<()> {
+    let granted_access = request.file_object()
+        .and_then(|file_object| file_object.context::<FileContext>().map(|context| context.granted_access))
+        .unwrap_or(0);
+
+    if granted_access & required == required {
+        Ok(())
+    } else {
+        Err(Error::from_nt_status(STATUS_ACCESS_DENIED, ErrorCode::AccessDenied))
+    }
+}
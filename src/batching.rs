@@ -0,0 +1,49 @@
+//! Limits on how many strokes a single `ControlRead` returns and a single `ControlWrite` injects.
+//!
+//! Reads take as many captured strokes as fit their output buffer, writes inject their records in
+//! order, and both stop at their limit so one request can't keep a processor at `DISPATCH_LEVEL`
+//! for long. The limits default to 64 and are replaced by the `MaxReadBatch` and `MaxWriteBatch`
+//! registry values, clamped to `1..=MAX_BATCH`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use nt_string::nt_unicode_str;
+use nt_string::unicode_string::NtUnicodeStr;
+use wdk_sys::WDFDRIVER;
+use crate::{log_debug, log_info};
+use crate::framework::registry::RegistryKey;
+
+pub const DEFAULT_BATCH: u32 = 64;
+pub const MAX_BATCH: u32 = 1024;
+
+static READ_LIMIT: AtomicU32 = AtomicU32::new(DEFAULT_BATCH);
+static WRITE_LIMIT: AtomicU32 = AtomicU32::new(DEFAULT_BATCH);
+
+/// Most records a `ControlRead` returns.
+pub fn read_limit() -> usize {
+    READ_LIMIT.load(Ordering::Relaxed) as usize
+}
+
+/// Most records of a `ControlWrite` injected.
+pub fn write_limit() -> usize {
+    WRITE_LIMIT.load(Ordering::Relaxed) as usize
+}
+
+/// Applies the `MaxReadBatch` and `MaxWriteBatch` values from the driver's `Parameters` key, if present.
+pub(crate) fn load_from_registry(driver: WDFDRIVER) {
+    let Ok(key) = RegistryKey::open_driver_parameters_for_read(driver) else {
+        log_debug!("No Parameters key, keeping the default batch limits");
+        return;
+    };
+
+    let limits: [(NtUnicodeStr, &AtomicU32); 2] = [
+        (nt_unicode_str!("MaxReadBatch"), &READ_LIMIT),
+        (nt_unicode_str!("MaxWriteBatch"), &WRITE_LIMIT),
+    ];
+    for (name, limit) in limits {
+        if let Ok(value) = key.query_u32(name) {
+            let value = value.clamp(1, MAX_BATCH);
+            limit.store(value, Ordering::Relaxed);
+            log_info!("{name} set to {value} from the registry");
+        }
+    }
+}
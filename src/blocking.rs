@@ -0,0 +1,78 @@
+//! The block rules applied to every keyboard, loaded from the `BlockRules` registry value at start
+//! and replaced through `ControlSetBlockRules`.
+//!
+//! They are enforced in `service_callback`, so they hold even while no client is running.
+
+use nt_string::nt_unicode_str;
+use wdk_sys::WDFDRIVER;
+use crate::{log_debug, log_error, log_info};
+use crate::framework::registry::RegistryKey;
+use crate::framework::spin_lock::SpinLock;
+use crate::framework::utils::read_from_buffer;
+use crate::keyboard::block::BlockRule;
+
+pub const MAX_BLOCK_RULES: usize = 32;
+
+#[derive(Debug, Copy, Clone)]
+pub struct BlockRules {
+    rules: [BlockRule; MAX_BLOCK_RULES],
+    len: usize,
+}
+
+impl BlockRules {
+    const fn new() -> Self {
+        Self {
+            rules: [BlockRule { make_code: 0, flags: 0, modifiers: 0, target: 0 }; MAX_BLOCK_RULES],
+            len: 0,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[BlockRule] {
+        &self.rules[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns `false`, leaving the rules unchanged, if there are more than [`MAX_BLOCK_RULES`].
+    pub fn set(&mut self, rules: &[BlockRule]) -> bool {
+        if rules.len() > MAX_BLOCK_RULES {
+            return false;
+        }
+
+        self.rules[..rules.len()].copy_from_slice(rules);
+        self.len = rules.len();
+        true
+    }
+}
+
+pub static BLOCK_RULES: SpinLock<BlockRules> = SpinLock::new(BlockRules::new());
+
+/// Applies the `BlockRules` `REG_BINARY` value from the driver's `Parameters` key, an array of [`BlockRule`], if present.
+pub(crate) fn load_from_registry(driver: WDFDRIVER) {
+    let mut buffer = [0u8; MAX_BLOCK_RULES * core::mem::size_of::<BlockRule>()];
+    let length = RegistryKey::open_driver_parameters_for_read(driver)
+        .and_then(|key| key.query_binary(nt_unicode_str!("BlockRules"), &mut buffer));
+
+    let length = match length {
+        Ok(length) if length % core::mem::size_of::<BlockRule>() == 0 => length,
+        Ok(_) => {
+            log_error!("Ignoring BlockRules registry value of invalid size");
+            return;
+        }
+        Err(_) => {
+            log_debug!("No BlockRules registry value, blocking nothing");
+            return;
+        }
+    };
+
+    let mut rules = [BlockRule::default(); MAX_BLOCK_RULES];
+    let count = length / core::mem::size_of::<BlockRule>();
+    for (i, rule) in rules[..count].iter_mut().enumerate() {
+        *rule = read_from_buffer(&buffer, i * core::mem::size_of::<BlockRule>()).unwrap_or_default();
+    }
+
+    BLOCK_RULES.lock().set(&rules[..count]);
+    log_info!("Loaded {count} block rules from the registry");
+}
@@ -0,0 +1,223 @@
+//! Strokes withheld from the class driver until a client reads them through the control device.
+
+use crate::foreign::{KEY_BREAK, KEY_E0, KEY_E1};
+use crate::framework::bounded_queue::{BoundedQueue, OverflowPolicy, Push};
+use crate::framework::spin_lock::{SpinLock, SpinLockGuard};
+use crate::protocol::{TimedStroke, FILTER_KEY_DOWN, FILTER_KEY_E0, FILTER_KEY_E1, FILTER_KEY_UP};
+
+pub const CAPTURE_CAPACITY: usize = 256;
+
+/// Whether a stroke with the given `KEY_*` flags is captured by an Interception style filter.
+pub fn filter_matches(filter: u16, flags: u16) -> bool {
+    let direction = if flags & KEY_BREAK == 0 { FILTER_KEY_DOWN } else { FILTER_KEY_UP };
+
+    filter & direction != 0
+        || (flags & KEY_E0 != 0 && filter & FILTER_KEY_E0 != 0)
+        || (flags & KEY_E1 != 0 && filter & FILTER_KEY_E1 != 0)
+}
+
+type Strokes = BoundedQueue<TimedStroke, CAPTURE_CAPACITY>;
+
+/// Captured strokes, pushed without locks from the service callback. Everything else locks out
+/// other readers first, as lookups peek at queued strokes, which the queue only allows while
+/// nothing pops.
+pub struct CaptureQueue {
+    strokes: Strokes,
+    reader: SpinLock<Reader>,
+}
+
+impl CaptureQueue {
+    const fn new() -> Self {
+        Self {
+            // Pushes never pop, so only the reader moves the tail
+            strokes: BoundedQueue::new(OverflowPolicy::PassThrough),
+            reader: SpinLock::new(Reader::new()),
+        }
+    }
+
+    /// Returns `false` when full; the caller should then pass the stroke through instead.
+    pub fn push(&self, stroke: TimedStroke) -> bool {
+        matches!(self.strokes.push(stroke), Push::Queued)
+    }
+
+    /// Locks out other readers. Pushes go on meanwhile.
+    pub fn lock(&self) -> CaptureReader<'_> {
+        CaptureReader { strokes: &self.strokes, reader: self.reader.lock() }
+    }
+}
+
+/// Strokes taken out of the middle of the queue, which can't be, so they are only marked and
+/// skipped once they reach the front.
+struct Reader {
+    /// Strokes popped so far, wrapping, so the stroke `index` places past the oldest sits in slot
+    /// `(popped + index) % CAPTURE_CAPACITY`.
+    popped: usize,
+    removed: [bool; CAPTURE_CAPACITY],
+}
+
+impl Reader {
+    const fn new() -> Self {
+        Self { popped: 0, removed: [false; CAPTURE_CAPACITY] }
+    }
+
+    const fn slot(&self, index: usize) -> usize {
+        self.popped.wrapping_add(index) % CAPTURE_CAPACITY
+    }
+
+    /// Strokes waiting, oldest first, along with how far from the oldest they are.
+    fn iter<'a>(&'a self, strokes: &'a Strokes) -> impl Iterator<Item = (usize, TimedStroke)> + 'a {
+        // Only the reader pops
+        (0..CAPTURE_CAPACITY)
+            .map_while(|index| unsafe { strokes.peek(index) }.map(|stroke| (index, stroke)))
+            .filter(|&(index, _)| !self.removed[self.slot(index)])
+    }
+
+    fn pop(&mut self, strokes: &Strokes) -> Option<TimedStroke> {
+        loop {
+            let stroke = strokes.pop()?;
+            let slot = self.slot(0);
+            self.popped = self.popped.wrapping_add(1);
+            if !core::mem::take(&mut self.removed[slot]) {
+                return Some(stroke);
+            }
+        }
+    }
+
+    /// Hands the room of removed strokes at the front back to pushes.
+    fn pop_removed(&mut self, strokes: &Strokes) {
+        while self.removed[self.slot(0)] && unsafe { strokes.peek(0) }.is_some() {
+            strokes.pop();
+            self.removed[self.slot(0)] = false;
+            self.popped = self.popped.wrapping_add(1);
+        }
+    }
+
+    fn oldest_timestamp(&self, strokes: &Strokes, device_id: u32) -> Option<u64> {
+        self.iter(strokes).find(|(_, stroke)| stroke.device == device_id).map(|(_, stroke)| stroke.timestamp)
+    }
+
+    fn pop_device(&mut self, strokes: &Strokes, device_id: u32) -> Option<TimedStroke> {
+        let (index, stroke) = self.iter(strokes).find(|(_, stroke)| stroke.device == device_id)?;
+        self.removed[self.slot(index)] = true;
+        self.pop_removed(strokes);
+        Some(stroke)
+    }
+
+    fn remove_device(&mut self, strokes: &Strokes, device_id: u32) {
+        for index in 0..CAPTURE_CAPACITY {
+            match unsafe { strokes.peek(index) } {
+                Some(stroke) if stroke.device == device_id => self.removed[self.slot(index)] = true,
+                Some(_) => {}
+                None => break,
+            }
+        }
+        self.pop_removed(strokes);
+    }
+}
+
+/// The captured strokes, locked for reading.
+pub struct CaptureReader<'a> {
+    strokes: &'a Strokes,
+    reader: SpinLockGuard<'a, Reader>,
+}
+
+impl CaptureReader<'_> {
+    pub fn pop(&mut self) -> Option<TimedStroke> {
+        self.reader.pop(self.strokes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reader.iter(self.strokes).next().is_none()
+    }
+
+    /// When the oldest stroke still waiting from `device_id` was captured.
+    pub fn oldest_timestamp(&self, device_id: u32) -> Option<u64> {
+        self.reader.oldest_timestamp(self.strokes, device_id)
+    }
+
+    /// Takes the oldest stroke captured on `device_id`, keeping the order of the others.
+    pub fn pop_device(&mut self, device_id: u32) -> Option<TimedStroke> {
+        self.reader.pop_device(self.strokes, device_id)
+    }
+
+    /// Drops every stroke captured on `device_id`, keeping the order of the others.
+    pub fn remove_device(&mut self, device_id: u32) {
+        self.reader.remove_device(self.strokes, device_id);
+    }
+}
+
+pub static CAPTURED: CaptureQueue = CaptureQueue::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stroke(device: u32, timestamp: u64) -> TimedStroke {
+        TimedStroke { timestamp, device, ..TimedStroke::EMPTY }
+    }
+
+    fn queue(strokes: &[(u32, u64)]) -> Strokes {
+        let queue = Strokes::new(OverflowPolicy::PassThrough);
+        for &(device, timestamp) in strokes {
+            assert!(matches!(queue.push(stroke(device, timestamp)), Push::Queued));
+        }
+        queue
+    }
+
+    fn drain(reader: &mut Reader, strokes: &Strokes) -> Vec<(u32, u64)> {
+        core::iter::from_fn(|| reader.pop(strokes)).map(|stroke| (stroke.device, stroke.timestamp)).collect()
+    }
+
+    #[test]
+    fn pop_device_keeps_the_order_of_the_others() {
+        let strokes = queue(&[(1, 10), (2, 20), (1, 30), (2, 40)]);
+        let mut reader = Reader::new();
+        assert_eq!(reader.oldest_timestamp(&strokes, 2), Some(20));
+        assert_eq!(reader.pop_device(&strokes, 2).map(|stroke| stroke.timestamp), Some(20));
+        assert_eq!(reader.oldest_timestamp(&strokes, 2), Some(40));
+        assert_eq!(reader.pop_device(&strokes, 3).map(|stroke| stroke.timestamp), None);
+        assert_eq!(drain(&mut reader, &strokes), [(1, 10), (1, 30), (2, 40)]);
+    }
+
+    #[test]
+    fn removed_strokes_at_the_front_make_room() {
+        let strokes = queue(&[(1, 10), (1, 20), (2, 30)]);
+        let mut reader = Reader::new();
+        reader.remove_device(&strokes, 1);
+        assert_eq!(strokes.len(), 1);
+        assert_eq!(reader.oldest_timestamp(&strokes, 1), None);
+        assert_eq!(drain(&mut reader, &strokes), [(2, 30)]);
+    }
+
+    #[test]
+    fn removed_strokes_in_the_middle_are_skipped() {
+        let strokes = queue(&[(1, 10), (2, 20), (2, 30), (1, 40)]);
+        let mut reader = Reader::new();
+        reader.remove_device(&strokes, 2);
+        assert!(reader.iter(&strokes).map(|(_, stroke)| stroke.timestamp).eq([10, 40]));
+        assert_eq!(drain(&mut reader, &strokes), [(1, 10), (1, 40)]);
+        // The marks went along with the strokes
+        assert!(!reader.removed.contains(&true));
+    }
+
+    #[test]
+    fn marks_follow_the_slots_around_the_ring() {
+        // Off by one from the start of the ring
+        let strokes = queue(&[(9, 0)]);
+        let mut reader = Reader::new();
+        assert!(reader.pop(&strokes).is_some());
+        for lap in 0..3 {
+            for timestamp in 0..CAPTURE_CAPACITY as u64 {
+                assert!(matches!(strokes.push(stroke((timestamp % 3) as u32, timestamp)), Push::Queued));
+            }
+            assert!(matches!(strokes.push(stroke(0, 0)), Push::Returned(_)));
+
+            reader.remove_device(&strokes, lap);
+            let expected: Vec<_> = (0..CAPTURE_CAPACITY as u64)
+                .map(|timestamp| ((timestamp % 3) as u32, timestamp))
+                .filter(|&(device, _)| device != lap)
+                .collect();
+            assert_eq!(drain(&mut reader, &strokes), expected);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! The capture ring of the shared rings, along with the driver's own copy of the strokes in it.
+//!
+//! The client may write anything over the ring, so the copy is what tells which keyboard each
+//! stroke the client hasn't consumed came from and when, for the watchdog, and what's handed to the
+//! class drivers when the rings are taken away from a client that stopped reading. Once the client
+//! garbles the ring's tail, every stroke still in the copy counts as unread.
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use crate::protocol::{SHARED_RING_CAPACITY, TimedStroke};
+use crate::ring::{self, Producer, RingError};
+
+#[derive(Debug)]
+pub struct CaptureRing {
+    producer: Producer<TimedStroke>,
+    /// Every stroke written, in its slot of the ring.
+    written: Vec<TimedStroke>,
+    /// Slots of `written` holding a stroke, up to the capacity.
+    filled: u32,
+}
+
+impl CaptureRing {
+    /// `None` when the copy can't be allocated.
+    ///
+    /// # Safety
+    /// Same as [`Producer::new`], for a ring of `SHARED_RING_CAPACITY` records.
+    pub unsafe fn new(memory: NonNull<u8>) -> Option<Self> {
+        let mut written = Vec::new();
+        written.try_reserve_exact(SHARED_RING_CAPACITY as usize).ok()?;
+        written.resize(SHARED_RING_CAPACITY as usize, TimedStroke::EMPTY);
+        let producer = unsafe { Producer::new(memory, SHARED_RING_CAPACITY) };
+        Some(Self { producer, written, filled: 0 })
+    }
+
+    pub fn push(&mut self, stroke: TimedStroke) -> Result<(), RingError> {
+        let head = self.producer.head();
+        self.producer.push(stroke)?;
+        self.written[ring::slot(head, SHARED_RING_CAPACITY)] = stroke;
+        self.filled = (self.filled + 1).min(SHARED_RING_CAPACITY);
+        Ok(())
+    }
+
+    /// The strokes the client hasn't consumed, oldest first.
+    pub fn unread(&self) -> impl Iterator<Item = &TimedStroke> {
+        let count = self.producer.unconsumed().unwrap_or(SHARED_RING_CAPACITY).min(self.filled);
+        let first = self.producer.head().wrapping_sub(count);
+        (0..count).map(move |index| &self.written[ring::slot(first.wrapping_add(index), SHARED_RING_CAPACITY)])
+    }
+
+    /// When the oldest stroke of `device_id` the client hasn't consumed was captured.
+    pub fn oldest_timestamp(&self, device_id: u32) -> Option<u64> {
+        self.unread().find(|stroke| stroke.device == device_id).map(|stroke| stroke.timestamp)
+    }
+
+    /// Strokes refused so far as the ring was full.
+    pub fn overflows(&self) -> u32 {
+        self.producer.overflows()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+    use core::sync::atomic::Ordering;
+    use crate::ring::{Consumer, RingHeader, ring_size};
+
+    const CAPACITY: usize = SHARED_RING_CAPACITY as usize;
+
+    /// Memory for the ring, aligned for the header and the strokes.
+    fn memory() -> Vec<u64> {
+        let mut words = vec![0; ring_size(SHARED_RING_CAPACITY, size_of::<TimedStroke>()).div_ceil(8)];
+        unsafe { ring::init::<TimedStroke>(base(&mut words), SHARED_RING_CAPACITY) };
+        words
+    }
+
+    fn base(words: &mut [u64]) -> NonNull<u8> {
+        NonNull::new(words.as_mut_ptr()).unwrap().cast()
+    }
+
+    /// The driver's side of the ring and the client's.
+    fn sides(words: &mut [u64]) -> (CaptureRing, Consumer<TimedStroke>) {
+        unsafe { (CaptureRing::new(base(words)).unwrap(), Consumer::new(base(words), SHARED_RING_CAPACITY)) }
+    }
+
+    fn stroke(device: u32, timestamp: u64) -> TimedStroke {
+        TimedStroke { timestamp, device, ..TimedStroke::EMPTY }
+    }
+
+    fn unread(ring: &CaptureRing) -> Vec<(u32, u64)> {
+        ring.unread().map(|stroke| (stroke.device, stroke.timestamp)).collect()
+    }
+
+    #[test]
+    fn a_stalled_consumer_leaves_its_oldest_unread_stroke_behind() {
+        let mut words = memory();
+        let (mut ring, mut client) = sides(&mut words);
+        ring.push(stroke(1, 10)).unwrap();
+        ring.push(stroke(2, 20)).unwrap();
+        ring.push(stroke(1, 30)).unwrap();
+        assert_eq!(client.pop().unwrap().timestamp, 10);
+        assert_eq!((ring.oldest_timestamp(1), ring.oldest_timestamp(2), ring.oldest_timestamp(3)), (Some(30), Some(20), None));
+
+        // The client stops reading, while the keyboards keep going until the ring is full
+        for timestamp in 0..CAPACITY as u64 {
+            let _ = ring.push(stroke(1, 40 + timestamp));
+        }
+        assert_eq!(ring.push(stroke(2, 1000)), Err(RingError::Full));
+        assert_eq!((ring.oldest_timestamp(1), ring.oldest_timestamp(2)), (Some(30), Some(20)));
+        assert_eq!(ring.overflows(), 3);
+
+        // What's forwarded once the rings are taken away, in the order it was captured
+        let expected: Vec<_> = [(2, 20), (1, 30)].into_iter()
+            .chain((40..).take(CAPACITY - 2).map(|timestamp| (1, timestamp)))
+            .collect();
+        assert_eq!(unread(&ring), expected);
+
+        // Catching up clears the backlog
+        while client.pop().is_ok() {}
+        assert_eq!((ring.oldest_timestamp(1), ring.oldest_timestamp(2)), (None, None));
+        assert!(unread(&ring).is_empty());
+    }
+
+    #[test]
+    fn unread_strokes_are_followed_across_the_wrap() {
+        let mut words = memory();
+        let (mut ring, mut client) = sides(&mut words);
+        for timestamp in 0..3 * CAPACITY as u64 / 2 {
+            ring.push(stroke(u32::from(timestamp % 2 == 0), timestamp)).unwrap();
+            if timestamp % 4 == 3 {
+                client.pop().unwrap();
+                client.pop().unwrap();
+            }
+        }
+
+        let first = 3 * CAPACITY as u64 / 4;
+        assert_eq!(unread(&ring), (first..3 * CAPACITY as u64 / 2).map(|timestamp| (u32::from(timestamp % 2 == 0), timestamp)).collect::<Vec<_>>());
+        assert_eq!((ring.oldest_timestamp(1), ring.oldest_timestamp(0)), (Some(first), Some(first + 1)));
+    }
+
+    #[test]
+    fn a_garbled_tail_leaves_every_stroke_unread() {
+        let mut words = memory();
+        let (mut ring, mut client) = sides(&mut words);
+        for timestamp in 0..3 {
+            ring.push(stroke(7, timestamp)).unwrap();
+        }
+        client.pop().unwrap();
+
+        let header = unsafe { base(&mut words).cast::<RingHeader>().as_ref() };
+        header.tail.store(1000, Ordering::Relaxed);
+        assert_eq!(unread(&ring), [(7, 0), (7, 1), (7, 2)]);
+        assert_eq!(ring.oldest_timestamp(7), Some(0));
+        assert_eq!(ring.push(stroke(7, 3)), Err(RingError::Corrupt));
+    }
+}
@@ -0,0 +1,744 @@
+//! The control device, a single well-known endpoint multiplexing every filtered keyboard.
+//!
+//! Clients list the attached keyboards, set a capture filter per keyboard, then read captured
+//! strokes tagged with the id of the keyboard they came from and write strokes back to any keyboard.
+//! Reads return as many strokes as fit and writes take arrays, both up to the batch limits.
+//! Filters are dropped when the handle that set them is closed.
+//! Handles may switch to timestamped reads through `SetReadFormat`, and `WriteText` types text
+//! on a keyboard using one of the built-in layouts. The block rules applied to every keyboard are
+//! read and replaced through `GetBlockRules` and `SetBlockRules`, the targets block rules and
+//! filters can be restricted to through `GetTargets` and `SetTargets`. `WaitNotification` requests
+//! stay pending until the driver has something to tell, such as the escape chord being typed.
+//! `GetCaptureTimeout` and `SetCaptureTimeout` read and replace how long a keyboard's captured
+//! strokes may wait to be read before the watchdog passes them through. `GetKeyState` returns the
+//! keys held on a keyboard, or on any keyboard, so clients starting mid-press know where they are.
+//! `MapRing` and `SubmitRing` trade captured and injected strokes through rings in shared memory
+//! instead, see [`crate::shared_ring`].
+
+use alloc::vec::Vec;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU16, Ordering};
+use nt_string::nt_unicode_str;
+use nt_string::unicode_string::NtUnicodeStr;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use wdk_sys::{*};
+use crate::access::{check_access, file_object_config, INJECT_ACCESS, MONITOR_ACCESS};
+use crate::batching;
+use crate::blocking::{BLOCK_RULES, MAX_BLOCK_RULES};
+use crate::{ControlContext, FileContext, log_debug, log_error, log_trace, log_warn, shared_ring};
+use crate::capture::CAPTURED;
+use crate::device::{inject_strokes, release_held_keys};
+use crate::foreign::KeyboardInputData;
+use crate::framework::{Device, Error, ErrorCode, Queue, QueueBuilder, Request, Result};
+use crate::framework::control_device::ControlDeviceBuilder;
+use crate::framework::security::default_sddl;
+use crate::framework::time;
+use crate::framework::timer::Timer;
+use crate::framework::utils::{ctl_code, read_from_buffer, write_to_buffer};
+use crate::instances::ATTACHED;
+use crate::keyboard::key_set::KeySet;
+use crate::keyboard::key_state::PressedKeys;
+use crate::keyboard::layout;
+use crate::keyboard::scan_code::ScanCode;
+use crate::keyboard::sequence::{Encoder, KeyEvent, RawStroke};
+use crate::keyboard::text::{compile, MAX_EVENTS_PER_CHARACTER};
+use crate::protocol::{
+    BlockRule, BlockRulesHeader, CaptureTimeout, DEVICE_ID_ALL, DeviceFilter, DeviceKeyState, DeviceListHeader, DeviceStroke, DeviceTarget, FILTER_KEY_NONE, MAX_TARGETS,
+    MAX_TEXT_LENGTH, Notification, ReadFormat, ReadFormatRequest, ReadFormatResponse, TargetsHeader, TextInjectionHeader,
+    WriteResponse,
+};
+use crate::targeting::{TARGET_FILTER_OWNERS, TARGET_FILTERS, TargetMatch, TARGETS};
+use crate::watchdog;
+
+const CONTROL_DEVICE_NAME: NtUnicodeStr<'static> = nt_unicode_str!("\\Device\\Interustception");
+const CONTROL_SYMBOLIC_LINK: NtUnicodeStr<'static> = nt_unicode_str!("\\DosDevices\\Interustception");
+
+static CONTROL_DEVICE: AtomicPtr<WDFDEVICE__> = AtomicPtr::new(null_mut());
+
+#[derive(Debug, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+enum ControlIoctl {
+    ListDevices = ctl_code(FILE_DEVICE_KEYBOARD, 0x900, METHOD_BUFFERED, FILE_READ_DATA),
+    GetFilter = ctl_code(FILE_DEVICE_KEYBOARD, 0x901, METHOD_BUFFERED, FILE_READ_DATA),
+    SetFilter = ctl_code(FILE_DEVICE_KEYBOARD, 0x902, METHOD_BUFFERED, FILE_READ_DATA | FILE_WRITE_DATA),
+    Read = ctl_code(FILE_DEVICE_KEYBOARD, 0x903, METHOD_BUFFERED, FILE_READ_DATA),
+    Write = ctl_code(FILE_DEVICE_KEYBOARD, 0x904, METHOD_BUFFERED, FILE_WRITE_DATA),
+    SetReadFormat = ctl_code(FILE_DEVICE_KEYBOARD, 0x905, METHOD_BUFFERED, FILE_READ_DATA),
+    WriteText = ctl_code(FILE_DEVICE_KEYBOARD, 0x906, METHOD_BUFFERED, FILE_WRITE_DATA),
+    GetBlockRules = ctl_code(FILE_DEVICE_KEYBOARD, 0x907, METHOD_BUFFERED, FILE_READ_DATA),
+    SetBlockRules = ctl_code(FILE_DEVICE_KEYBOARD, 0x908, METHOD_BUFFERED, FILE_WRITE_DATA),
+    GetTargets = ctl_code(FILE_DEVICE_KEYBOARD, 0x909, METHOD_BUFFERED, FILE_READ_DATA),
+    SetTargets = ctl_code(FILE_DEVICE_KEYBOARD, 0x90A, METHOD_BUFFERED, FILE_WRITE_DATA),
+    WaitNotification = ctl_code(FILE_DEVICE_KEYBOARD, 0x90B, METHOD_BUFFERED, FILE_READ_DATA),
+    GetCaptureTimeout = ctl_code(FILE_DEVICE_KEYBOARD, 0x90C, METHOD_BUFFERED, FILE_READ_DATA),
+    SetCaptureTimeout = ctl_code(FILE_DEVICE_KEYBOARD, 0x90D, METHOD_BUFFERED, FILE_READ_DATA | FILE_WRITE_DATA),
+    GetKeyState = ctl_code(FILE_DEVICE_KEYBOARD, 0x90E, METHOD_BUFFERED, FILE_READ_DATA),
+    MapRing = ctl_code(FILE_DEVICE_KEYBOARD, 0x90F, METHOD_OUT_DIRECT, FILE_READ_DATA | FILE_WRITE_DATA),
+    SubmitRing = ctl_code(FILE_DEVICE_KEYBOARD, 0x910, METHOD_BUFFERED, FILE_WRITE_DATA),
+}
+
+impl ControlIoctl {
+    /// Capture filters both reveal strokes and keep them from the system, so they need both.
+    fn required_access(&self) -> ACCESS_MASK {
+        match self {
+            ControlIoctl::ListDevices
+            | ControlIoctl::GetFilter
+            | ControlIoctl::Read
+            | ControlIoctl::SetReadFormat
+            | ControlIoctl::GetBlockRules
+            | ControlIoctl::GetTargets
+            | ControlIoctl::WaitNotification
+            | ControlIoctl::GetCaptureTimeout
+            | ControlIoctl::GetKeyState => MONITOR_ACCESS,
+            ControlIoctl::SetFilter | ControlIoctl::SetCaptureTimeout | ControlIoctl::MapRing => MONITOR_ACCESS | INJECT_ACCESS,
+            ControlIoctl::Write
+            | ControlIoctl::WriteText
+            | ControlIoctl::SetBlockRules
+            | ControlIoctl::SetTargets
+            | ControlIoctl::SubmitRing => INJECT_ACCESS,
+        }
+    }
+}
+
+/// Creates the control device along with the first filter device.
+pub(crate) fn create_if_needed(driver: WDFDRIVER) -> Result<()> {
+    if !CONTROL_DEVICE.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+
+    let mut builder = ControlDeviceBuilder::new(driver, default_sddl())?;
+    let mut device = builder
+        .with_name(CONTROL_DEVICE_NAME)
+        .with_file_object_config(file_object_config())
+        .with_symbolic_link(CONTROL_SYMBOLIC_LINK)
+        .with_device_type(FILE_DEVICE_KEYBOARD)
+        .with_io_in_caller_context(Some(control_in_caller_context))
+        .build_with_context::<ControlContext>()?;
+
+    if let Err(e) = create_queues(&mut device) {
+        device.delete();
+        return Err(e);
+    }
+
+    if CONTROL_DEVICE.compare_exchange(null_mut(), device.handle(), Ordering::AcqRel, Ordering::Acquire).is_err() {
+        log_debug!("Control device created concurrently, deleting ours");
+        device.delete();
+        return Ok(());
+    }
+
+    device.finish_initializing();
+    log_debug!("Control device created");
+    Ok(())
+}
+
+fn create_queues(device: &mut Device<ControlContext>) -> Result<()> {
+    QueueBuilder::new()
+        .default_queue()
+        .parallel_dispatch()
+        .device_control(Some(control_ioctl))
+        .create(device)?;
+
+    let read_queue = QueueBuilder::new()
+        .manual_dispatch()
+        .create(device)?;
+
+    let notify_queue = QueueBuilder::new()
+        .manual_dispatch()
+        .create(device)?;
+
+    device.context_mut().read_queue = read_queue.handle();
+    device.context_mut().notify_queue = notify_queue.handle();
+
+    // Deleted along with the control device
+    Timer::create_periodic(device, watchdog::CHECK_PERIOD_MS, Some(watchdog::on_timer))?
+        .start(watchdog::CHECK_PERIOD_MS);
+    Ok(())
+}
+
+/// Deletes the control device once the last filter device is gone, so the driver can unload.
+pub(crate) fn delete_if_unused() {
+    if !ATTACHED.lock().is_empty() {
+        return;
+    }
+
+    let device = CONTROL_DEVICE.swap(null_mut(), Ordering::AcqRel);
+    if let Some(device) = unsafe { device.as_mut() } {
+        Device::<ControlContext>::new(device).delete();
+        log_debug!("Control device deleted");
+    }
+}
+
+fn read_queue() -> Option<Queue<ControlContext>> {
+    let device = unsafe { CONTROL_DEVICE.load(Ordering::Acquire).as_mut() }?;
+    let read_queue = Device::<ControlContext>::new(device).context().read_queue;
+    (!read_queue.is_null()).then(|| unsafe { Queue::new(read_queue) })
+}
+
+fn notify_queue() -> Option<Queue<ControlContext>> {
+    let device = unsafe { CONTROL_DEVICE.load(Ordering::Acquire).as_mut() }?;
+    let notify_queue = Device::<ControlContext>::new(device).context().notify_queue;
+    (!notify_queue.is_null()).then(|| unsafe { Queue::new(notify_queue) })
+}
+
+/// Completes every pending `WaitNotification`. Nothing is kept for clients not waiting at the time.
+pub(crate) fn notify(kind: u32, device_id: u32) {
+    let Some(notify_queue) = notify_queue() else {
+        return;
+    };
+
+    let notification = Notification { kind, device_id, timestamp: time::performance_counter() };
+    while let Some(mut request) = notify_queue.retrieve_next_request() {
+        match request.write_output(&notification) {
+            Ok(length) => request.complete_with_information(STATUS_SUCCESS, length),
+            Err(e) => request.complete(e.nt_status()),
+        }
+    }
+}
+
+/// Completes pending reads with captured strokes, oldest first, for as long as there are both.
+pub(crate) fn complete_pending_reads() {
+    let Some(read_queue) = read_queue() else {
+        return;
+    };
+
+    loop {
+        let mut captured = CAPTURED.lock();
+        if captured.is_empty() {
+            return;
+        }
+
+        let Some(mut request) = read_queue.retrieve_next_request() else {
+            return;
+        };
+
+        let format = read_format(&mut request);
+        let record_size = format.record_size();
+        let res = request.output_buffer(record_size).map(|buffer| {
+            let limit = (buffer.len() / record_size).min(batching::read_limit());
+            let mut count = 0;
+            while count < limit {
+                let Some(stroke) = captured.pop() else {
+                    break;
+                };
+                match format {
+                    ReadFormat::Legacy => write_to_buffer(buffer, count * record_size, &DeviceStroke::from(stroke)),
+                    ReadFormat::Timed => write_to_buffer(buffer, count * record_size, &stroke),
+                };
+                count += 1;
+            }
+            count * record_size
+        });
+        drop(captured);
+
+        match res {
+            Ok(length) => request.complete_with_information(STATUS_SUCCESS, length),
+            Err(e) => request.complete(e.nt_status()),
+        }
+    }
+}
+
+/// Sees every request in the context of the thread that sent it. `MapRing` looks up a handle of
+/// the sender's, so its rings are reserved here before it goes to the queues with everything else.
+extern "C" fn control_in_caller_context(device: WDFDEVICE, request: WDFREQUEST) {
+    let mut request = Request::new(unsafe { request.as_mut().expect("Request is null") });
+    if request.io_control_code() == Some(ControlIoctl::MapRing.into()) {
+        let res = check_access(&mut request, ControlIoctl::MapRing.required_access())
+            .and_then(|_| shared_ring::reserve(&mut request));
+        if let Err(e) = res {
+            log_warn!("MapRing failed: {e}");
+            request.complete(e.nt_status());
+            return;
+        }
+    }
+
+    if let Err(e) = request.enqueue(device) {
+        log_warn!("Enqueueing control request failed: {e}");
+        request.complete(e.nt_status());
+    }
+}
+
+extern "C" fn control_ioctl(queue: WDFQUEUE, request: WDFREQUEST, output_buffer_length: usize, _input_buffer_length: usize, io_control_code: ULONG) {
+    log_trace!("control_ioctl {io_control_code:#X}");
+
+    let queue = unsafe { Queue::<ControlContext>::new(queue) };
+    let mut request = Request::new(unsafe { request.as_mut().expect("Request is null") });
+
+    let res = match ControlIoctl::try_from(io_control_code) {
+        Ok(ioctl) => check_access(&mut request, ioctl.required_access()).map(|_| ioctl),
+        Err(_) => Err(Error::from_nt_status(STATUS_NOT_IMPLEMENTED, ErrorCode::UnsupportedIoctl)),
+    }.and_then(|ioctl| match ioctl {
+        ControlIoctl::ListDevices => list_devices(&mut request).map(Some),
+        ControlIoctl::GetFilter => get_filter(&mut request).map(Some),
+        ControlIoctl::SetFilter => set_filter(&mut request).map(|_| Some(0)),
+        ControlIoctl::Read => queue_read(&queue, &mut request, output_buffer_length).map(|_| None),
+        ControlIoctl::Write => write_strokes(&mut request).map(Some),
+        ControlIoctl::SetReadFormat => set_read_format(&mut request).map(Some),
+        ControlIoctl::WriteText => write_text(&mut request).map(|_| Some(0)),
+        ControlIoctl::GetBlockRules => get_block_rules(&mut request).map(Some),
+        ControlIoctl::SetBlockRules => set_block_rules(&mut request).map(|_| Some(0)),
+        ControlIoctl::GetTargets => get_targets(&mut request).map(Some),
+        ControlIoctl::SetTargets => set_targets(&mut request).map(|_| Some(0)),
+        ControlIoctl::WaitNotification => queue_notification_wait(&queue, &mut request, output_buffer_length).map(|_| None),
+        ControlIoctl::GetCaptureTimeout => get_capture_timeout(&mut request).map(Some),
+        ControlIoctl::GetKeyState => get_key_state(&mut request).map(Some),
+        ControlIoctl::SetCaptureTimeout => request.read_input::<CaptureTimeout>().and_then(|timeout| set_capture_timeout(&timeout)).map(|_| Some(0)),
+        ControlIoctl::MapRing => shared_ring::map(&mut request).map(|_| None),
+        ControlIoctl::SubmitRing => shared_ring::submit(&mut request).map(Some),
+    });
+
+    match res {
+        Ok(Some(bytes_transferred)) => request.complete_with_information(STATUS_SUCCESS, bytes_transferred),
+        // The read or notify queue owns the request now, or it holds the shared rings
+        Ok(None) => complete_pending_reads(),
+        Err(e) => {
+            log_warn!("control_ioctl {io_control_code:#X} failed: {e}");
+            request.complete(e.nt_status());
+        }
+    }
+}
+
+/// The format negotiated on the handle the request was sent on.
+fn read_format(request: &mut Request) -> ReadFormat {
+    request.file_object()
+        .and_then(|file_object| file_object.context::<FileContext>().map(|context| context.read_format))
+        .and_then(|format| ReadFormat::try_from(format).ok())
+        .unwrap_or(ReadFormat::Legacy)
+}
+
+fn set_read_format(request: &mut Request) -> Result<usize> {
+    let format = ReadFormat::try_from(request.read_input::<ReadFormatRequest>()?.format)
+        .map_err(|_| Error::invalid_buffer(ErrorCode::IoctlInputInvalid))?;
+
+    let mut file_object = request.file_object()
+        .ok_or_else(|| Error::invalid_buffer(ErrorCode::IoctlInputInvalid))?;
+
+    let length = request.write_output(&ReadFormatResponse {
+        format: format.into(),
+        record_size: format.record_size() as u32,
+        timestamp_frequency: time::performance_frequency(),
+    })?;
+
+    if let Some(context) = file_object.context_mut::<FileContext>() {
+        context.read_format = format.into();
+    }
+    Ok(length)
+}
+
+fn queue_read(queue: &Queue<ControlContext>, request: &mut Request, output_buffer_length: usize) -> Result<()> {
+    let required = read_format(request).record_size();
+    if output_buffer_length < required {
+        return Err(Error::buffer_too_small(ErrorCode::IoctlOutputTooSmall, required, output_buffer_length));
+    }
+
+    request.forward_to_queue(queue.device().context().read_queue)
+}
+
+fn queue_notification_wait(queue: &Queue<ControlContext>, request: &mut Request, output_buffer_length: usize) -> Result<()> {
+    let required = core::mem::size_of::<Notification>();
+    if output_buffer_length < required {
+        return Err(Error::buffer_too_small(ErrorCode::IoctlOutputTooSmall, required, output_buffer_length));
+    }
+
+    request.forward_to_queue(queue.device().context().notify_queue)
+}
+
+fn list_devices(request: &mut Request) -> Result<usize> {
+    let header_size = core::mem::size_of::<DeviceListHeader>();
+    let buffer = request.output_buffer(header_size)?;
+
+    let instances = ATTACHED.lock();
+    let mut offset = header_size;
+    let mut header = DeviceListHeader { total: instances.len() as u32, count: 0 };
+    for instance in instances.iter() {
+        let entry = instance.entry();
+        if !write_to_buffer(buffer, offset, &entry) {
+            break;
+        }
+        offset += core::mem::size_of_val(&entry);
+        header.count += 1;
+    }
+    drop(instances);
+
+    write_to_buffer(buffer, 0, &header);
+    Ok(offset)
+}
+
+/// The filter of the target a non-zero `target` of a `DeviceFilter` refers to.
+fn target_filter(target: u16) -> Result<&'static AtomicU16> {
+    TARGET_FILTERS.get(usize::from(target) - 1)
+        .ok_or_else(|| Error::from_nt_status(STATUS_INVALID_PARAMETER, ErrorCode::IoctlInputInvalid))
+}
+
+fn get_filter(request: &mut Request) -> Result<usize> {
+    let DeviceFilter { device_id, target, .. } = request.read_input::<DeviceFilter>()?;
+    let filter = if target != 0 {
+        target_filter(target)?.load(Ordering::Relaxed)
+    } else {
+        ATTACHED.lock()
+            .with_device(device_id, |device| device.context().filter.load(Ordering::Relaxed))
+            .ok_or_else(|| Error::from_nt_status(STATUS_NO_SUCH_DEVICE, ErrorCode::DeviceNotFound))?
+    };
+
+    request.write_output(&DeviceFilter { device_id, filter, target })
+}
+
+/// Setting a filter also resumes capturing on keyboards the watchdog stalled. The handle it's set on
+/// owns it until another one sets it; dropping a filter gives the system the breaks of held keys,
+/// whose releases may have been captured.
+fn set_filter(request: &mut Request) -> Result<()> {
+    let filter = request.read_input::<DeviceFilter>()?;
+    let owner = match request.file_object() {
+        Some(file_object) if filter.filter != FILTER_KEY_NONE => file_object.handle(),
+        _ => null_mut(),
+    };
+
+    if filter.target != 0 {
+        let previous = target_filter(filter.target)?.swap(filter.filter, Ordering::Relaxed);
+        TARGET_FILTER_OWNERS[usize::from(filter.target) - 1].store(owner, Ordering::Relaxed);
+        // Which keyboards the target covers is only known per stroke
+        let attached = ATTACHED.lock();
+        for instance in attached.iter() {
+            attached.with_device(instance.id, |device| device.context().stalled.store(false, Ordering::Relaxed));
+        }
+        drop(attached);
+
+        if previous != FILTER_KEY_NONE && filter.filter == FILTER_KEY_NONE {
+            release_targeted_keys(1 << (filter.target - 1));
+        }
+        log_debug!("Filter of target {} set to {:#06X}", filter.target, filter.filter);
+        return Ok(());
+    }
+
+    ATTACHED.lock()
+        .with_device(filter.device_id, |device| {
+            let context = device.context();
+            let previous = context.filter.swap(filter.filter, Ordering::Relaxed);
+            context.filter_owner.store(owner, Ordering::Relaxed);
+            context.stalled.store(false, Ordering::Relaxed);
+            if previous != FILTER_KEY_NONE && filter.filter == FILTER_KEY_NONE {
+                release_held_keys(context);
+            }
+        })
+        .ok_or_else(|| Error::from_nt_status(STATUS_NO_SUCH_DEVICE, ErrorCode::DeviceNotFound))?;
+
+    log_debug!("Filter of device {} set to {:#06X}", filter.device_id, filter.filter);
+    Ok(())
+}
+
+/// Releases the held keys of every keyboard that may fall under one of the targets in `mask`.
+fn release_targeted_keys(mask: u32) -> usize {
+    let attached = ATTACHED.lock();
+    attached.iter()
+        .filter_map(|instance| attached.with_device(instance.id, |device| {
+            let context = device.context();
+            if TargetMatch::for_device(context).covers(mask) { release_held_keys(context) } else { 0 }
+        }))
+        .sum()
+}
+
+/// Drops the filters and shared rings a closing handle owned. Strokes they captured are dropped as
+/// nobody is left to read them, and the system gets the breaks of keys it saw go down.
+pub(crate) extern "C" fn on_file_cleanup(file_object: WDFFILEOBJECT) {
+    shared_ring::unmap(file_object);
+
+    let attached = ATTACHED.lock();
+    let mut released: usize = attached.iter()
+        .filter_map(|instance| attached.with_device(instance.id, |device| {
+            let context = device.context();
+            if context.filter_owner.compare_exchange(file_object, null_mut(), Ordering::AcqRel, Ordering::Relaxed).is_err() {
+                return 0;
+            }
+
+            context.filter.store(FILTER_KEY_NONE, Ordering::Relaxed);
+            CAPTURED.lock().remove_device(context.instance);
+            release_held_keys(context)
+        }))
+        .sum();
+    drop(attached);
+
+    let mut targets = 0u32;
+    for (i, (filter, owner)) in TARGET_FILTERS.iter().zip(&TARGET_FILTER_OWNERS).enumerate() {
+        if owner.compare_exchange(file_object, null_mut(), Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            filter.store(FILTER_KEY_NONE, Ordering::Relaxed);
+            targets |= 1 << i;
+        }
+    }
+    if targets != 0 {
+        released += release_targeted_keys(targets);
+    }
+
+    if released > 0 {
+        log_debug!("Handle closed with filters set, released {released} held keys");
+    }
+}
+
+fn get_capture_timeout(request: &mut Request) -> Result<usize> {
+    let CaptureTimeout { device_id, .. } = request.read_input::<CaptureTimeout>()?;
+    let timeout_ms = ATTACHED.lock()
+        .with_device(device_id, |device| device.context().capture_timeout.load(Ordering::Relaxed))
+        .ok_or_else(|| Error::from_nt_status(STATUS_NO_SUCH_DEVICE, ErrorCode::DeviceNotFound))?;
+
+    request.write_output(&CaptureTimeout { device_id, timeout_ms })
+}
+
+fn set_capture_timeout(timeout: &CaptureTimeout) -> Result<()> {
+    ATTACHED.lock()
+        .with_device(timeout.device_id, |device| device.context().capture_timeout.store(timeout.timeout_ms, Ordering::Relaxed))
+        .ok_or_else(|| Error::from_nt_status(STATUS_NO_SUCH_DEVICE, ErrorCode::DeviceNotFound))?;
+
+    log_debug!("Capture timeout of device {} set to {} ms", timeout.device_id, timeout.timeout_ms);
+    Ok(())
+}
+
+fn get_key_state(request: &mut Request) -> Result<usize> {
+    let DeviceKeyState { device_id, .. } = request.read_input::<DeviceKeyState>()?;
+    let attached = ATTACHED.lock();
+    let pressed = if device_id == DEVICE_ID_ALL {
+        attached.iter()
+            .filter_map(|instance| attached.with_device(instance.id, |device| *device.context().key_state.lock().pressed()))
+            .fold(PressedKeys::new(), |mut pressed, device_pressed| {
+                pressed.union(&device_pressed);
+                pressed
+            })
+    } else {
+        attached.with_device(device_id, |device| *device.context().key_state.lock().pressed())
+            .ok_or_else(|| Error::from_nt_status(STATUS_NO_SUCH_DEVICE, ErrorCode::DeviceNotFound))?
+    };
+    drop(attached);
+
+    request.write_output(&DeviceKeyState { device_id, reserved: 0, pressed: pressed.bits() })
+}
+
+/// Number of written records injected per call into the class driver.
+const WRITE_CHUNK: usize = 16;
+
+/// Injects the [`DeviceStroke`] records of a `ControlWrite` in order, up to the write limit, and
+/// stops at the first one the class driver doesn't take. How many were injected is returned in the
+/// output buffer, if there's room for it.
+fn write_strokes(request: &mut Request) -> Result<usize> {
+    let record_size = core::mem::size_of::<DeviceStroke>();
+    let buffer = request.input_buffer(record_size)?;
+    let count = (buffer.len() / record_size).min(batching::write_limit());
+    let injected = inject_records(count, |index| read_from_buffer::<DeviceStroke>(buffer, index * record_size))?;
+
+    // Clients that don't care about the count pass no output buffer
+    Ok(request.write_output(&WriteResponse { injected: injected as u32 }).unwrap_or(0))
+}
+
+/// Injects the first `count` records returned by `record` in order, stopping at the first one the
+/// class driver doesn't take, and returns how many were injected. An unknown keyboard is an error
+/// if it's the first record's.
+pub(crate) fn inject_records(count: usize, record: impl Fn(usize) -> Option<DeviceStroke>) -> Result<usize> {
+    let mut injected = 0;
+    while injected < count {
+        // Consecutive records for the same keyboard go in one call
+        let device_id = record(injected).unwrap_or(DeviceStroke::EMPTY).device_id;
+        let mut strokes = [KeyboardInputData::EMPTY; WRITE_CHUNK];
+        let mut len = 0;
+        while len < WRITE_CHUNK && injected + len < count {
+            match record(injected + len) {
+                Some(record) if record.device_id == device_id => strokes[len] = record.stroke,
+                _ => break,
+            }
+            len += 1;
+        }
+
+        let taken = ATTACHED.lock().with_device(device_id, |device| inject_strokes(device.context(), &mut strokes[..len]));
+        match taken {
+            None if injected == 0 => return Err(Error::from_nt_status(STATUS_NO_SUCH_DEVICE, ErrorCode::DeviceNotFound)),
+            None => {
+                log_warn!("Write stopped after {injected} of {count} strokes at unknown device {device_id}");
+                break;
+            }
+            Some(taken) => {
+                injected += taken;
+                if taken < len {
+                    log_error!("Class driver accepted {injected} of {count} injected strokes");
+                    break;
+                }
+            }
+        }
+    }
+    Ok(injected)
+}
+
+fn get_block_rules(request: &mut Request) -> Result<usize> {
+    let rules = *BLOCK_RULES.lock();
+    let rules = rules.as_slice();
+
+    let header_size = core::mem::size_of::<BlockRulesHeader>();
+    let required = header_size + core::mem::size_of_val(rules);
+    let buffer = request.output_buffer(required)?;
+
+    write_to_buffer(buffer, 0, &BlockRulesHeader { count: rules.len() as u32, reserved: 0 });
+    for (i, rule) in rules.iter().enumerate() {
+        write_to_buffer(buffer, header_size + i * core::mem::size_of::<BlockRule>(), rule);
+    }
+    Ok(required)
+}
+
+/// Rules apply to strokes arriving after the call; keys already held keep the fate decided when they went down.
+fn set_block_rules(request: &mut Request) -> Result<()> {
+    let header_size = core::mem::size_of::<BlockRulesHeader>();
+    let buffer = request.input_buffer(header_size)?;
+    let count = read_from_buffer::<BlockRulesHeader>(buffer, 0)
+        .ok_or_else(|| Error::invalid_buffer(ErrorCode::IoctlInputInvalid))?
+        .count as usize;
+
+    if count > MAX_BLOCK_RULES {
+        return Err(Error::from_nt_status(STATUS_INVALID_PARAMETER, ErrorCode::IoctlInputInvalid));
+    }
+
+    let required = header_size + count * core::mem::size_of::<BlockRule>();
+    if buffer.len() < required {
+        return Err(Error::buffer_too_small(ErrorCode::IoctlInputInvalid, required, buffer.len()));
+    }
+
+    let mut rules = [BlockRule::default(); MAX_BLOCK_RULES];
+    for (i, rule) in rules[..count].iter_mut().enumerate() {
+        *rule = read_from_buffer(buffer, header_size + i * core::mem::size_of::<BlockRule>()).unwrap_or_default();
+    }
+
+    BLOCK_RULES.lock().set(&rules[..count]);
+    log_debug!("Block rules replaced, {count} rules");
+    Ok(())
+}
+
+fn get_targets(request: &mut Request) -> Result<usize> {
+    let targets = *TARGETS.lock();
+    let targets = targets.as_slice();
+
+    let header_size = core::mem::size_of::<TargetsHeader>();
+    let required = header_size + core::mem::size_of_val(targets);
+    let buffer = request.output_buffer(required)?;
+
+    write_to_buffer(buffer, 0, &TargetsHeader { count: targets.len() as u32, reserved: 0 });
+    for (i, target) in targets.iter().enumerate() {
+        write_to_buffer(buffer, header_size + i * core::mem::size_of::<DeviceTarget>(), target);
+    }
+    Ok(required)
+}
+
+/// Rules and filters keep referring to targets by index. Filters of indexes past the new table are cleared.
+fn set_targets(request: &mut Request) -> Result<()> {
+    let header_size = core::mem::size_of::<TargetsHeader>();
+    let buffer = request.input_buffer(header_size)?;
+    let count = read_from_buffer::<TargetsHeader>(buffer, 0)
+        .ok_or_else(|| Error::invalid_buffer(ErrorCode::IoctlInputInvalid))?
+        .count as usize;
+
+    if count > MAX_TARGETS {
+        return Err(Error::from_nt_status(STATUS_INVALID_PARAMETER, ErrorCode::IoctlInputInvalid));
+    }
+
+    let required = header_size + count * core::mem::size_of::<DeviceTarget>();
+    if buffer.len() < required {
+        return Err(Error::buffer_too_small(ErrorCode::IoctlInputInvalid, required, buffer.len()));
+    }
+
+    let mut targets = [DeviceTarget::EMPTY; MAX_TARGETS];
+    for (i, target) in targets[..count].iter_mut().enumerate() {
+        *target = read_from_buffer(buffer, header_size + i * core::mem::size_of::<DeviceTarget>()).unwrap_or(DeviceTarget::EMPTY);
+    }
+
+    TARGETS.lock().set(&targets[..count]);
+    for (filter, owner) in TARGET_FILTERS[count..].iter().zip(&TARGET_FILTER_OWNERS[count..]) {
+        filter.store(FILTER_KEY_NONE, Ordering::Relaxed);
+        owner.store(null_mut(), Ordering::Relaxed);
+    }
+
+    log_debug!("Targets replaced, {count} targets");
+    Ok(())
+}
+
+/// Number of text events encoded and injected per call into the class driver.
+const TEXT_CHUNK: usize = MAX_EVENTS_PER_CHARACTER;
+
+/// Types the text of a `ControlWriteText` request. If the class driver stops taking strokes
+/// midway, typing stops there and the keys it saw go down are released.
+fn write_text(request: &mut Request) -> Result<()> {
+    let header_size = core::mem::size_of::<TextInjectionHeader>();
+    let buffer = request.input_buffer(header_size)?;
+    let header = read_from_buffer::<TextInjectionHeader>(buffer, 0)
+        .ok_or_else(|| Error::invalid_buffer(ErrorCode::IoctlInputInvalid))?;
+
+    let layout = layout::by_id(header.layout_id)
+        .ok_or_else(|| Error::from_nt_status(STATUS_INVALID_PARAMETER, ErrorCode::LayoutNotFound))?;
+
+    let length = header.length as usize;
+    if length > MAX_TEXT_LENGTH {
+        return Err(Error::from_nt_status(STATUS_INVALID_PARAMETER, ErrorCode::TextTooLong));
+    }
+
+    let required = header_size + length * core::mem::size_of::<u16>();
+    if buffer.len() < required {
+        return Err(Error::buffer_too_small(ErrorCode::IoctlInputInvalid, required, buffer.len()));
+    }
+
+    let mut text = [0u16; MAX_TEXT_LENGTH];
+    for (i, unit) in text[..length].iter_mut().enumerate() {
+        *unit = read_from_buffer(buffer, header_size + i * core::mem::size_of::<u16>()).unwrap_or_default();
+    }
+    let text = &text[..length];
+
+    // Nothing is typed unless all of it can be
+    let mut events = Vec::new();
+    events.try_reserve_exact(length * MAX_EVENTS_PER_CHARACTER)
+        .map_err(|_| Error::from_nt_status(STATUS_INSUFFICIENT_RESOURCES, ErrorCode::TextAllocationFailed))?;
+    events.resize(length * MAX_EVENTS_PER_CHARACTER, KeyEvent::released(ScanCode::new(0)));
+    let count = compile(text, layout, &mut events).map_err(|e| {
+        log_warn!("Text can't be typed on the {} layout: {e:?}", layout.name);
+        Error::from_nt_status(STATUS_UNMAPPABLE_CHARACTER, ErrorCode::TextUnmappable)
+    })?;
+
+    let mut encoder = Encoder::new();
+    // Keys the class driver saw go down but not up, released if it stops taking strokes midway
+    let mut held = KeySet::new();
+    for chunk in events[..count].chunks(TEXT_CHUNK) {
+        let mut strokes = [KeyboardInputData::EMPTY; TEXT_CHUNK * 2];
+        let mut len = 0;
+        for event in chunk {
+            for stroke in encoder.encode(*event).as_slice() {
+                strokes[len] = KeyboardInputData::from(*stroke);
+                len += 1;
+            }
+        }
+
+        let injected = ATTACHED.lock()
+            .with_device(header.device_id, |device| inject_strokes(device.context(), &mut strokes[..len]))
+            .ok_or_else(|| Error::from_nt_status(STATUS_NO_SUCH_DEVICE, ErrorCode::DeviceNotFound))?;
+
+        for stroke in &strokes[..injected] {
+            let stroke = RawStroke::from(*stroke);
+            if let Some(scan_code) = stroke.scan_code() {
+                if stroke.is_break() {
+                    held.remove(scan_code);
+                } else {
+                    held.insert(scan_code);
+                }
+            }
+        }
+
+        if injected < len {
+            log_error!("Class driver accepted {injected} of {len} strokes typing text, stopping");
+            release_typed_keys(header.device_id, &held);
+            return Ok(());
+        }
+    }
+
+    log_debug!("Typed {} characters on device {} with the {} layout", text.len(), header.device_id, layout.name);
+    Ok(())
+}
+
+/// Hands the class driver a break for each of `keys`, the keys left down by text typed on `device_id`.
+fn release_typed_keys(device_id: u32, keys: &KeySet) {
+    let mut breaks = [KeyboardInputData::EMPTY; TEXT_CHUNK];
+    let mut len = 0;
+    for scan_code in keys.iter().take(TEXT_CHUNK) {
+        breaks[len] = KeyboardInputData::from(RawStroke::release(scan_code));
+        len += 1;
+    }
+
+    let released = ATTACHED.lock().with_device(device_id, |device| inject_strokes(device.context(), &mut breaks[..len]));
+    log_debug!("Released {} of {len} keys left down by the text", released.unwrap_or(0));
+}
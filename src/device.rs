@@ -3,52 +3,70 @@
 
 use alloc::format;
 use core::fmt::Debug;
-use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+use core::sync::atomic::{AtomicU32, Ordering};
 use nt_string::nt_unicode_str;
 use nt_string::unicode_string::{NtUnicodeStr, NtUnicodeString};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use wdk::{nt_success, println};
+use wdk::nt_success;
 use wdk_sys::{*};
+use wdk_sys::_DEVICE_REGISTRY_PROPERTY::DevicePropertyHardwareID;
+use wdk_sys::_WDF_EXECUTION_LEVEL::WdfExecutionLevelPassive;
 use wdk_sys::_WDF_REQUEST_SEND_OPTIONS_FLAGS::WDF_REQUEST_SEND_OPTION_SEND_AND_FORGET;
-use wdk_sys::_WDF_REQUEST_TYPE::WdfRequestTypeDeviceControlInternal;
 use wdk_sys::macros::call_unsafe_wdf_function_binding;
 use wdk_sys::ntddk::KeGetCurrentIrql;
 
-use crate::{dbg, DeviceContext, get_pdo_context, GUID_DEVINTERFACE_INTERUSTCEPTION, kernel_callback, PdoContext, wdf_object_get_device_context};
-use crate::device::KeyboardIoctl::PdoKeyboardAttributes;
-use crate::foreign::{ConnectData, GUID_CLASS_KEYBOARD, KeyboardAttributes, KeyboardInputData};
-use crate::framework::{Device, DeviceBuilder, ErrorCode, NtStatusError, Queue, QueueBuilder, Result, KeyboardConnectRequest, Request};
+use crate::access::{check_access, file_object_config, INJECT_ACCESS, MONITOR_ACCESS};
+use crate::{control, DeviceContext, escape, GUID_DEVINTERFACE_INTERUSTCEPTION, kernel_callback, log_debug, log_error, log_trace, log_warn, PdoContext, shared_ring, wdf_object_get_device_context};
+use crate::foreign::{ConnectData, GUID_CLASS_KEYBOARD, KeyboardAttributes, KeyboardIndicatorParameters, KeyboardInputData, KeyboardTypematicParameters};
+use crate::framework::{CompletionKind, CompletionParams, Device, DeviceBuilder, Error, ErrorCode, NtStatusError, Queue, QueueBuilder, Result, KeyboardConnectRequest, Request};
+use crate::framework::log::{self, Level};
+use crate::framework::{log_ring, time};
+use crate::framework::nt_status::NtStatusName;
 use crate::framework::pdo::PdoBuilder;
 use crate::framework::utils::ctl_code;
+use crate::blocking::BLOCK_RULES;
+use crate::capture::{CAPTURED, filter_matches};
+use crate::forwarding::{Classifier, ForwardBuffer};
+use crate::instances::{ATTACHED, Instance};
+use crate::keyboard::block::{BlockRule, BlockState};
+use crate::keyboard::sequence::RawStroke;
+use crate::privacy::{LOG_KEYSTROKES, StrokeSummary};
+use crate::targeting::{any_target_filter, TargetMatch};
+use crate::protocol::{FILTER_KEY_NONE, TimedStroke, HARDWARE_ID_LENGTH, LogLevelRequest};
 
 static mut INSTANCES: AtomicU32 = AtomicU32::new(0);
 
 
-pub(crate) fn device_create(device_init: &mut WDFDEVICE_INIT) -> Result<()> {
-    dbg!("device_create");
+pub(crate) fn device_create(driver: WDFDRIVER, device_init: &mut WDFDEVICE_INIT) -> Result<()> {
+    log_trace!("device_create");
 
     let mut builder = DeviceBuilder::new(device_init);
+    builder.attributes().with_cleanup_callback(Some(device_cleanup));
     let mut device = builder
         .as_filter_device()
         .with_device_type(FILE_DEVICE_KEYBOARD)
         .build_with_context::<DeviceContext>()?;
 
-    dbg!("device_create - created device");
+    log_trace!("device_create - created device");
 
     let _default_queue = QueueBuilder::new()
         .default_queue()
         .parallel_dispatch()
         .internal_device_control(Some(internal_ioctl_cb))
-        .create(device.handle())?;
+        .create(&mut device)?;
 
-    dbg!("device_create - created default queue");
+    log_trace!("device_create - created default queue");
 
-    let pdo_queue = QueueBuilder::new()
+    let mut pdo_queue_builder = QueueBuilder::new();
+    // Handlers wait synchronously on the lower stack, so they must run at PASSIVE_LEVEL.
+    pdo_queue_builder.attributes().with_execution_level(WdfExecutionLevelPassive);
+    let pdo_queue = pdo_queue_builder
         .parallel_dispatch()
         .device_control(Some(pdo_from_ioctl))
-        .create(device.handle())?;
+        .create(&mut device)?;
 
-    dbg!("device_create - created pdo queue");
+    log_trace!("device_create - created pdo queue");
 
     let context = device.context_mut();
     context.raw_pdo_queue = pdo_queue.handle();
@@ -57,27 +75,79 @@ pub(crate) fn device_create(device_init: &mut WDFDEVICE_INIT) -> Result<()> {
         INSTANCES.fetch_add(1, core::sync::atomic::Ordering::SeqCst)
     } + 1;
 
-    dbg!("device_create - starting to create pdos");
+    context.instance = current;
+
+    log_trace!("device_create - starting to create pdos");
 
     create_pdo(&mut device, current)?;
 
-    dbg!("device_create - created pdos");
+    log_trace!("device_create - created pdos");
+
+    register_instance(&mut device, current);
+
+    // The filter keeps working without it, only the control device clients lose access.
+    if let Err(e) = control::create_if_needed(driver) {
+        log_error!("Creating the control device failed: {e}");
+    }
 
     Ok(())
 }
 
+/// Longest hardware ID list read from the device; only the first ID is kept.
+const HARDWARE_IDS_LENGTH: usize = 512;
+
+/// Records the keyboard's first hardware ID, which targets match, and makes it reachable through the control device.
+fn register_instance(device: &mut Device<DeviceContext>, id: u32) {
+    let mut hardware_ids = [0u16; HARDWARE_IDS_LENGTH];
+    let mut hardware_id = [0u16; HARDWARE_ID_LENGTH];
+    match device.query_property_string(DevicePropertyHardwareID, &mut hardware_ids) {
+        Ok(length) => {
+            let first = hardware_ids[..length].split(|c| *c == 0).next().unwrap_or_default();
+            let length = first.len().min(HARDWARE_ID_LENGTH - 1);
+            hardware_id[..length].copy_from_slice(&first[..length]);
+        }
+        Err(e) => log_warn!("Querying the hardware ID of device {id} failed: {e}"),
+    }
+    device.context_mut().hardware_id = hardware_id;
+
+    let instance = Instance {
+        id,
+        device: device.handle(),
+        hardware_id,
+    };
+
+    if !ATTACHED.lock().add(instance) {
+        log_warn!("Too many keyboards, device {id} is not reachable through the control device");
+    }
+}
+
+extern "C" fn device_cleanup(object: WDFOBJECT) {
+    log_trace!("device_cleanup");
+
+    if let Some(context) = unsafe { wdf_object_get_device_context(object).as_ref() } {
+        let mut attached = ATTACHED.lock();
+        // The class device is above ours and still there: removal runs down the stack before it deletes it
+        attached.with_device(context.instance, |device| release_held_keys(device.context()));
+        attached.remove(context.instance);
+        drop(attached);
+        CAPTURED.lock().remove_device(context.instance);
+    }
+
+    control::delete_if_unused();
+}
+
 const DEVICE_ID: NtUnicodeStr<'static> = nt_unicode_str!("{A65C87F9-BE02-4ed9-92EC-012D416169FA}\\Interustception");
 
 const DEVICE_LOCATION: NtUnicodeStr<'static> = nt_unicode_str!("Interustception");
 
 fn create_pdo(device: &mut Device<DeviceContext>, current: u32) -> Result<()> {
-    dbg!("create_pdo");
+    log_trace!("create_pdo");
 
     let instance_id = NtUnicodeString::try_from(format!("{current:02}")).unwrap();
 
     let device_description = NtUnicodeString::try_from(format!("Interustception PDO {current:02}")).unwrap();
 
-    dbg!("create_pdo - starting to create pdo");
+    log_trace!("create_pdo - starting to create pdo");
 
     let mut builder = PdoBuilder::new(device.handle());
     let mut pdo = builder
@@ -86,25 +156,26 @@ fn create_pdo(device: &mut Device<DeviceContext>, current: u32) -> Result<()> {
         .with_instance_id(instance_id)
         .with_device_text(device_description, DEVICE_LOCATION, 0x409)
         .allow_forwarding_request_to_parent()
+        .with_file_object_config(file_object_config())
         .build_with_context::<PdoContext>()?;
 
-    dbg!("create_pdo - created pdo");
+    log_trace!("create_pdo - created pdo");
 
     {
-        let context = dbg!(pdo.context_mut());
+        let context = pdo.context_mut();
         context.instance = current;
         context.queue = device.context().raw_pdo_queue;
     }
 
 
-    dbg!("create_pdo - starting to create pdo queue");
+    log_trace!("create_pdo - starting to create pdo queue");
 
     let _pdo_queue = QueueBuilder::new()
         .default_queue()
         .device_control(Some(pdo_to_ioctl))
-        .create(pdo.handle())?;
+        .create(&mut pdo.device)?;
 
-    dbg!("create_pdo - created pdo queue");
+    log_trace!("create_pdo - created pdo queue");
 
     pdo.set_capabilities(
         true,
@@ -115,12 +186,12 @@ fn create_pdo(device: &mut Device<DeviceContext>, current: u32) -> Result<()> {
 
     pdo.create_interface(&GUID_DEVINTERFACE_INTERUSTCEPTION)?;
 
-    dbg!("create_pdo - created interface");
+    log_trace!("create_pdo - created interface");
     pdo.attach(device.handle())?;
 
-    dbg!("create_pdo - attached pdo");
+    log_trace!("create_pdo - attached pdo");
 
-    dbg!(pdo.save());
+    pdo.save();
 
     Ok(())
 }
@@ -140,10 +211,43 @@ enum KeyboardIoctl {
     KeyboardConnect = ctl_code(FILE_DEVICE_KEYBOARD, 0x80, METHOD_NEITHER, FILE_ANY_ACCESS),
     KeyboardDisconnect = ctl_code(FILE_DEVICE_KEYBOARD, 0x100, METHOD_NEITHER, FILE_ANY_ACCESS),
     KeyboardQueryAttributes = 720896u32,
+    KeyboardQueryTypematic = ctl_code(FILE_DEVICE_KEYBOARD, 0x08, METHOD_BUFFERED, FILE_ANY_ACCESS),
+    KeyboardQueryIndicators = ctl_code(FILE_DEVICE_KEYBOARD, 0x10, METHOD_BUFFERED, FILE_ANY_ACCESS),
 
     PdoKeyboardAttributes = ctl_code(FILE_DEVICE_KEYBOARD, 0x800, METHOD_BUFFERED, FILE_READ_DATA),
+    PdoKeyboardIndicators = ctl_code(FILE_DEVICE_KEYBOARD, 0x801, METHOD_BUFFERED, FILE_READ_DATA),
+    PdoKeyboardTypematic = ctl_code(FILE_DEVICE_KEYBOARD, 0x802, METHOD_BUFFERED, FILE_READ_DATA),
+    PdoSetLogLevel = ctl_code(FILE_DEVICE_KEYBOARD, 0x803, METHOD_BUFFERED, FILE_WRITE_DATA),
+    PdoGetLogRecords = ctl_code(FILE_DEVICE_KEYBOARD, 0x804, METHOD_BUFFERED, FILE_READ_DATA),
+    PdoGetStatistics = ctl_code(FILE_DEVICE_KEYBOARD, 0x805, METHOD_BUFFERED, FILE_READ_DATA),
+    PdoResetStatistics = ctl_code(FILE_DEVICE_KEYBOARD, 0x806, METHOD_BUFFERED, FILE_WRITE_DATA),
+}
+
+impl KeyboardIoctl {
+    /// IOCTLs sent to the raw PDO that are handled by the filter device it belongs to.
+    fn is_forwarded_from_pdo(&self) -> bool {
+        matches!(self,
+            KeyboardIoctl::PdoKeyboardAttributes
+            | KeyboardIoctl::PdoKeyboardIndicators
+            | KeyboardIoctl::PdoKeyboardTypematic
+            | KeyboardIoctl::PdoSetLogLevel
+            | KeyboardIoctl::PdoGetLogRecords
+            | KeyboardIoctl::PdoGetStatistics
+            | KeyboardIoctl::PdoResetStatistics)
+    }
+
+    /// Access the PDO handle must have been opened with.
+    fn required_access(&self) -> ACCESS_MASK {
+        match self {
+            KeyboardIoctl::PdoSetLogLevel | KeyboardIoctl::PdoResetStatistics => INJECT_ACCESS,
+            _ => MONITOR_ACCESS,
+        }
+    }
 }
 
+/// How long to wait for the lower stack when the driver queries it on its own behalf.
+const LOWER_QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
 
 kernel_callback!(
     fn internal_ioctl_cb(queue: WDFQUEUE, request: WDFREQUEST, _output_buffer_length: usize, _input_buffer_length: usize, io_control_code: ULONG) -> ()
@@ -155,22 +259,26 @@ kernel_callback!(
 
 
 fn internal_ioctl(queue: WDFQUEUE, request: WDFREQUEST, io_control_code: ULONG) {
-    dbg!("internal_ioctl");
+    log_trace!("internal_ioctl");
 
-    let queue = Queue::new(queue);
-    let mut device = queue.get_device::<DeviceContext>();
-    dbg!("internal_ioctl - got device");
+    let queue = unsafe { Queue::<DeviceContext>::new(queue) };
+    let mut device = queue.device();
+    log_trace!("internal_ioctl - got device");
 
     let res = match KeyboardIoctl::try_from(io_control_code) {
         Ok(KeyboardIoctl::KeyboardConnect) =>
             on_keyboard_connect(request, &mut device).map(|_| false),
         Ok(KeyboardIoctl::KeyboardDisconnect) => {
-            dbg!("Keyboard disconnect");
+            log_trace!("Keyboard disconnect");
+            // Through the attached devices lock, which raises to the IRQL the class driver expects
+            ATTACHED.lock().with_device(device.context().instance, |device| release_held_keys(device.context()));
             device.context_mut().upper_connect_data = ConnectData::default();
+            device.context().key_state.lock().reset();
+            device.context().statistics.on_disconnect();
             Ok(false)
         }
         Ok(KeyboardIoctl::KeyboardQueryAttributes) => {
-            dbg!("Keyboard query attributes");
+            log_trace!("Keyboard query attributes");
             Ok(true)
         }
         _ => Ok(false),
@@ -181,14 +289,16 @@ fn internal_ioctl(queue: WDFQUEUE, request: WDFREQUEST, io_control_code: ULONG)
     let forward_request = match res {
         Ok(forward_request) => forward_request,
         Err(e) => {
+            log_warn!("internal_ioctl {io_control_code:#X} failed: {e}");
             request.complete(e.nt_status());
             return;
         }
     };
 
     if !forward_request {
-        if let Err(e) = request.send(device.io_target(), WDF_REQUEST_SEND_OPTION_SEND_AND_FORGET as u32) {
-            dbg!(&e, request.complete(e.nt_status()));
+        if let Err(e) = request.send(&device.io_target(), WDF_REQUEST_SEND_OPTION_SEND_AND_FORGET as u32) {
+            log_error!("Forwarding internal ioctl {io_control_code:#X} failed: {e}");
+            request.complete(e.nt_status());
         }
         return;
     }
@@ -201,20 +311,21 @@ fn internal_ioctl(queue: WDFQUEUE, request: WDFREQUEST, io_control_code: ULONG)
         }
     };
 
-    if let Err(e) = request.format_for_internal_ioctl(device.io_target(), io_control_code, output_memory) {
-        dbg!(&e, request.complete(e.nt_status()));
+    let io_target = device.io_target();
+    if let Err(e) = io_target.format_internal_ioctl(&mut request, io_control_code, None, Some(output_memory)) {
+        log_error!("Formatting internal ioctl {io_control_code:#X} failed: {e}");
+        request.complete(e.nt_status());
         return;
     }
 
-    request.set_completion_callback(Some(completion_routine), device.context_mut() as *mut _ as PVOID);
-
-    if let Err(e) = request.send(device.io_target(), WDF_REQUEST_SEND_OPTION_SEND_AND_FORGET as u32) {
-        dbg!(&e, request.complete(e.nt_status()));
+    if let Err(e) = request.send_with_completion(&io_target, device.context_mut(), on_query_attributes_completed) {
+        log_error!("Sending internal ioctl {io_control_code:#X} failed: {e}");
+        request.complete(e.nt_status());
     }
 }
 
 fn on_keyboard_connect(request: WDFREQUEST, device: &mut Device<DeviceContext>) -> Result<()> {
-    dbg!("Keyboard connect");
+    log_trace!("Keyboard connect");
 
     let mut request = KeyboardConnectRequest::new(unsafe { request.as_mut().expect("Request is null") });
 
@@ -223,73 +334,108 @@ fn on_keyboard_connect(request: WDFREQUEST, device: &mut Device<DeviceContext>)
         STATUS_SHARING_VIOLATION.check_status(ErrorCode::SharingViolation)?;
     }
 
-    let mut connect_data = dbg!(request.connect_data())?;
+    let class_device_object = device.device_object();
+    let connect_data = request.connect_data()?;
 
-    device.context_mut().upper_connect_data = connect_data;
+    device.context_mut().upper_connect_data = *connect_data;
+    device.context_mut().block_state.reset();
+    device.context().key_state.lock().reset();
 
-    connect_data.class_device_object = dbg!(device.device_object());
+    connect_data.class_device_object = class_device_object;
     connect_data.class_service = service_callback as PVOID;
 
+    device.context().statistics.on_connect();
+
     Ok(())
 }
 
-extern "C" fn pdo_from_ioctl(queue: WDFQUEUE, request: WDFREQUEST, output_buffer_length: usize, _input_buffer_length: usize, io_control_code: ULONG) {
-    println!("WAWAWA pdo_from_ioctl 1");
+extern "C" fn pdo_from_ioctl(queue: WDFQUEUE, request: WDFREQUEST, _output_buffer_length: usize, _input_buffer_length: usize, io_control_code: ULONG) {
+    log_trace!("pdo_from_ioctl");
 
-    let mut status = STATUS_NOT_IMPLEMENTED;
-    let mut bytes_transferred: ULONG_PTR = 0;
-    if io_control_code == PdoKeyboardAttributes as u32 {
-        if output_buffer_length < core::mem::size_of::<KeyboardAttributes>() {
-            status = STATUS_BUFFER_TOO_SMALL;
-        } else {
-            let mut output_memory = core::ptr::null_mut() as WDFMEMORY;
-            status = unsafe {
-                call_unsafe_wdf_function_binding!(
-                    WdfRequestRetrieveOutputMemory,
-                    request,
-                    &mut output_memory
-                )
-            };
-
-            if nt_success(status) {
-                let handle = queue as WDFOBJECT;
-                let device_context: &mut DeviceContext = unsafe { wdf_object_get_device_context(handle).as_mut().unwrap() }; // TODO: Handle this better.
-                status = unsafe {
-                    call_unsafe_wdf_function_binding!(
-                        WdfMemoryCopyFromBuffer,
-                        output_memory,
-                        0,
-                        (&mut device_context.keyboard_attributes) as *mut _ as *mut _,
-                        core::mem::size_of::<KeyboardAttributes>(),
-                    )
-                };
-
-                if nt_success(status) {
-                    status = STATUS_SUCCESS;
-                    bytes_transferred = core::mem::size_of::<KeyboardAttributes>() as ULONG_PTR;
-                } else {
-                    println!("WAWAWA WdfMemoryCopyFromBuffer failed {status:#010X}");
-                }
-            } else {
-                println!("WAWAWA WdfRequestRetrieveOutputMemory failed {status:#010X}");
-            }
+    let queue = unsafe { Queue::<DeviceContext>::new(queue) };
+    let mut device = queue.device();
+    let mut request = Request::new(unsafe { request.as_mut().expect("Request is null") });
+
+    let ioctl = KeyboardIoctl::try_from(io_control_code);
+    if let Ok(ioctl) = &ioctl {
+        if let Err(e) = check_access(&mut request, ioctl.required_access()) {
+            log_warn!("pdo_from_ioctl {io_control_code:#X} denied: {e}");
+            request.complete(e.nt_status());
+            return;
         }
     }
 
-    unsafe {
-        call_unsafe_wdf_function_binding!(
-            WdfRequestCompleteWithInformation,
-            request,
-            status,
-            bytes_transferred,
-        )
+    let res = match ioctl {
+        Ok(KeyboardIoctl::PdoKeyboardAttributes) =>
+            request.write_output(&device.context().keyboard_attributes),
+        Ok(KeyboardIoctl::PdoKeyboardIndicators) =>
+            query_lower_stack::<KeyboardIndicatorParameters>(&mut device, KeyboardIoctl::KeyboardQueryIndicators)
+                .and_then(|indicators| request.write_output(&indicators)),
+        Ok(KeyboardIoctl::PdoKeyboardTypematic) =>
+            query_lower_stack::<KeyboardTypematicParameters>(&mut device, KeyboardIoctl::KeyboardQueryTypematic)
+                .and_then(|typematic| request.write_output(&typematic)),
+        Ok(KeyboardIoctl::PdoSetLogLevel) =>
+            request.read_input::<LogLevelRequest>()
+                .and_then(|log_level| apply_log_level(&log_level))
+                .map(|_| 0),
+        Ok(KeyboardIoctl::PdoGetLogRecords) => copy_log_records(&mut request),
+        Ok(KeyboardIoctl::PdoGetStatistics) =>
+            request.write_output(&device.context().statistics.snapshot()),
+        Ok(KeyboardIoctl::PdoResetStatistics) => {
+            device.context().statistics.reset();
+            Ok(0)
+        }
+        _ => STATUS_NOT_IMPLEMENTED.check_status(ErrorCode::UnsupportedIoctl).map(|_| 0),
     };
+
+    match res {
+        Ok(bytes_transferred) => request.complete_with_information(STATUS_SUCCESS, bytes_transferred),
+        Err(e) => {
+            log_warn!("pdo_from_ioctl {io_control_code:#X} failed: {e}");
+            request.complete(e.nt_status());
+        }
+    }
+}
+
+fn copy_log_records(request: &mut Request) -> Result<usize> {
+    let buffer = request.output_buffer(log_ring::HEADER_SIZE)?;
+    let length = buffer.len();
+
+    // The error logs, which takes the ring's lock, so it's built once the guard is gone
+    let encoded = log::RING.lock().encode(buffer);
+    encoded.ok_or_else(|| Error::buffer_too_small(ErrorCode::IoctlOutputTooSmall, log_ring::HEADER_SIZE, length))
+}
+
+fn apply_log_level(request: &LogLevelRequest) -> Result<()> {
+    let level = u8::try_from(request.level).ok()
+        .and_then(|level| Level::try_from(level).ok())
+        .ok_or_else(|| Error::invalid_buffer(ErrorCode::IoctlInputInvalid))?;
+
+    let target_length = request.target.iter().position(|byte| *byte == 0).unwrap_or(request.target.len());
+    let target = core::str::from_utf8(&request.target[..target_length])
+        .map_err(|_| Error::invalid_buffer(ErrorCode::IoctlInputInvalid))?;
+
+    if target.is_empty() {
+        log::set_max_level(level);
+    } else if !log::set_target_level(target, level) {
+        return Err(Error::invalid_buffer(ErrorCode::IoctlInputInvalid));
+    }
+
+    log_debug!("Log level of '{target}' set to {}", level.as_str());
+    Ok(())
+}
+
+/// Queries the keyboard port driver directly, without waiting for the class driver to ask first.
+fn query_lower_stack<T: Copy + Default>(device: &mut Device<DeviceContext>, ioctl: KeyboardIoctl) -> Result<T> {
+    let mut output = T::default();
+    device.io_target().send_internal_ioctl_sync::<(), T>(ioctl.into(), None, Some(&mut output), Some(LOWER_QUERY_TIMEOUT))?;
+    Ok(output)
 }
 
 extern "C" fn pdo_to_ioctl(queue: WDFQUEUE, request: WDFREQUEST, _output_buffer_length: usize, _input_buffer_length: usize, io_control_code: ULONG) {
-    println!("WAWAWA pdo_to_ioctl 1");
+    log_trace!("pdo_to_ioctl {io_control_code:#X}");
 
-    if io_control_code == PdoKeyboardAttributes as u32 {
+    if KeyboardIoctl::try_from(io_control_code).is_ok_and(|ioctl| ioctl.is_forwarded_from_pdo()) {
         let forward_options = WDF_REQUEST_SEND_OPTIONS {
             Size: core::mem::size_of::<WDF_REQUEST_SEND_OPTIONS>() as ULONG,
             Flags: WDF_REQUEST_SEND_OPTION_SEND_AND_FORGET as ULONG,
@@ -302,14 +448,8 @@ extern "C" fn pdo_to_ioctl(queue: WDFQUEUE, request: WDFREQUEST, _output_buffer_
         }
          */
 
-        let parent = unsafe {
-            call_unsafe_wdf_function_binding!(
-                WdfIoQueueGetDevice,
-                queue
-            )
-        };
-
-        let pdo_context = unsafe { get_pdo_context(parent as WDFOBJECT) };
+        let queue = unsafe { Queue::<PdoContext>::new(queue) };
+        let parent_queue = queue.device().context().queue;
 
         /*        status = WdfRequestForwardToParentDeviceIoQueue(Request, pdoData->ParentQueue, &forwardOptions);
         if (!NT_SUCCESS(status)) {
@@ -321,13 +461,13 @@ extern "C" fn pdo_to_ioctl(queue: WDFQUEUE, request: WDFREQUEST, _output_buffer_
             call_unsafe_wdf_function_binding!(
                 WdfRequestForwardToParentDeviceIoQueue,
                 request,
-                (*pdo_context).queue,
+                parent_queue,
                 &forward_options as *const _ as *mut _,
             )
         };
 
         if !nt_success(status) {
-            println!("WAWAWA WdfRequestForwardToParentDeviceIoQueue failed {status:#010X}");
+            log_error!("WdfRequestForwardToParentDeviceIoQueue failed {}", NtStatusName(status));
             unsafe {
                 call_unsafe_wdf_function_binding!(
                     WdfRequestComplete,
@@ -352,8 +492,6 @@ type ServiceCallback = extern "C" fn(device_object: PDEVICE_OBJECT, input_data_s
 
 
 unsafe extern "C" fn service_callback(device_object: PDEVICE_OBJECT, input_data_start: *mut KeyboardInputData, input_data_end: *mut KeyboardInputData, input_data_consumed: PULONG) {
-    println!("WAWAWA service_callback 1");
-
     let device = call_unsafe_wdf_function_binding!(
         WdfWdmDeviceGetWdfDeviceHandle,
         device_object
@@ -362,48 +500,253 @@ unsafe extern "C" fn service_callback(device_object: PDEVICE_OBJECT, input_data_
     let device_context: &mut DeviceContext =
         unsafe { wdf_object_get_device_context(device as WDFOBJECT).as_mut().unwrap() }; // TODO: Handle this better.
 
-    println!("WAWAWA Service callback called for device");
     let input_data_length = (input_data_end as usize - input_data_start as usize) / core::mem::size_of::<KeyboardInputData>();
-    if input_data_length > 0 {
+    if input_data_length > 0 && log::enabled(Level::Trace, core::module_path!()) {
         let input_data_slice = unsafe { core::slice::from_raw_parts(input_data_start, input_data_length) };
-        for (i, input_data) in input_data_slice.iter().enumerate() {
-            dbg!((i, input_data));
+        log_trace!("service_callback: {}", StrokeSummary(input_data_slice));
+        if LOG_KEYSTROKES {
+            for (i, input_data) in input_data_slice.iter().enumerate() {
+                log_trace!("stroke {i}: {input_data:?}");
+            }
         }
     }
 
 
-    if !device_context.upper_connect_data.class_service.is_null() {
+    device_context.statistics.on_input(input_data_length, time::system_time());
+
+    if device_context.upper_connect_data.class_service.is_null() {
+        return;
+    }
+
+    let input_data_slice = unsafe { core::slice::from_raw_parts(input_data_start, input_data_length) };
+    if escape::detect(device_context, input_data_slice) {
+        escape::engage(device_context.instance);
+    }
+
+    let filter = device_context.filter.load(Ordering::Relaxed);
+    let capturing = !device_context.stalled.load(Ordering::Relaxed) && (filter != FILTER_KEY_NONE || any_target_filter());
+    // Copied so the lock isn't held while the class driver runs
+    let rules = *BLOCK_RULES.lock();
+    let consumed = if !capturing && rules.is_empty() {
         let callback: ServiceCallback = unsafe { core::mem::transmute(device_context.upper_connect_data.class_service) };
 
         callback(device_context.upper_connect_data.class_device_object, input_data_start, input_data_end, input_data_consumed);
+
+        let consumed = (unsafe { *input_data_consumed } as usize).min(input_data_length);
+        track_delivered(device_context, &input_data_slice[..consumed]);
+        device_context.statistics.on_passed(consumed);
+        if consumed < input_data_length {
+            device_context.statistics.on_partial_consumption();
+        }
+        consumed
+    } else {
+        let consumed = capture_and_forward(device_context, input_data_slice, capturing.then_some(filter), rules.as_slice());
+        unsafe { *input_data_consumed = consumed as ULONG };
+        consumed
+    };
+
+    // Strokes past `consumed` stay with the port driver, which delivers them again.
+    let mut key_state = device_context.key_state.lock();
+    for stroke in &input_data_slice[..consumed] {
+        key_state.feed(RawStroke::from(*stroke));
     }
 }
 
-unsafe extern "C" fn completion_routine(request: WDFREQUEST, _handle: WDFIOTARGET, params: *mut WDF_REQUEST_COMPLETION_PARAMS, context: WDFCONTEXT) {
-    println!("WAWAWA completion_routine 1");
-
-    let params_ioctl = unsafe { &mut (*params).Parameters.Ioctl };
-    let params_status = unsafe { (*params).IoStatus.__bindgen_anon_1.Status };
-
-    let buffer = params_ioctl.Output.Buffer;
-    let mut status = params_status;
-
-    if nt_success(status) && unsafe { (*params).Type } == WdfRequestTypeDeviceControlInternal && params_ioctl.IoControlCode == KeyboardIoctl::KeyboardQueryAttributes as u32 && params_ioctl.Output.Length >= core::mem::size_of::<KeyboardAttributes>() {
-        let device_context: &mut DeviceContext = unsafe { core::mem::transmute(context) };
-        status = call_unsafe_wdf_function_binding!(
-            WdfMemoryCopyToBuffer,
-            buffer,
-            params_ioctl.Output.Offset,
-            (&mut device_context.keyboard_attributes) as *mut _ as *mut _,
-            core::mem::size_of::<KeyboardAttributes>(),
-        );
-        dbg!(device_context.keyboard_attributes);
+/// Number of forwarded strokes buffered before they are handed to the class driver.
+const FORWARD_CHUNK: usize = 64;
+
+type Forwarded = ForwardBuffer<KeyboardInputData, FORWARD_CHUNK>;
+
+/// How the filter keeps a stroke from the class driver.
+#[derive(Debug, Copy, Clone)]
+enum Kept {
+    Blocked,
+    Captured,
+}
+
+/// Picks the strokes of one keyboard's batch to block or capture, counting those it kept.
+struct StrokeClassifier<'a> {
+    block_state: BlockState,
+    rules: &'a [BlockRule],
+    target_match: TargetMatch,
+    filter: Option<u16>,
+    timestamp: u64,
+    device: u32,
+    blocked: usize,
+    captured: usize,
+}
+
+impl Classifier<KeyboardInputData> for StrokeClassifier<'_> {
+    type Kept = Kept;
+
+    fn classify(&mut self, stroke: &KeyboardInputData) -> Option<Kept> {
+        let targets = self.target_match.mask(stroke.unit_id);
+        if self.block_state.should_block(self.rules, targets, RawStroke::from(*stroke)) {
+            Some(Kept::Blocked)
+        } else if self.filter.is_some_and(|filter| filter_matches(filter | TargetMatch::filter(targets), stroke.flags)) {
+            Some(Kept::Captured)
+        } else {
+            None
+        }
     }
 
-    call_unsafe_wdf_function_binding!(
-        WdfRequestComplete,
-        request,
-        status
-    );
+    fn keep(&mut self, stroke: &KeyboardInputData, kept: Kept) -> bool {
+        match kept {
+            Kept::Blocked => self.blocked += 1,
+            Kept::Captured => {
+                if !store_captured(TimedStroke { data: *stroke, timestamp: self.timestamp, device: self.device, reserved: 0 }) {
+                    return false;
+                }
+                self.captured += 1;
+            }
+        }
+        true
+    }
+}
+
+/// Drops the strokes matching a block rule, moves those matching `filter` or the filter of a target
+/// they fall under to the capture queue and hands the rest to the class driver. Strokes are passed
+/// through instead when the capture queue is full, or `filter` is `None` as the keyboard stalled.
+///
+/// Returns how many strokes of the batch were dealt with, as [`ForwardBuffer::run`] does.
+fn capture_and_forward(context: &mut DeviceContext, strokes: &[KeyboardInputData], filter: Option<u16>, rules: &[BlockRule]) -> usize {
+    let mut classifier = StrokeClassifier {
+        // Put back once the batch is done, as forwarding borrows the whole context meanwhile
+        block_state: context.block_state,
+        rules,
+        target_match: TargetMatch::for_device(context),
+        filter,
+        // Strokes of one batch were delivered together, so they share a timestamp.
+        timestamp: time::performance_counter(),
+        device: context.instance,
+        blocked: 0,
+        captured: 0,
+    };
+
+    let shared: &DeviceContext = context;
+    let consumed = Forwarded::run(KeyboardInputData::EMPTY, strokes, &mut classifier, |pending| {
+        let consumed = forward_to_class(shared, pending);
+        shared.statistics.on_passed(consumed);
+        if consumed < pending.len() {
+            shared.statistics.on_partial_consumption();
+        }
+        consumed
+    });
+
+    context.block_state = classifier.block_state;
+    context.statistics.on_blocked(classifier.blocked);
+    context.statistics.on_captured(classifier.captured);
+    if classifier.captured > 0 {
+        control::complete_pending_reads();
+        shared_ring::signal();
+    }
+    consumed
+}
+
+/// Hands a captured stroke to the shared capture ring if one is mapped, else to the capture queue.
+/// Returns `false` when it's full.
+fn store_captured(stroke: TimedStroke) -> bool {
+    shared_ring::push(stroke).unwrap_or_else(|| CAPTURED.push(stroke))
+}
+
+/// Passes strokes to the class driver, returning how many it consumed.
+fn forward_to_class(context: &DeviceContext, strokes: &mut [KeyboardInputData]) -> usize {
+    if context.upper_connect_data.class_service.is_null() {
+        return 0;
+    }
+
+    let callback: ServiceCallback = unsafe { core::mem::transmute(context.upper_connect_data.class_service) };
+    let range = strokes.as_mut_ptr_range();
+    let mut consumed: ULONG = 0;
+    callback(context.upper_connect_data.class_device_object, range.start, range.end, &mut consumed);
+
+    let consumed = (consumed as usize).min(strokes.len());
+    track_delivered(context, &strokes[..consumed]);
+    consumed
+}
+
+/// Records which keys the system now believes held, after the class driver consumed `strokes`.
+fn track_delivered(context: &DeviceContext, strokes: &[KeyboardInputData]) {
+    let mut delivered = context.delivered.lock();
+    for stroke in strokes {
+        let stroke = RawStroke::from(*stroke);
+        if let Some(scan_code) = stroke.scan_code() {
+            if stroke.is_break() {
+                delivered.remove(scan_code);
+            } else {
+                delivered.insert(scan_code);
+            }
+        }
+    }
 }
 
+/// Number of synthesized breaks handed to the class driver per call.
+const RELEASE_CHUNK: usize = 16;
+
+/// Hands the class driver a break for every key it saw go down but not up, returning how many it consumed.
+pub(crate) fn release_held_keys(context: &DeviceContext) -> usize {
+    let held = core::mem::take(&mut *context.delivered.lock());
+
+    let mut breaks = [KeyboardInputData::EMPTY; RELEASE_CHUNK];
+    let mut len = 0;
+    let mut released = 0;
+    for scan_code in held.iter() {
+        breaks[len] = KeyboardInputData::from(RawStroke::release(scan_code));
+        len += 1;
+        if len == RELEASE_CHUNK {
+            released += inject_strokes(context, &mut breaks[..len]);
+            len = 0;
+        }
+    }
+    if len > 0 {
+        released += inject_strokes(context, &mut breaks[..len]);
+    }
+
+    if released > 0 {
+        context.statistics.on_keys_released(released);
+        log_debug!("Released {released} held keys of device {}", context.instance);
+    }
+    released
+}
+
+/// Hands a captured stroke to its keyboard's class driver after all, returning whether it was consumed.
+pub(crate) fn forward_captured(stroke: &TimedStroke) -> bool {
+    let mut data = [stroke.data];
+    ATTACHED.lock()
+        .with_device(stroke.device, |device| {
+            let consumed = forward_to_class(device.context(), &mut data);
+            device.context().statistics.on_passed(consumed);
+            consumed == 1
+        })
+        .unwrap_or(false)
+}
+
+/// Hands strokes to the class driver as if the keyboard had produced them, returning how many it consumed.
+/// Must be called at `DISPATCH_LEVEL`, like the port driver's own calls.
+pub(crate) fn inject_strokes(context: &DeviceContext, strokes: &mut [KeyboardInputData]) -> usize {
+    let consumed = forward_to_class(context, strokes);
+    context.statistics.on_injected(consumed);
+    consumed
+}
+
+fn on_query_attributes_completed(request: &mut Request, params: &CompletionParams, context: &mut DeviceContext) {
+    log_trace!("on_query_attributes_completed");
+
+    let mut status = params.status();
+
+    if let CompletionKind::Ioctl(ioctl) = params.kind() {
+        if nt_success(status) && ioctl.internal && ioctl.io_control_code == KeyboardIoctl::KeyboardQueryAttributes as u32 && ioctl.output_length >= core::mem::size_of::<KeyboardAttributes>() {
+            if let Some(output) = ioctl.output {
+                match output.read::<KeyboardAttributes>(ioctl.output_offset) {
+                    Ok(attributes) => {
+                        log_debug!("Keyboard attributes: {attributes:?}");
+                        context.keyboard_attributes = attributes;
+                    }
+                    Err(e) => status = e.nt_status(),
+                }
+            }
+        }
+    }
+
+    request.complete_with_information(status, params.information());
+}
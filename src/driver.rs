@@ -1,21 +1,81 @@
+use nt_string::nt_unicode_str;
+use nt_string::unicode_string::NtUnicodeString;
 use wdk_sys::{WDFDRIVER, *};
 use wdk_sys::ntddk::KeGetCurrentIrql;
-use crate::{dbg, driver_entry, kernel_callback};
+use crate::{batching, blocking, driver_entry, escape, kernel_callback, log_debug, log_error, log_info, targeting, watchdog};
 use crate::framework::*;
+use crate::framework::log::{Level, set_max_level};
+use crate::framework::registry::RegistryKey;
+use crate::framework::security::set_default_sddl;
 
 extern crate alloc;
 
 driver_entry!(fn (driver, registry_path) {
-    dbg!(DriverInit::new(driver)
+    let res = DriverInit::new(driver)
         .device_add(Some(device_add))
-        .create(registry_path)
-        .to_status())
+        .create(registry_path);
+
+    match &res {
+        Ok(driver) => {
+            configure_logging(*driver);
+            configure_security(*driver);
+            batching::load_from_registry(*driver);
+            blocking::load_from_registry(*driver);
+            targeting::load_from_registry(*driver);
+            escape::load_from_registry(*driver);
+            watchdog::load_from_registry(*driver);
+        }
+        Err(e) => log_error!("DriverEntry failed: {e}"),
+    }
+
+    res.to_status()
     });
 
+/// Applies the `LogLevel` value from the driver's `Parameters` key, if present.
+fn configure_logging(driver: WDFDRIVER) {
+    let level = RegistryKey::open_driver_parameters_for_read(driver)
+        .and_then(|key| key.query_u32(nt_unicode_str!("LogLevel")));
+
+    match level.map(|level| u8::try_from(level).ok().and_then(|level| Level::try_from(level).ok())) {
+        Ok(Some(level)) => {
+            set_max_level(level);
+            log_info!("Log level set to {} from the registry", level.as_str());
+        }
+        Ok(None) => log_error!("Ignoring invalid LogLevel registry value"),
+        Err(_) => log_debug!("No LogLevel registry value, keeping the default"),
+    }
+}
+
+/// Longest `Sddl` registry value accepted, in UTF-16 code units.
+const SDDL_MAX_LENGTH: usize = 256;
+
+/// Applies the `Sddl` value from the driver's `Parameters` key, if present, to the raw PDOs and the control device.
+fn configure_security(driver: WDFDRIVER) {
+    let mut buffer = [0u16; SDDL_MAX_LENGTH];
+    let length = RegistryKey::open_driver_parameters_for_read(driver)
+        .and_then(|key| key.query_string(nt_unicode_str!("Sddl"), &mut buffer));
+
+    match length.map(|length| NtUnicodeString::try_from_u16(&buffer[..length])) {
+        Ok(Ok(sddl)) if !sddl.is_empty() => {
+            set_default_sddl(sddl);
+            log_info!("Device SDDL set from the registry");
+        }
+        Ok(_) => log_error!("Ignoring invalid Sddl registry value"),
+        Err(_) => log_debug!("No Sddl registry value, keeping the default"),
+    }
+}
+
 kernel_callback!(
-    fn device_add(_driver: WDFDRIVER, device_init: PWDFDEVICE_INIT) -> NTSTATUS {
-        dbg!(crate::device::device_create(
+    fn device_add(driver: WDFDRIVER, device_init: PWDFDEVICE_INIT) -> NTSTATUS {
+        let res = crate::device::device_create(
+            driver,
             unsafe { device_init.as_mut() }.expect("device_init is null"),
-        ).to_status())
+        );
+
+        if let Err(e) = &res {
+            log_error!("device_add failed: {e}");
+        }
+
+        res.to_status()
     }
 );
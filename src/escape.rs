@@ -0,0 +1,130 @@
+//! The escape chord, a way out when a client hangs while capturing a keyboard.
+//!
+//! It is checked in `service_callback` before any filtering. Once typed on any keyboard, every
+//! capture filter is dropped, the shared rings are taken away from their client, the strokes still
+//! waiting in the capture queue or unread in the shared capture ring are handed to their keyboard's
+//! class driver, keys the system saw go down get their breaks and clients waiting for
+//! notifications are told. Clients have to set their filters again. Block rules stay in place, so
+//! the chord can't be used to get out of a kiosk's lockdown.
+//!
+//! The chord defaults to left Control + left Alt + left Shift + F12, and is replaced by the
+//! `EscapeChord` `REG_BINARY` value, an array of `KEYBOARD_INPUT_DATA` style `MakeCode` and
+//! `Flags` pairs. An empty value disables the chord.
+
+use core::ptr::null_mut;
+use core::sync::atomic::Ordering;
+use nt_string::nt_unicode_str;
+use wdk_sys::{STATUS_CANCELLED, WDFDRIVER};
+use crate::{control, DeviceContext, log_debug, log_error, log_info, log_warn, shared_ring};
+use crate::capture::CAPTURED;
+use crate::device::{forward_captured, release_held_keys};
+use crate::foreign::KeyboardInputData;
+use crate::framework::registry::RegistryKey;
+use crate::framework::spin_lock::SpinLock;
+use crate::framework::utils::read_from_buffer;
+use crate::instances::ATTACHED;
+use crate::keyboard::chord::{Chord, MAX_CHORD_KEYS};
+use crate::keyboard::scan_code::ScanCode;
+use crate::keyboard::sequence::RawStroke;
+use crate::protocol::{FILTER_KEY_NONE, NOTIFICATION_ESCAPE};
+use crate::targeting::{TARGET_FILTER_OWNERS, TARGET_FILTERS};
+
+const DEFAULT_ESCAPE_CHORD: Chord = match Chord::new(&[ScanCode::new(0x1D), ScanCode::new(0x38), ScanCode::new(0x2A), ScanCode::new(0x58)]) {
+    Some(chord) => chord,
+    None => panic!("Too many keys"),
+};
+
+static ESCAPE_CHORD: SpinLock<Chord> = SpinLock::new(DEFAULT_ESCAPE_CHORD);
+
+/// Feeds a batch to the keyboard's chord detector, returning whether it completed the escape chord.
+pub(crate) fn detect(context: &mut DeviceContext, strokes: &[KeyboardInputData]) -> bool {
+    let chord = *ESCAPE_CHORD.lock();
+    let mut detected = false;
+    for stroke in strokes {
+        detected |= context.chord_detector.feed(&chord, RawStroke::from(*stroke));
+    }
+    detected
+}
+
+/// Stops capturing on every keyboard. `device_id` is the keyboard the chord was typed on.
+pub(crate) fn engage(device_id: u32) {
+    log_warn!("Escape chord typed on device {device_id}, dropping all capture filters");
+
+    {
+        let attached = ATTACHED.lock();
+        for instance in attached.iter() {
+            attached.with_device(instance.id, |device| {
+                device.context().filter.store(FILTER_KEY_NONE, Ordering::Relaxed);
+                device.context().filter_owner.store(null_mut(), Ordering::Relaxed);
+            });
+        }
+    }
+    for (filter, owner) in TARGET_FILTERS.iter().zip(&TARGET_FILTER_OWNERS) {
+        filter.store(FILTER_KEY_NONE, Ordering::Relaxed);
+        owner.store(null_mut(), Ordering::Relaxed);
+    }
+
+    let mut flushed = 0;
+    loop {
+        // Not held while forwarding, which takes the attached devices lock
+        let stroke = CAPTURED.lock().pop();
+        let Some(stroke) = stroke else {
+            break;
+        };
+        flushed += usize::from(forward_captured(&stroke));
+    }
+    flushed += shared_ring::revoke(STATUS_CANCELLED, "revoked by the escape chord");
+
+    let attached = ATTACHED.lock();
+    let released: usize = attached.iter()
+        .filter_map(|instance| attached.with_device(instance.id, |device| release_held_keys(device.context())))
+        .sum();
+    drop(attached);
+
+    log_debug!("Escape flushed {flushed} captured strokes and released {released} keys");
+    control::notify(NOTIFICATION_ESCAPE, device_id);
+}
+
+/// Applies the `EscapeChord` value from the driver's `Parameters` key, if present.
+pub(crate) fn load_from_registry(driver: WDFDRIVER) {
+    const PAIR_SIZE: usize = 2 * core::mem::size_of::<u16>();
+
+    let mut buffer = [0u8; MAX_CHORD_KEYS * PAIR_SIZE];
+    let length = RegistryKey::open_driver_parameters_for_read(driver)
+        .and_then(|key| key.query_binary(nt_unicode_str!("EscapeChord"), &mut buffer));
+
+    let length = match length {
+        Ok(length) if length % PAIR_SIZE == 0 => length,
+        Ok(_) => {
+            log_error!("Ignoring EscapeChord registry value of invalid size");
+            return;
+        }
+        Err(_) => {
+            log_debug!("No EscapeChord registry value, keeping the default");
+            return;
+        }
+    };
+
+    let mut keys = [ScanCode::new(0); MAX_CHORD_KEYS];
+    let count = length / PAIR_SIZE;
+    for (i, key) in keys[..count].iter_mut().enumerate() {
+        let make_code = read_from_buffer::<u16>(&buffer, i * PAIR_SIZE).unwrap_or_default();
+        let flags = read_from_buffer::<u16>(&buffer, i * PAIR_SIZE + 2).unwrap_or_default();
+        match ScanCode::from_input(make_code, flags) {
+            Some(scan_code) => *key = scan_code,
+            None => {
+                log_error!("Ignoring EscapeChord registry value with invalid make code {make_code:#X}");
+                return;
+            }
+        }
+    }
+
+    if let Some(chord) = Chord::new(&keys[..count]) {
+        *ESCAPE_CHORD.lock() = chord;
+        if chord.is_empty() {
+            log_info!("Escape chord disabled from the registry");
+        } else {
+            log_info!("Escape chord of {count} keys set from the registry");
+        }
+    }
+}
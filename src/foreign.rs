@@ -1,4 +1,6 @@
 use wdk_sys::{GUID, PDEVICE_OBJECT, PVOID};
+use crate::any_bit_pattern;
+use crate::keyboard::sequence::RawStroke;
 
 /*DEFINE_GUID( CLASS_KEYBOARD,            0x4d36e96bL, 0xe325, 0x11ce, 0xbf, 0xc1, 0x08, 0x00, 0x2b, 0xe1, 0x03, 0x18 );*/
 pub static GUID_CLASS_KEYBOARD: GUID = GUID {
@@ -9,13 +11,20 @@ pub static GUID_CLASS_KEYBOARD: GUID = GUID {
 };
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct KeyboardTypematicParameters {
     pub unit_id: u16,
     pub rate: u16,
     pub delay: u16,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KeyboardIndicatorParameters {
+    pub unit_id: u16,
+    pub led_flags: u16,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct KeyboardId {
@@ -24,8 +33,14 @@ pub struct KeyboardId {
 }
 
 
+pub const KEY_MAKE: u16 = 0;
+pub const KEY_BREAK: u16 = 1;
+pub const KEY_E0: u16 = 2;
+pub const KEY_E1: u16 = 4;
+
+// Debug is implemented in `privacy`, which redacts the scan code.
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone)]
 pub struct KeyboardInputData {
     pub unit_id: u16,
     pub make_code: u16,
@@ -34,6 +49,35 @@ pub struct KeyboardInputData {
     pub extra_information: u32,
 }
 
+impl KeyboardInputData {
+    pub const EMPTY: Self = Self {
+        unit_id: 0,
+        make_code: 0,
+        flags: 0,
+        reserved: 0,
+        extra_information: 0,
+    };
+}
+
+impl From<RawStroke> for KeyboardInputData {
+    fn from(stroke: RawStroke) -> Self {
+        Self {
+            make_code: stroke.make_code,
+            flags: stroke.flags,
+            ..Self::EMPTY
+        }
+    }
+}
+
+impl From<KeyboardInputData> for RawStroke {
+    fn from(input: KeyboardInputData) -> Self {
+        Self {
+            make_code: input.make_code,
+            flags: input.flags,
+        }
+    }
+}
+
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -54,3 +98,5 @@ pub struct ConnectData {
     pub class_device_object: PDEVICE_OBJECT,
     pub class_service: PVOID,
 }
+
+any_bit_pattern!(KeyboardId, KeyboardTypematicParameters, KeyboardInputData, KeyboardAttributes);
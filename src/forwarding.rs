@@ -0,0 +1,235 @@
+//! Accounting for a service callback batch whose strokes are partly forwarded to the class driver.
+//!
+//! The port driver drops the strokes reported consumed and delivers the rest again later, so the
+//! count must cover a prefix of the batch in which every stroke was either kept by the filter,
+//! captured or blocked, or taken by the class driver. Forwarded strokes wait in a [`ForwardBuffer`]
+//! along with their index in the batch. It is handed to the class driver when full, before a
+//! stroke is kept and at the end of the batch, and what the class driver leaves is offered again
+//! as long as it takes some. Once it takes none, the batch ends at the first stroke it left.
+//! [`ForwardBuffer::run`] deals with a whole batch that way, the [`Classifier`] picking the strokes
+//! the filter keeps.
+
+/// Picks the strokes of a batch the filter keeps, by capturing or blocking them, instead of forwarding them.
+pub trait Classifier<T> {
+    /// How a stroke is kept.
+    type Kept;
+
+    /// How `stroke` is to be kept, `None` to forward it. Called once per stroke, in order, up to
+    /// where the batch ends.
+    fn classify(&mut self, stroke: &T) -> Option<Self::Kept>;
+
+    /// Keeps `stroke`, once the class driver took every stroke forwarded before it. Returns `false`
+    /// to forward it after all.
+    fn keep(&mut self, stroke: &T, kept: Self::Kept) -> bool;
+}
+
+#[derive(Debug)]
+pub struct ForwardBuffer<T: Copy, const N: usize> {
+    strokes: [T; N],
+    /// Index in the batch of each stroke.
+    origins: [usize; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> ForwardBuffer<T, N> {
+    pub const fn new(empty: T) -> Self {
+        Self { strokes: [empty; N], origins: [0; N], len: 0 }
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Returns `false`, leaving the buffer unchanged, when it's full.
+    pub const fn push(&mut self, origin: usize, stroke: T) -> bool {
+        if self.is_full() {
+            return false;
+        }
+
+        self.strokes[self.len] = stroke;
+        self.origins[self.len] = origin;
+        self.len += 1;
+        true
+    }
+
+    /// The strokes to offer the class driver.
+    pub const fn pending_mut(&mut self) -> &mut [T] {
+        self.strokes.split_at_mut(self.len).0
+    }
+
+    /// Drops the first `consumed` strokes, which the class driver took.
+    pub fn consume(&mut self, consumed: usize) {
+        let consumed = consumed.min(self.len);
+        self.strokes.copy_within(consumed..self.len, 0);
+        self.origins.copy_within(consumed..self.len, 0);
+        self.len -= consumed;
+    }
+
+    /// The batch index of the first stroke the class driver left, `None` if it took them all.
+    pub const fn first_left(&self) -> Option<usize> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(self.origins[0])
+        }
+    }
+
+    /// Deals with a batch, handing the strokes `classifier` doesn't keep to `class`, which returns
+    /// how many of the strokes it's offered it took. Returns how many strokes of the batch were
+    /// dealt with: all of them, unless the class driver stopped taking strokes, in which case the
+    /// count ends at the first one it left.
+    pub fn run<C: Classifier<T>>(empty: T, strokes: &[T], classifier: &mut C, mut class: impl FnMut(&mut [T]) -> usize) -> usize {
+        let mut forwarded = Self::new(empty);
+        'strokes: {
+            for (index, stroke) in strokes.iter().enumerate() {
+                let kept = classifier.classify(stroke);
+
+                // A kept stroke is never delivered again, so the strokes forwarded before it must be taken first
+                if (kept.is_some() || forwarded.is_full()) && !forwarded.flush(&mut class) {
+                    break 'strokes;
+                }
+
+                if !kept.is_some_and(|kept| classifier.keep(stroke, kept)) {
+                    forwarded.push(index, *stroke);
+                }
+            }
+            forwarded.flush(&mut class);
+        }
+        forwarded.first_left().unwrap_or(strokes.len())
+    }
+
+    /// Hands the forwarded strokes to `class`, offering again what it leaves as long as it takes
+    /// some. Returns whether it took them all.
+    fn flush(&mut self, class: &mut impl FnMut(&mut [T]) -> usize) -> bool {
+        while !self.is_empty() {
+            let consumed = class(self.pending_mut());
+            self.consume(consumed);
+            if consumed == 0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_BATCH: usize = 7;
+    const BUFFER: usize = 3;
+
+    /// A class driver taking at most `per_call` strokes per call, and `capacity` overall.
+    struct Class {
+        capacity: usize,
+        per_call: usize,
+        taken: Vec<usize>,
+    }
+
+    impl Class {
+        fn take(&mut self, strokes: &[usize]) -> usize {
+            let count = strokes.len().min(self.per_call).min(self.capacity - self.taken.len());
+            self.taken.extend_from_slice(&strokes[..count]);
+            count
+        }
+    }
+
+    /// Keeps the strokes whose bit is set in `kept`, if `accepts` allows, recording those it kept.
+    struct Keeper {
+        kept: u32,
+        accepts: u32,
+        classified: Vec<usize>,
+        kept_done: u32,
+    }
+
+    impl Classifier<usize> for Keeper {
+        type Kept = ();
+
+        fn classify(&mut self, stroke: &usize) -> Option<()> {
+            self.classified.push(*stroke);
+            (self.kept & 1 << stroke != 0).then_some(())
+        }
+
+        fn keep(&mut self, stroke: &usize, (): ()) -> bool {
+            let accepted = self.accepts & 1 << stroke != 0;
+            if accepted {
+                self.kept_done |= 1 << stroke;
+            }
+            accepted
+        }
+    }
+
+    /// Every batch of up to `MAX_BATCH` strokes, every choice of strokes to keep, of those that
+    /// can't be kept after all, and class drivers taking a limited number of strokes, in total and per call.
+    #[test]
+    fn every_batch_is_accounted_for() {
+        for len in 0..=MAX_BATCH {
+            let strokes: Vec<usize> = (0..len).collect();
+            let all = (1u32 << len) - 1;
+            for kept in 0..=all {
+                for refused in (0..=all).filter(|refused| refused & !kept == 0) {
+                    let accepts = !refused;
+                    let forwarded_count = len - (kept & accepts).count_ones() as usize;
+                    for capacity in 0..=MAX_BATCH {
+                        for per_call in 1..=BUFFER + 1 {
+                            let mut class = Class { capacity, per_call, taken: Vec::new() };
+                            let mut keeper = Keeper { kept, accepts, classified: Vec::new(), kept_done: 0 };
+                            let consumed = ForwardBuffer::<usize, BUFFER>::run(0, &strokes, &mut keeper, |pending| class.take(pending));
+
+                            // Everything is consumed when the class driver has room for every forwarded stroke
+                            assert!(consumed <= len);
+                            assert_eq!(consumed == len, capacity >= forwarded_count);
+
+                            // The class driver got exactly the forwarded strokes before the count, once and in order
+                            let forwarded = |index: &usize| kept & accepts & 1 << index == 0;
+                            let expected: Vec<usize> = (0..consumed).filter(forwarded).collect();
+                            assert_eq!(class.taken, expected);
+
+                            // Strokes before the count were kept if they had to be, none after it was
+                            let before = if consumed >= 32 { u32::MAX } else { (1 << consumed) - 1 };
+                            assert_eq!(keeper.kept_done, kept & accepts & before);
+
+                            // Strokes were classified once and in order, at least up to the count
+                            assert!(keeper.classified.len() >= consumed);
+                            assert_eq!(keeper.classified, strokes[..keeper.classified.len()]);
+
+                            // The batch only stops short at a stroke the class driver left
+                            assert!(consumed == len || forwarded(&consumed));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn leftovers_move_to_the_front_with_their_batch_index() {
+        let mut forwarded = ForwardBuffer::<u8, 4>::new(0);
+        assert!(forwarded.push(2, 20) && forwarded.push(5, 50) && forwarded.push(6, 60));
+        forwarded.consume(1);
+        assert_eq!(forwarded.len(), 2);
+        assert_eq!(forwarded.first_left(), Some(5));
+        assert_eq!(forwarded.pending_mut(), [50, 60]);
+
+        forwarded.consume(10);
+        assert!(forwarded.is_empty());
+        assert_eq!(forwarded.first_left(), None);
+    }
+
+    #[test]
+    fn push_refuses_a_full_buffer() {
+        let mut forwarded = ForwardBuffer::<u8, 2>::new(0);
+        assert!(forwarded.push(0, 1) && forwarded.push(1, 2));
+        assert!(forwarded.is_full());
+        assert!(!forwarded.push(2, 3));
+        assert_eq!(forwarded.pending_mut(), [1, 2]);
+    }
+}
@@ -0,0 +1,513 @@
+//! A fixed-capacity queue of `Copy` records that pushes and pops without locks or allocation, for
+//! moving strokes between `DISPATCH_LEVEL` callbacks and request handlers.
+//!
+//! Any number of producers and consumers may share a queue, so it serves as SPSC and MPSC queue
+//! alike. It's Dmitry Vyukov's bounded queue: every slot carries a sequence number telling whose
+//! turn it is. A push claims position `head` once its slot's sequence equals `head` by advancing
+//! `head`, writes the record, then publishes it by setting the sequence to `head + 1` with release
+//! ordering. A pop claims position `tail` once its slot's sequence equals `tail + 1` by advancing
+//! `tail`, reads the record, then hands the slot to the push `N` positions later by setting the
+//! sequence to `tail + N`. Positions wrap, so `N` must be a power of two.
+//!
+//! Nothing waits for another thread: one interrupted between claiming a slot and publishing it
+//! makes the slot look full to pushes, or empty to pops, until it resumes. Pushes finding the queue
+//! full are counted, and the queue's [`OverflowPolicy`] decides what becomes of their record.
+//!
+//! Only depends on `core`, so it's tested on the host, and under loom with `--cfg loom`, which
+//! swaps the atomics and cells below for loom's.
+
+use core::fmt::{Debug, Formatter};
+use core::mem::MaybeUninit;
+use sync::{AtomicU32, AtomicUsize, Ordering, UnsafeCell};
+
+#[cfg(not(loom))]
+mod sync {
+    pub use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+    /// `core::cell::UnsafeCell` behind loom's interface, which checks every access.
+    pub struct UnsafeCell<T>(core::cell::UnsafeCell<T>);
+
+    impl<T> UnsafeCell<T> {
+        pub const fn new(value: T) -> Self {
+            Self(core::cell::UnsafeCell::new(value))
+        }
+
+        pub fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+            f(self.0.get())
+        }
+
+        pub fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+            f(self.0.get())
+        }
+    }
+}
+
+#[cfg(loom)]
+mod sync {
+    pub use loom::cell::UnsafeCell;
+    pub use loom::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// The oldest record is dropped to make room.
+    DropOldest,
+    /// The new record is dropped.
+    DropNewest,
+    /// The new record is handed back, for the caller to pass on.
+    PassThrough,
+}
+
+/// What became of a pushed record.
+#[must_use]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Push<T> {
+    Queued,
+    /// The queue was full and its oldest record was dropped to make room.
+    QueuedDroppingOldest,
+    /// The queue was full and the record was dropped.
+    Dropped,
+    /// The queue was full, its oldest record was dropped to make room, then other pushes took the
+    /// room and the record was dropped too.
+    DroppedWithOldest,
+    /// The queue was full and the record is handed back.
+    Returned(T),
+}
+
+/// How a slot's sequence compares with the one a push or pop wants.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Turn {
+    /// The slot is the caller's to claim.
+    Ready,
+    /// The slot is a lap behind: the queue is full for a push, empty for a pop.
+    Early,
+    /// Another thread claimed the position first.
+    Late,
+}
+
+const fn turn(sequence: usize, wanted: usize) -> Turn {
+    match sequence.wrapping_sub(wanted) as isize {
+        0 => Turn::Ready,
+        difference if difference < 0 => Turn::Early,
+        _ => Turn::Late,
+    }
+}
+
+const fn slot(position: usize, capacity: usize) -> usize {
+    position & (capacity - 1)
+}
+
+struct Slot<T> {
+    /// The sequence number less the slot's index, so a new queue is all zeroes.
+    stamp: AtomicUsize,
+    record: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    #[cfg(not(loom))]
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Self = Self {
+        stamp: AtomicUsize::new(0),
+        record: UnsafeCell::new(MaybeUninit::uninit()),
+    };
+
+    #[cfg(loom)]
+    fn empty() -> Self {
+        Self {
+            stamp: AtomicUsize::new(0),
+            record: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+pub struct BoundedQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    /// Positions of the next push and the next pop.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    policy: OverflowPolicy,
+    overflows: AtomicU32,
+}
+
+// Records only ever move in or out whole, by the one thread that claimed their slot
+unsafe impl<T: Send, const N: usize> Sync for BoundedQueue<T, N> {}
+
+impl<T: Copy, const N: usize> BoundedQueue<T, N> {
+    #[cfg(not(loom))]
+    pub const fn new(policy: OverflowPolicy) -> Self {
+        assert!(N >= 2 && N.is_power_of_two(), "Queue capacity must be a power of two of at least 2");
+        Self {
+            slots: [Slot::EMPTY; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            policy,
+            overflows: AtomicU32::new(0),
+        }
+    }
+
+    /// Loom's atomics can't be created in constants.
+    #[cfg(loom)]
+    pub fn new(policy: OverflowPolicy) -> Self {
+        assert!(N >= 2 && N.is_power_of_two(), "Queue capacity must be a power of two of at least 2");
+        Self {
+            slots: core::array::from_fn(|_| Slot::empty()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            policy,
+            overflows: AtomicU32::new(0),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub const fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// Records queued at the time of the call.
+    pub fn len(&self) -> usize {
+        // The tail never passes the head, so it's loaded first
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Relaxed);
+        head.wrapping_sub(tail).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pushes that found the queue full so far.
+    pub fn overflows(&self) -> u32 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    fn sequence(&self, index: usize) -> usize {
+        self.slots[index].stamp.load(Ordering::Acquire).wrapping_add(index)
+    }
+
+    fn set_sequence(&self, index: usize, sequence: usize) {
+        self.slots[index].stamp.store(sequence.wrapping_sub(index), Ordering::Release);
+    }
+
+    /// Queues `record` according to the overflow policy.
+    pub fn push(&self, record: T) -> Push<T> {
+        let Err(record) = self.try_push(record) else {
+            return Push::Queued;
+        };
+
+        self.overflows.fetch_add(1, Ordering::Relaxed);
+        match self.policy {
+            OverflowPolicy::DropOldest => {
+                // Other threads may take the room first, then the new record goes instead
+                let dropped = self.pop().is_some();
+                match self.try_push(record) {
+                    Ok(()) if dropped => Push::QueuedDroppingOldest,
+                    Ok(()) => Push::Queued,
+                    Err(_) if dropped => Push::DroppedWithOldest,
+                    Err(_) => Push::Dropped,
+                }
+            }
+            OverflowPolicy::DropNewest => Push::Dropped,
+            OverflowPolicy::PassThrough => Push::Returned(record),
+        }
+    }
+
+    /// Queues `record`, or hands it back if the queue is full, whatever the policy.
+    pub fn try_push(&self, record: T) -> Result<(), T> {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let index = slot(head, N);
+            match turn(self.sequence(index), head) {
+                Turn::Ready => match self.head.compare_exchange_weak(head, head.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        self.slots[index].record.with_mut(|slot| unsafe { (*slot).write(record) });
+                        self.set_sequence(index, head.wrapping_add(1));
+                        return Ok(());
+                    }
+                    Err(current) => head = current,
+                },
+                Turn::Early => return Err(record),
+                Turn::Late => head = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Takes the oldest record.
+    pub fn pop(&self) -> Option<T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let index = slot(tail, N);
+            match turn(self.sequence(index), tail.wrapping_add(1)) {
+                Turn::Ready => match self.tail.compare_exchange_weak(tail, tail.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let record = self.slots[index].record.with(|slot| unsafe { (*slot).assume_init() });
+                        self.set_sequence(index, tail.wrapping_add(N));
+                        return Some(record);
+                    }
+                    Err(current) => tail = current,
+                },
+                Turn::Early => return None,
+                Turn::Late => tail = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// The record `index` places past the oldest, if it's published, leaving it queued.
+    ///
+    /// # Safety
+    /// Nothing may pop meanwhile, or the record could be read as its slot is written over.
+    pub unsafe fn peek(&self, index: usize) -> Option<T> {
+        if index >= N {
+            return None;
+        }
+
+        let position = self.tail.load(Ordering::Relaxed).wrapping_add(index);
+        let index = slot(position, N);
+        (self.sequence(index) == position.wrapping_add(1))
+            .then(|| self.slots[index].record.with(|record| unsafe { (*record).assume_init() }))
+    }
+}
+
+impl<T, const N: usize> Debug for BoundedQueue<T, N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BoundedQueue")
+            .field("capacity", &N)
+            .field("policy", &self.policy)
+            .field("overflows", &self.overflows)
+            .finish_non_exhaustive()
+    }
+}
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// A queue whose positions are both at `start`, as if that many records went through it.
+    fn queue_at<const N: usize>(policy: OverflowPolicy, start: usize) -> BoundedQueue<u32, N> {
+        let queue = BoundedQueue::new(policy);
+        queue.head.store(start, Ordering::Relaxed);
+        queue.tail.store(start, Ordering::Relaxed);
+        for (index, slot) in queue.slots.iter().enumerate() {
+            // The sequence of a free slot is the position of the next push into it
+            let next = start.wrapping_add(index.wrapping_sub(start) & (N - 1));
+            slot.stamp.store(next.wrapping_sub(index), Ordering::Relaxed);
+        }
+        queue
+    }
+
+    fn drain<const N: usize>(queue: &BoundedQueue<u32, N>) -> Vec<u32> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn turns_compare_across_the_wrap() {
+        assert_eq!(turn(5, 5), Turn::Ready);
+        assert_eq!(turn(4, 5), Turn::Early);
+        assert_eq!(turn(6, 5), Turn::Late);
+        assert_eq!(turn(usize::MAX, 0), Turn::Early);
+        assert_eq!(turn(0, usize::MAX), Turn::Late);
+        assert_eq!(turn(1, usize::MAX.wrapping_add(2)), Turn::Ready);
+        assert_eq!(slot(usize::MAX, 4), 3);
+        assert_eq!(slot(usize::MAX.wrapping_add(1), 4), 0);
+    }
+
+    #[test]
+    fn records_leave_in_order_across_the_wrap() {
+        let queue = queue_at::<4>(OverflowPolicy::DropNewest, usize::MAX - 5);
+        for record in 0..10 {
+            assert_eq!(queue.push(record), Push::Queued);
+            if record % 3 == 2 {
+                drain(&queue);
+            }
+        }
+        assert_eq!(queue.len(), 1);
+        assert_eq!(drain(&queue), [9]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn a_full_queue_follows_its_policy() {
+        let queue = BoundedQueue::<u32, 2>::new(OverflowPolicy::DropNewest);
+        assert!(queue.push(1) == Push::Queued && queue.push(2) == Push::Queued);
+        assert_eq!(queue.push(3), Push::Dropped);
+        assert_eq!(drain(&queue), [1, 2]);
+
+        let queue = BoundedQueue::<u32, 2>::new(OverflowPolicy::DropOldest);
+        assert!(queue.push(1) == Push::Queued && queue.push(2) == Push::Queued);
+        assert_eq!(queue.push(3), Push::QueuedDroppingOldest);
+        assert_eq!(drain(&queue), [2, 3]);
+
+        let queue = BoundedQueue::<u32, 2>::new(OverflowPolicy::PassThrough);
+        assert!(queue.push(1) == Push::Queued && queue.push(2) == Push::Queued);
+        assert_eq!(queue.push(3), Push::Returned(3));
+        assert_eq!(queue.try_push(4), Err(4));
+        assert_eq!(queue.overflows(), 1);
+        assert_eq!(drain(&queue), [1, 2]);
+    }
+
+    #[test]
+    fn peeking_leaves_records_queued() {
+        let queue = queue_at::<4>(OverflowPolicy::DropNewest, usize::MAX - 1);
+        for record in 10..13 {
+            assert_eq!(queue.push(record), Push::Queued);
+        }
+
+        let peeked: Vec<_> = (0..5).map(|index| unsafe { queue.peek(index) }).collect();
+        assert_eq!(peeked, [Some(10), Some(11), Some(12), None, None]);
+        assert_eq!(queue.pop(), Some(10));
+        assert_eq!(unsafe { queue.peek(0) }, Some(11));
+        assert_eq!(drain(&queue), [11, 12]);
+        assert_eq!(unsafe { queue.peek(0) }, None);
+    }
+
+    #[test]
+    fn threads_exchange_every_record_in_order() {
+        const PRODUCERS: u32 = 3;
+        const RECORDS: u32 = 50_000;
+
+        let queue = BoundedQueue::<u32, 8>::new(OverflowPolicy::PassThrough);
+        thread::scope(|scope| {
+            for producer in 0..PRODUCERS {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for sequence in 0..RECORDS {
+                        let mut record = producer << 24 | sequence;
+                        while let Err(returned) = queue.try_push(record) {
+                            record = returned;
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            // Records of one producer leave in the order it pushed them
+            let mut next = [0; PRODUCERS as usize];
+            let mut received = 0;
+            while received < PRODUCERS * RECORDS {
+                let Some(record) = queue.pop() else {
+                    thread::yield_now();
+                    continue;
+                };
+                let producer = (record >> 24) as usize;
+                assert_eq!(record & 0xFF_FFFF, next[producer]);
+                next[producer] += 1;
+                received += 1;
+            }
+        });
+        assert!(queue.is_empty());
+        assert_eq!(queue.overflows(), 0);
+    }
+}
+
+/// Run with `RUSTFLAGS="--cfg loom" cargo test --release bounded_queue`. Loom runs every
+/// interleaving of the threads, with the weakest orderings the atomics allow, and fails on a
+/// record read while its slot is written.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    fn push_retrying<const N: usize>(queue: &BoundedQueue<u32, N>, mut record: u32) {
+        while let Err(returned) = queue.try_push(record) {
+            record = returned;
+            thread::yield_now();
+        }
+    }
+
+    fn pop_waiting<const N: usize>(queue: &BoundedQueue<u32, N>) -> u32 {
+        loop {
+            if let Some(record) = queue.pop() {
+                return record;
+            }
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn contended_pushes_are_each_read_once() {
+        loom::model(|| {
+            let queue = Arc::new(BoundedQueue::<u32, 2>::new(OverflowPolicy::PassThrough));
+            let producers: Vec<_> = [1, 2].into_iter()
+                .map(|record| {
+                    let queue = Arc::clone(&queue);
+                    thread::spawn(move || push_retrying(&queue, record))
+                })
+                .collect();
+
+            let mut received = [pop_waiting(&queue), pop_waiting(&queue)];
+            received.sort_unstable();
+            assert_eq!(received, [1, 2]);
+            for producer in producers {
+                producer.join().unwrap();
+            }
+            assert!(queue.pop().is_none());
+        });
+    }
+
+    #[test]
+    fn slots_are_reused_only_once_read() {
+        loom::model(|| {
+            let queue = Arc::new(BoundedQueue::<u32, 2>::new(OverflowPolicy::PassThrough));
+            let producer = {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || (1..=3).for_each(|record| push_retrying(&queue, record)))
+            };
+
+            assert_eq!([pop_waiting(&queue), pop_waiting(&queue), pop_waiting(&queue)], [1, 2, 3]);
+            producer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn dropping_the_oldest_loses_nothing_else() {
+        loom::model(|| {
+            let queue = Arc::new(BoundedQueue::<u32, 2>::new(OverflowPolicy::DropOldest));
+            assert!(queue.push(1) == Push::Queued && queue.push(2) == Push::Queued);
+            let consumer = {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || queue.pop())
+            };
+
+            let pushed = queue.push(3);
+            let popped = consumer.join().unwrap();
+            let mut left = Vec::new();
+            while let Some(record) = queue.pop() {
+                left.push(record);
+            }
+
+            // Every record was taken, dropped or is still queued, in order
+            let dropped = match pushed {
+                Push::Queued => 0,
+                Push::QueuedDroppingOldest | Push::Dropped => 1,
+                Push::DroppedWithOldest => 2,
+                Push::Returned(_) => unreachable!(),
+            };
+            let taken = usize::from(popped.is_some());
+            assert_eq!(taken + dropped + left.len(), 3);
+            assert!(left.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(popped.is_none_or(|record| left.iter().all(|&later| later > record)));
+        });
+    }
+
+    #[test]
+    fn peeking_sees_only_published_records() {
+        loom::model(|| {
+            let queue = Arc::new(BoundedQueue::<u32, 2>::new(OverflowPolicy::PassThrough));
+            let producer = {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || push_retrying(&queue, 7))
+            };
+
+            // This thread is the only one popping
+            let peeked = unsafe { queue.peek(0) };
+            assert!(matches!(peeked, None | Some(7)));
+            producer.join().unwrap();
+            assert_eq!(unsafe { queue.peek(0) }, Some(7));
+        });
+    }
+}
@@ -0,0 +1,105 @@
+use alloc::boxed::Box;
+use wdk_sys::{NTSTATUS, PWDF_REQUEST_COMPLETION_PARAMS, WDF_REQUEST_COMPLETION_PARAMS, WDF_REQUEST_TYPE, WDFCONTEXT, WDFIOTARGET, WDFMEMORY, WDFREQUEST};
+use wdk_sys::_WDF_REQUEST_TYPE::{WdfRequestTypeDeviceControl, WdfRequestTypeDeviceControlInternal, WdfRequestTypeRead, WdfRequestTypeWrite};
+use crate::framework::{Memory, Request};
+
+pub type CompletionCallback<C> = fn(&mut Request, &CompletionParams, &mut C);
+
+/// Safe view over `WDF_REQUEST_COMPLETION_PARAMS`.
+pub struct CompletionParams<'a> {
+    params: &'a WDF_REQUEST_COMPLETION_PARAMS,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct IoctlCompletion {
+    pub io_control_code: u32,
+    pub internal: bool,
+    pub input: Option<Memory>,
+    pub input_offset: usize,
+    pub output: Option<Memory>,
+    pub output_offset: usize,
+    pub output_length: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TransferCompletion {
+    pub buffer: Option<Memory>,
+    pub offset: usize,
+    pub length: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum CompletionKind {
+    Ioctl(IoctlCompletion),
+    Read(TransferCompletion),
+    Write(TransferCompletion),
+    Other(WDF_REQUEST_TYPE),
+}
+
+fn memory(handle: WDFMEMORY) -> Option<Memory> {
+    (!handle.is_null()).then(|| Memory::new(handle))
+}
+
+impl<'a> CompletionParams<'a> {
+    pub fn new(params: &'a WDF_REQUEST_COMPLETION_PARAMS) -> Self {
+        Self {
+            params
+        }
+    }
+
+    pub fn status(&self) -> NTSTATUS {
+        unsafe { self.params.IoStatus.__bindgen_anon_1.Status }
+    }
+
+    pub fn information(&self) -> usize {
+        self.params.IoStatus.Information as usize
+    }
+
+    pub fn kind(&self) -> CompletionKind {
+        let parameters = &self.params.Parameters;
+        match self.params.Type {
+            request_type @ (WdfRequestTypeDeviceControl | WdfRequestTypeDeviceControlInternal) => {
+                let ioctl = unsafe { &parameters.Ioctl };
+                CompletionKind::Ioctl(IoctlCompletion {
+                    io_control_code: ioctl.IoControlCode,
+                    internal: request_type == WdfRequestTypeDeviceControlInternal,
+                    input: memory(ioctl.Input.Buffer),
+                    input_offset: ioctl.Input.Offset,
+                    output: memory(ioctl.Output.Buffer),
+                    output_offset: ioctl.Output.Offset,
+                    output_length: ioctl.Output.Length,
+                })
+            }
+            WdfRequestTypeRead => {
+                let read = unsafe { &parameters.Read };
+                CompletionKind::Read(TransferCompletion {
+                    buffer: memory(read.Buffer),
+                    offset: read.Offset,
+                    length: read.Length,
+                })
+            }
+            WdfRequestTypeWrite => {
+                let write = unsafe { &parameters.Write };
+                CompletionKind::Write(TransferCompletion {
+                    buffer: memory(write.Buffer),
+                    offset: write.Offset,
+                    length: write.Length,
+                })
+            }
+            other => CompletionKind::Other(other),
+        }
+    }
+}
+
+pub(crate) struct Completion<C> {
+    pub(crate) callback: CompletionCallback<C>,
+    pub(crate) context: *mut C,
+}
+
+pub(crate) unsafe extern "C" fn completion_trampoline<C>(request: WDFREQUEST, _target: WDFIOTARGET, params: PWDF_REQUEST_COMPLETION_PARAMS, context: WDFCONTEXT) {
+    let completion = unsafe { Box::from_raw(context.cast::<Completion<C>>()) };
+    let mut request = Request::new(unsafe { request.as_mut().expect("Request is null") });
+    let params = CompletionParams::new(unsafe { params.as_ref().expect("Completion params are null") });
+
+    (completion.callback)(&mut request, &params, unsafe { &mut *completion.context });
+}
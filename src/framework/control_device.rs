@@ -0,0 +1,161 @@
+use nt_string::unicode_string::NtUnicodeStr;
+use wdk_sys::{PFN_WDF_IO_IN_CALLER_CONTEXT, PWDFDEVICE_INIT, UNICODE_STRING, WDFDEVICE_INIT, WDFDRIVER};
+use wdk_sys::macros::call_unsafe_wdf_function_binding;
+use crate::framework::{Context, Device, ErrorCode, FileObjectConfig, NtStatusError, ObjectAttributes, Result};
+use crate::log_trace;
+
+/// Builds a non-PnP control device. Once its queues are created,
+/// call [`Device::finish_initializing`] so it starts receiving requests.
+pub(crate) struct ControlDeviceBuilder {
+    init: PWDFDEVICE_INIT,
+    name: Option<NtUnicodeStr<'static>>,
+    symbolic_link: Option<NtUnicodeStr<'static>>,
+    device_type: Option<u32>,
+    file_object_config: Option<FileObjectConfig>,
+    io_in_caller_context: PFN_WDF_IO_IN_CALLER_CONTEXT,
+    attrs: ObjectAttributes,
+}
+
+impl ControlDeviceBuilder {
+    pub(crate) fn new(driver: WDFDRIVER, sddl: NtUnicodeStr) -> Result<Self> {
+        let init = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfControlDeviceInitAllocate,
+                driver,
+                sddl.as_ptr() as *const UNICODE_STRING,
+            )
+        };
+
+        if init.is_null() {
+            wdk_sys::STATUS_INSUFFICIENT_RESOURCES.check_status(ErrorCode::ControlDeviceInitAllocateFailed)?;
+        }
+
+        Ok(Self {
+            init,
+            name: None,
+            symbolic_link: None,
+            device_type: None,
+            file_object_config: None,
+            io_in_caller_context: None,
+            attrs: ObjectAttributes::new(),
+        })
+    }
+
+    pub(crate) fn with_name(&mut self, name: NtUnicodeStr<'static>) -> &mut Self {
+        self.name = Some(name);
+        self
+    }
+
+    pub(crate) fn with_symbolic_link(&mut self, symbolic_link: NtUnicodeStr<'static>) -> &mut Self {
+        self.symbolic_link = Some(symbolic_link);
+        self
+    }
+
+    pub(crate) fn with_device_type(&mut self, device_type: u32) -> &mut Self {
+        self.device_type = Some(device_type);
+        self
+    }
+
+    pub(crate) fn with_file_object_config(&mut self, config: FileObjectConfig) -> &mut Self {
+        self.file_object_config = Some(config);
+        self
+    }
+
+    /// `callback` sees every request first, in the context of the thread that sent it, and hands
+    /// those it doesn't complete to the queues with [`Request::enqueue`](crate::framework::Request::enqueue).
+    pub(crate) fn with_io_in_caller_context(&mut self, callback: PFN_WDF_IO_IN_CALLER_CONTEXT) -> &mut Self {
+        self.io_in_caller_context = callback;
+        self
+    }
+
+    pub(crate) fn attributes(&mut self) -> &mut ObjectAttributes {
+        &mut self.attrs
+    }
+
+    pub(crate) fn build_with_context<T: Context>(&mut self) -> Result<Device<'static, T>> {
+        if let Some(name) = self.name {
+            unsafe {
+                call_unsafe_wdf_function_binding!(
+                    WdfDeviceInitAssignName,
+                    self.init,
+                    name.as_ptr() as *const UNICODE_STRING,
+                )
+            }.check_status(ErrorCode::DeviceInitAssignNameFailed)?;
+        }
+
+        if let Some(device_type) = self.device_type {
+            unsafe {
+                call_unsafe_wdf_function_binding!(
+                    WdfDeviceInitSetDeviceType,
+                    self.init,
+                    device_type
+                );
+            }
+        }
+
+        if let Some(config) = &mut self.file_object_config {
+            config.apply(self.init);
+        }
+
+        if self.io_in_caller_context.is_some() {
+            unsafe {
+                call_unsafe_wdf_function_binding!(
+                    WdfDeviceInitSetIoInCallerContextCallback,
+                    self.init,
+                    self.io_in_caller_context,
+                );
+            }
+        }
+
+        self.attrs.with_context::<T>();
+
+        let mut device_ptr = core::ptr::null_mut();
+        let mut device = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDeviceCreate,
+                &mut self.init as *mut *mut WDFDEVICE_INIT,
+                self.attrs.as_mut_ptr(),
+                &mut device_ptr,
+            )
+        }.check_status(ErrorCode::DeviceCreationFailed).map(|_| {
+            Device::<T>::new(unsafe { device_ptr.as_mut().expect("Device is null") })
+        })?;
+
+        // WdfDeviceCreate consumed the init structure
+        self.init = core::ptr::null_mut();
+
+        if let Some(symbolic_link) = self.symbolic_link {
+            // Deleting the device also deletes the symbolic link
+            let res = unsafe {
+                call_unsafe_wdf_function_binding!(
+                    WdfDeviceCreateSymbolicLink,
+                    device.handle(),
+                    symbolic_link.as_ptr() as *const UNICODE_STRING,
+                )
+            }.check_status(ErrorCode::DeviceCreateSymbolicLinkFailed);
+
+            if let Err(e) = res {
+                device.delete();
+                return Err(e);
+            }
+        }
+
+        Ok(device)
+    }
+}
+
+impl Drop for ControlDeviceBuilder {
+    fn drop(&mut self) {
+        if self.init.is_null() {
+            log_trace!("ControlDeviceBuilder consumed by WdfDeviceCreate, not freeing init");
+            return;
+        }
+
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDeviceInitFree,
+                self.init
+            );
+        }
+    }
+}
@@ -1,28 +1,41 @@
-use wdk_sys::{PDEVICE_OBJECT, PWDFDEVICE_INIT, WDF_NO_HANDLE, WDF_OBJECT_ATTRIBUTES, WDFDEVICE, WDFDEVICE__, WDFDEVICE_INIT, WDFOBJECT};
-use wdk_sys::_WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent;
-use wdk_sys::_WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent;
+use nt_string::unicode_string::NtUnicodeStr;
+use wdk_sys::{DEVICE_REGISTRY_PROPERTY, PDEVICE_OBJECT, PWDFDEVICE_INIT, ULONG, UNICODE_STRING, WDF_NO_HANDLE, WDFDEVICE, WDFDEVICE__, WDFDEVICE_INIT, WDFOBJECT};
 use wdk_sys::macros::call_unsafe_wdf_function_binding;
-use crate::framework::{Context, ErrorCode, NtStatusError, Result};
-use crate::{dbg, init_object};
+use crate::framework::{Context, ErrorCode, FileObjectConfig, IoTarget, NtStatusError, ObjectAttributes, Result};
 
-#[derive(Debug)]
 pub struct DeviceBuilder<'a> {
     device_init: &'a mut WDFDEVICE_INIT,
-    attrs: WDF_OBJECT_ATTRIBUTES,
+    attrs: ObjectAttributes,
+    sddl: Option<NtUnicodeStr<'static>>,
+    file_object_config: Option<FileObjectConfig>,
 }
 
 impl<'a> DeviceBuilder<'a> {
     pub fn new(device_init: &'a mut WDFDEVICE_INIT) -> Self {
-        let mut attrs = init_object!(WDF_OBJECT_ATTRIBUTES);
-        attrs.ExecutionLevel = WdfExecutionLevelInheritFromParent;
-        attrs.SynchronizationScope = WdfSynchronizationScopeInheritFromParent;
-
         Self {
             device_init,
-            attrs,
+            attrs: ObjectAttributes::new(),
+            sddl: None,
+            file_object_config: None,
         }
     }
 
+    pub fn attributes(&mut self) -> &mut ObjectAttributes {
+        &mut self.attrs
+    }
+
+    /// Restricts who may open the device. Unlike PDOs, PnP devices get no SDDL by default,
+    /// since a filter's security descriptor also applies to the stack it is attached to.
+    pub fn with_sddl(&mut self, sddl: NtUnicodeStr<'static>) -> &mut Self {
+        self.sddl = Some(sddl);
+        self
+    }
+
+    pub fn with_file_object_config(&mut self, config: FileObjectConfig) -> &mut Self {
+        self.file_object_config = Some(config);
+        self
+    }
+
     pub fn as_filter_device(&mut self) -> &mut Self {
         unsafe {
             call_unsafe_wdf_function_binding!(
@@ -47,14 +60,22 @@ impl<'a> DeviceBuilder<'a> {
     }
 
     pub fn build_with_context<T: Context>(&mut self) -> Result<Device<T>> {
-        self.attrs.ContextTypeInfo = T::get_context_type_info();
+        if let Some(sddl) = self.sddl {
+            assign_sddl(self.device_init, sddl)?;
+        }
+
+        if let Some(config) = &mut self.file_object_config {
+            config.apply(self.device_init);
+        }
+
+        self.attrs.with_context::<T>();
 
         let mut device = WDF_NO_HANDLE as _;
         unsafe {
             call_unsafe_wdf_function_binding!(
                 WdfDeviceCreate,
                 &mut (self.device_init as PWDFDEVICE_INIT),
-                core::ptr::addr_of_mut!(self.attrs),
+                self.attrs.as_mut_ptr(),
                 &mut device,
             )
         }.check_status(ErrorCode::DeviceCreationFailed).map(|_| {
@@ -63,6 +84,17 @@ impl<'a> DeviceBuilder<'a> {
     }
 }
 
+/// Sets the security descriptor of the device about to be created from `init`.
+pub(crate) fn assign_sddl(init: PWDFDEVICE_INIT, sddl: NtUnicodeStr) -> Result<()> {
+    unsafe {
+        call_unsafe_wdf_function_binding!(
+            WdfDeviceInitAssignSDDLString,
+            init,
+            sddl.as_ptr() as *const UNICODE_STRING,
+        )
+    }.check_status(ErrorCode::DeviceInitAssignSddlFailed)
+}
+
 
 #[derive(Debug)]
 pub struct Device<'a, T: Context> {
@@ -89,7 +121,7 @@ impl<'a, T: Context> Device<'a, T> {
 
     pub fn context_mut(&mut self) -> &mut T {
         unsafe {
-            T::get_context(dbg!(self.device as *mut _ as WDFOBJECT))
+            T::get_context(self.device as *mut _ as WDFOBJECT)
                 .as_mut()
         }.expect("Context is null")
     }
@@ -107,12 +139,49 @@ impl<'a, T: Context> Device<'a, T> {
         }
     }
 
-    pub fn io_target(&mut self) -> wdk_sys::WDFIOTARGET {
+    /// Completes initialization of a control device, after which it receives requests.
+    pub fn finish_initializing(&mut self) {
         unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfControlFinishInitializing,
+                self.handle()
+            )
+        }
+    }
+
+    pub fn delete(&mut self) {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfObjectDelete,
+                self.handle() as WDFOBJECT
+            )
+        }
+    }
+
+    /// Reads a string device property into `buffer`, returning its length in UTF-16 code units.
+    /// Multi-string properties are returned as is, NUL separated.
+    pub fn query_property_string(&mut self, property: DEVICE_REGISTRY_PROPERTY, buffer: &mut [u16]) -> Result<usize> {
+        let mut result_length: ULONG = 0;
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDeviceQueryProperty,
+                self.handle(),
+                property,
+                core::mem::size_of_val(buffer) as ULONG,
+                buffer.as_mut_ptr().cast(),
+                &mut result_length,
+            )
+        }.check_status(ErrorCode::DeviceQueryPropertyFailed)?;
+
+        Ok(result_length as usize / core::mem::size_of::<u16>())
+    }
+
+    pub fn io_target(&mut self) -> IoTarget {
+        IoTarget::new(unsafe {
             call_unsafe_wdf_function_binding!(
                             WdfDeviceGetIoTarget,
                             self.handle()
                         )
-        }
+        })
     }
 }
@@ -1,6 +1,6 @@
 use wdk_sys::{DRIVER_OBJECT, PFN_WDF_DRIVER_DEVICE_ADD, WDF_DRIVER_CONFIG, WDF_NO_HANDLE, WDF_NO_OBJECT_ATTRIBUTES, WDFDRIVER};
 use wdk_sys::macros::call_unsafe_wdf_function_binding;
-use crate::{dbg, init_object};
+use crate::{init_object, log_debug, log_trace};
 use crate::framework::error::{Result, NtStatusError, ErrorCode};
 
 #[derive(Debug,)]
@@ -24,7 +24,7 @@ impl<'a> DriverInit<'a> {
     pub fn create(&mut self, registry_path: wdk_sys::PCUNICODE_STRING) -> Result<WDFDRIVER> {
         let mut driver_handle_output = WDF_NO_HANDLE as WDFDRIVER;
 
-        dbg!("WdfDriverCreate");
+        log_trace!("WdfDriverCreate");
 
         unsafe {
             call_unsafe_wdf_function_binding!(
@@ -36,7 +36,7 @@ impl<'a> DriverInit<'a> {
             &mut driver_handle_output)
         }.check_status(ErrorCode::DriverEntryFailed).map(|_| driver_handle_output)?;
 
-        dbg!("WdfDriverCreate succeeded");
+        log_debug!("WdfDriverCreate succeeded");
 
         Ok(driver_handle_output)
     }
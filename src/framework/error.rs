@@ -1,7 +1,10 @@
+use alloc::boxed::Box;
+use core::panic::Location;
 use core::result;
 use snafu::Snafu;
-use wdk_sys::{NT_SUCCESS, NTSTATUS, STATUS_SUCCESS};
-use crate::dbg;
+use wdk_sys::{NT_SUCCESS, NTSTATUS, STATUS_BUFFER_TOO_SMALL, STATUS_INVALID_PARAMETER, STATUS_REVISION_MISMATCH, STATUS_SUCCESS};
+use crate::log_debug;
+use crate::framework::nt_status::NtStatusName;
 
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -20,23 +23,145 @@ pub enum ErrorCode {
     RequestSendFailed,
     RequestOutputMemoryRetrievalFailed,
     RequestFormatForInternalIoctlFailed,
+    MemoryCopyFailed,
+    RequestFormatForIoctlFailed,
+    IoTargetSendFailed,
+    IoTargetNotStarted,
+    UnsupportedIoctl,
+    RequestInputMemoryRetrievalFailed,
+    IoctlInputInvalid,
+    IoctlOutputTooSmall,
+    ProtocolVersionUnsupported,
+    RegistryOpenFailed,
+    RegistryQueryFailed,
+    RequestOutputBufferRetrievalFailed,
+    ControlDeviceInitAllocateFailed,
+    DeviceInitAssignNameFailed,
+    DeviceCreateSymbolicLinkFailed,
+    DeviceQueryPropertyFailed,
+    RequestForwardFailed,
+    RequestInputBufferRetrievalFailed,
+    DeviceNotFound,
+    DeviceInitAssignSddlFailed,
+    AccessDenied,
+    LayoutNotFound,
+    TextTooLong,
+    TextUnmappable,
+    TimerCreateFailed,
+    RequestOutputMdlRetrievalFailed,
+    RequestOutputMdlMappingFailed,
+    EventReferenceFailed,
+    RequestEnqueueFailed,
+    RingAlreadyMapped,
+    RingNotMapped,
+    RingCorrupt,
+    RingBufferMisaligned,
+    RingAllocationFailed,
+    RequestMarkCancelableFailed,
+    TextAllocationFailed,
 }
 
 #[derive(Snafu, Debug)]
 pub enum Error {
-    #[snafu(display("{:#?}: {nt_status:010X}"))]
+    #[snafu(display("{error_code:?} failed with {} at {raised_at}", NtStatusName(*nt_status)))]
     NtStatusError {
         nt_status: NTSTATUS,
         error_code: ErrorCode,
-    }
+        raised_at: &'static Location<'static>,
+    },
+
+    #[snafu(display("{error_code:?}: buffer holds {actual} bytes, at least {required} are required at {raised_at}"))]
+    BufferTooSmall {
+        error_code: ErrorCode,
+        required: usize,
+        actual: usize,
+        raised_at: &'static Location<'static>,
+    },
+
+    #[snafu(display("{error_code:?}: invalid buffer at {raised_at}"))]
+    InvalidBuffer {
+        error_code: ErrorCode,
+        raised_at: &'static Location<'static>,
+    },
+
+    #[snafu(display("{error_code:?}: protocol version {requested} is not supported (supported: {supported}) at {raised_at}"))]
+    UnsupportedVersion {
+        error_code: ErrorCode,
+        requested: u32,
+        supported: u32,
+        raised_at: &'static Location<'static>,
+    },
+
+    #[snafu(display("{error_code:?} at {raised_at}, caused by: {cause}"))]
+    Chained {
+        error_code: ErrorCode,
+        raised_at: &'static Location<'static>,
+        cause: Box<Error>,
+    },
 }
 
 impl Error {
     pub fn nt_status(&self) -> NTSTATUS {
         match self {
             Error::NtStatusError { nt_status, .. } => *nt_status,
+            Error::BufferTooSmall { .. } => STATUS_BUFFER_TOO_SMALL,
+            Error::InvalidBuffer { .. } => STATUS_INVALID_PARAMETER,
+            Error::UnsupportedVersion { .. } => STATUS_REVISION_MISMATCH,
+            Error::Chained { cause, .. } => cause.nt_status(),
+        }
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            Error::NtStatusError { error_code, .. }
+            | Error::BufferTooSmall { error_code, .. }
+            | Error::InvalidBuffer { error_code, .. }
+            | Error::UnsupportedVersion { error_code, .. }
+            | Error::Chained { error_code, .. } => *error_code,
+        }
+    }
+
+    pub fn raised_at(&self) -> &'static Location<'static> {
+        match self {
+            Error::NtStatusError { raised_at, .. }
+            | Error::BufferTooSmall { raised_at, .. }
+            | Error::InvalidBuffer { raised_at, .. }
+            | Error::UnsupportedVersion { raised_at, .. }
+            | Error::Chained { raised_at, .. } => raised_at,
+        }
+    }
+
+    pub fn cause(&self) -> Option<&Error> {
+        match self {
+            Error::Chained { cause, .. } => Some(cause),
+            _ => None,
         }
     }
+
+    fn logged(self) -> Self {
+        log_debug!("{self}");
+        self
+    }
+
+    #[track_caller]
+    pub fn from_nt_status(nt_status: NTSTATUS, error_code: ErrorCode) -> Self {
+        Error::NtStatusError { nt_status, error_code, raised_at: Location::caller() }.logged()
+    }
+
+    #[track_caller]
+    pub fn buffer_too_small(error_code: ErrorCode, required: usize, actual: usize) -> Self {
+        Error::BufferTooSmall { error_code, required, actual, raised_at: Location::caller() }.logged()
+    }
+
+    #[track_caller]
+    pub fn invalid_buffer(error_code: ErrorCode) -> Self {
+        Error::InvalidBuffer { error_code, raised_at: Location::caller() }.logged()
+    }
+
+    #[track_caller]
+    pub fn unsupported_version(error_code: ErrorCode, requested: u32, supported: u32) -> Self {
+        Error::UnsupportedVersion { error_code, requested, supported, raised_at: Location::caller() }.logged()
+    }
 }
 
 pub(crate) trait ToStatus {
@@ -57,11 +182,27 @@ pub(crate) trait NtStatusError {
 }
 
 impl NtStatusError for NTSTATUS {
+    #[track_caller]
     fn check_status(self, error_code: ErrorCode) -> Result<()> {
         if NT_SUCCESS(self) {
             Ok(())
         } else {
-            Err(dbg!(Error::NtStatusError { error_code, nt_status: self }))
+            Err(Error::NtStatusError { error_code, nt_status: self, raised_at: Location::caller() }.logged())
+        }
+    }
+}
+
+pub(crate) trait ErrorContext<T> {
+    /// Wraps the error, if any, in an [`Error::Chained`] describing the operation that was being attempted.
+    fn context(self, error_code: ErrorCode) -> Result<T>;
+}
+
+impl<T> ErrorContext<T> for Result<T> {
+    #[track_caller]
+    fn context(self, error_code: ErrorCode) -> Result<T> {
+        match self {
+            Ok(value) => Ok(value),
+            Err(cause) => Err(Error::Chained { error_code, raised_at: Location::caller(), cause: Box::new(cause) }.logged()),
         }
     }
 }
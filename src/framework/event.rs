@@ -0,0 +1,44 @@
+use core::ptr::null_mut;
+use wdk_sys::_MODE::UserMode;
+use wdk_sys::{EVENT_MODIFY_STATE, HANDLE, IO_NO_INCREMENT, KPROCESSOR_MODE, PKEVENT, PVOID};
+use wdk_sys::ntddk::{ExEventObjectType, KeSetEvent, ObReferenceObjectByHandle, ObfDereferenceObject};
+use crate::framework::{ErrorCode, NtStatusError, Result};
+
+/// An event created by a user mode client, referenced so the driver can set it at up to `DISPATCH_LEVEL`.
+#[derive(Debug)]
+pub struct UserEvent {
+    event: PKEVENT,
+}
+
+// Kernel objects may be used from any thread once referenced
+unsafe impl Send for UserEvent {}
+
+impl UserEvent {
+    /// References the event behind `handle`, which must have been opened with `EVENT_MODIFY_STATE`.
+    /// Handles belong to a process, so this has to run in the context of the client.
+    pub fn from_handle(handle: u64) -> Result<Self> {
+        let mut object: PVOID = null_mut();
+        unsafe {
+            ObReferenceObjectByHandle(
+                handle as HANDLE,
+                EVENT_MODIFY_STATE,
+                *ExEventObjectType,
+                UserMode as KPROCESSOR_MODE,
+                &mut object,
+                null_mut(),
+            )
+        }.check_status(ErrorCode::EventReferenceFailed)?;
+
+        Ok(Self { event: object.cast() })
+    }
+
+    pub fn set(&self) {
+        unsafe { KeSetEvent(self.event, IO_NO_INCREMENT as i32, 0) };
+    }
+}
+
+impl Drop for UserEvent {
+    fn drop(&mut self) {
+        unsafe { ObfDereferenceObject(self.event.cast()) };
+    }
+}
@@ -0,0 +1,75 @@
+use wdk_sys::{PFN_WDF_DEVICE_FILE_CREATE, PFN_WDF_FILE_CLEANUP, PWDFDEVICE_INIT, WDF_FILEOBJECT_CONFIG, WDFFILEOBJECT, WDFOBJECT};
+use wdk_sys::_WDF_FILEOBJECT_CLASS::WdfFileObjectWdfCannotUseFsContexts;
+use wdk_sys::_WDF_TRI_STATE::WdfUseDefault;
+use wdk_sys::macros::call_unsafe_wdf_function_binding;
+use crate::framework::{Context, ObjectAttributes};
+use crate::init_object;
+
+/// How a device tracks the handles opened on it. Pass it to a device builder before building.
+pub struct FileObjectConfig {
+    config: WDF_FILEOBJECT_CONFIG,
+    attrs: ObjectAttributes,
+}
+
+impl FileObjectConfig {
+    /// `on_create` owns the create request and must complete it.
+    pub fn new(on_create: PFN_WDF_DEVICE_FILE_CREATE) -> Self {
+        let mut config = init_object!(WDF_FILEOBJECT_CONFIG);
+        config.EvtDeviceFileCreate = on_create;
+        config.AutoForwardCleanupClose = WdfUseDefault;
+        config.FileObjectClass = WdfFileObjectWdfCannotUseFsContexts;
+
+        Self {
+            config,
+            attrs: ObjectAttributes::new(),
+        }
+    }
+
+    pub fn with_context<T: Context>(&mut self) -> &mut Self {
+        self.attrs.with_context::<T>();
+        self
+    }
+
+    /// `on_cleanup` runs once the last handle to a file object is closed, at `PASSIVE_LEVEL`.
+    pub fn with_cleanup(&mut self, on_cleanup: PFN_WDF_FILE_CLEANUP) -> &mut Self {
+        self.config.EvtFileCleanup = on_cleanup;
+        self
+    }
+
+    pub(crate) fn apply(&mut self, init: PWDFDEVICE_INIT) {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDeviceInitSetFileObjectConfig,
+                init,
+                &mut self.config,
+                self.attrs.as_mut_ptr(),
+            )
+        };
+    }
+}
+
+#[derive(Debug)]
+pub struct FileObject {
+    handle: WDFFILEOBJECT,
+}
+
+impl FileObject {
+    pub fn new(handle: WDFFILEOBJECT) -> Self {
+        Self {
+            handle
+        }
+    }
+
+    pub fn handle(&self) -> WDFFILEOBJECT {
+        self.handle
+    }
+
+    /// The file object's `T` context, if it was created with one.
+    pub fn context<T: Context>(&self) -> Option<&T> {
+        unsafe { T::get_context(self.handle as WDFOBJECT).as_ref() }
+    }
+
+    pub fn context_mut<T: Context>(&mut self) -> Option<&mut T> {
+        unsafe { T::get_context(self.handle as WDFOBJECT).as_mut() }
+    }
+}
@@ -0,0 +1,143 @@
+use core::ptr::null_mut;
+use core::time::Duration;
+use wdk_sys::{PWDF_MEMORY_DESCRIPTOR, STATUS_DEVICE_NOT_READY, ULONG, ULONG_PTR, WDF_MEMORY_DESCRIPTOR, WDF_REQUEST_SEND_OPTIONS, WDFIOTARGET};
+use wdk_sys::_WDF_IO_TARGET_STATE::{WdfIoTargetClosed, WdfIoTargetClosedForQueryRemove, WdfIoTargetDeleted, WdfIoTargetStarted, WdfIoTargetStopped};
+use wdk_sys::_WDF_MEMORY_DESCRIPTOR_TYPE::WdfMemoryDescriptorTypeBuffer;
+use wdk_sys::_WDF_REQUEST_SEND_OPTIONS_FLAGS::WDF_REQUEST_SEND_OPTION_TIMEOUT;
+use wdk_sys::macros::call_unsafe_wdf_function_binding;
+use crate::framework::{ErrorCode, Memory, NtStatusError, Request, Result};
+use crate::init_object;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoTargetState {
+    Started,
+    Stopped,
+    ClosedForQueryRemove,
+    Closed,
+    Deleted,
+    Undefined,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct IoTarget {
+    handle: WDFIOTARGET,
+}
+
+impl IoTarget {
+    pub fn new(handle: WDFIOTARGET) -> Self {
+        Self {
+            handle
+        }
+    }
+
+    pub fn handle(&self) -> WDFIOTARGET {
+        self.handle
+    }
+
+    pub fn state(&self) -> IoTargetState {
+        let state = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoTargetGetState,
+                self.handle,
+            )
+        };
+
+        match state {
+            WdfIoTargetStarted => IoTargetState::Started,
+            WdfIoTargetStopped => IoTargetState::Stopped,
+            WdfIoTargetClosedForQueryRemove => IoTargetState::ClosedForQueryRemove,
+            WdfIoTargetClosed => IoTargetState::Closed,
+            WdfIoTargetDeleted => IoTargetState::Deleted,
+            _ => IoTargetState::Undefined,
+        }
+    }
+
+    pub fn is_stopped_or_removed(&self) -> bool {
+        self.state() != IoTargetState::Started
+    }
+
+    /// Sends an internal ioctl to the target and waits for it to complete.
+    /// Must be called at `PASSIVE_LEVEL`. Returns the number of bytes the target reported.
+    pub fn send_internal_ioctl_sync<I: Copy, O: Copy>(&self, io_control_code: u32, input: Option<&I>, output: Option<&mut O>, timeout: Option<Duration>) -> Result<usize> {
+        self.ensure_started()?;
+
+        let mut input_descriptor = input.map(|input| buffer_descriptor((input as *const I).cast_mut()));
+        let mut output_descriptor = output.map(|output| buffer_descriptor(output as *mut O));
+        let mut options = send_options(timeout);
+        let mut bytes_returned: ULONG_PTR = 0;
+
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoTargetSendInternalIoctlSynchronously,
+                self.handle,
+                null_mut(),
+                io_control_code,
+                descriptor_ptr(&mut input_descriptor),
+                descriptor_ptr(&mut output_descriptor),
+                &mut options,
+                &mut bytes_returned,
+            )
+        }.check_status(ErrorCode::IoTargetSendFailed)?;
+
+        Ok(bytes_returned as usize)
+    }
+
+    pub fn format_ioctl(&self, request: &mut Request, io_control_code: u32, input: Option<Memory>, output: Option<Memory>) -> Result<()> {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoTargetFormatRequestForIoctl,
+                self.handle,
+                request.handle(),
+                io_control_code,
+                input.map_or(null_mut(), |memory| memory.handle()),
+                null_mut(),
+                output.map_or(null_mut(), |memory| memory.handle()),
+                null_mut(),
+            )
+        }.check_status(ErrorCode::RequestFormatForIoctlFailed)
+    }
+
+    pub fn format_internal_ioctl(&self, request: &mut Request, io_control_code: u32, input: Option<Memory>, output: Option<Memory>) -> Result<()> {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoTargetFormatRequestForInternalIoctl,
+                self.handle,
+                request.handle(),
+                io_control_code,
+                input.map_or(null_mut(), |memory| memory.handle()),
+                null_mut(),
+                output.map_or(null_mut(), |memory| memory.handle()),
+                null_mut(),
+            )
+        }.check_status(ErrorCode::RequestFormatForInternalIoctlFailed)
+    }
+
+    fn ensure_started(&self) -> Result<()> {
+        if self.is_stopped_or_removed() {
+            STATUS_DEVICE_NOT_READY.check_status(ErrorCode::IoTargetNotStarted)?;
+        }
+        Ok(())
+    }
+}
+
+fn buffer_descriptor<T>(buffer: *mut T) -> WDF_MEMORY_DESCRIPTOR {
+    let mut descriptor = WDF_MEMORY_DESCRIPTOR::default();
+    descriptor.Type = WdfMemoryDescriptorTypeBuffer;
+    descriptor.u.BufferType.Buffer = buffer.cast();
+    descriptor.u.BufferType.Length = core::mem::size_of::<T>() as ULONG;
+    descriptor
+}
+
+fn descriptor_ptr(descriptor: &mut Option<WDF_MEMORY_DESCRIPTOR>) -> PWDF_MEMORY_DESCRIPTOR {
+    descriptor.as_mut().map_or(null_mut(), |descriptor| descriptor as PWDF_MEMORY_DESCRIPTOR)
+}
+
+fn send_options(timeout: Option<Duration>) -> WDF_REQUEST_SEND_OPTIONS {
+    let mut options = init_object!(WDF_REQUEST_SEND_OPTIONS);
+    if let Some(timeout) = timeout {
+        options.Flags |= WDF_REQUEST_SEND_OPTION_TIMEOUT as ULONG;
+        // Negative values are relative, in 100ns units
+        options.Timeout = -((timeout.as_nanos() / 100) as i64);
+    }
+    options
+}
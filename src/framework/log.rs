@@ -0,0 +1,262 @@
+use core::fmt::Arguments;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use crate::framework::log_ring::LogRing;
+use crate::framework::spin_lock::SpinLock;
+use crate::framework::time;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Most verbose level compiled into the driver. The most restrictive `log-max-*` feature wins,
+/// and everything above it is removed at compile time.
+pub const STATIC_MAX_LEVEL: Level = if cfg!(feature = "log-off") {
+    Level::Off
+} else if cfg!(feature = "log-max-error") {
+    Level::Error
+} else if cfg!(feature = "log-max-warn") {
+    Level::Warn
+} else if cfg!(feature = "log-max-info") {
+    Level::Info
+} else if cfg!(feature = "log-max-debug") {
+    Level::Debug
+} else {
+    Level::Trace
+};
+
+pub const DEFAULT_LEVEL: Level = Level::Info;
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+
+/// Highest level any target may log at, checked before looking at per-target overrides.
+static CEILING: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+
+pub const MAX_TARGET_OVERRIDES: usize = 8;
+pub const MAX_TARGET_LENGTH: usize = 48;
+
+const EMPTY_LEVEL: u8 = u8::MAX;
+
+/// A per-target level. Writers bump `version` to an odd value while updating it,
+/// so readers on any IRQL can detect and skip a torn entry without taking a lock.
+struct TargetOverride {
+    version: AtomicU32,
+    level: AtomicU8,
+    length: AtomicU8,
+    prefix: [AtomicU8; MAX_TARGET_LENGTH],
+}
+
+impl TargetOverride {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU8 = AtomicU8::new(0);
+        Self {
+            version: AtomicU32::new(0),
+            level: AtomicU8::new(EMPTY_LEVEL),
+            length: AtomicU8::new(0),
+            prefix: [ZERO; MAX_TARGET_LENGTH],
+        }
+    }
+
+    /// Returns the length of the prefix and the level, if this override applies to `target`.
+    fn matches(&self, target: &str) -> Option<(usize, u8)> {
+        let version = self.version.load(Ordering::Acquire);
+        if version % 2 == 1 {
+            return None;
+        }
+
+        let level = self.level.load(Ordering::Relaxed);
+        let length = usize::from(self.length.load(Ordering::Relaxed));
+        if level == EMPTY_LEVEL || target.len() < length {
+            return None;
+        }
+
+        let matched = target.as_bytes()[..length].iter()
+            .zip(self.prefix.iter())
+            .all(|(byte, prefix)| *byte == prefix.load(Ordering::Relaxed));
+
+        if self.version.load(Ordering::Acquire) != version {
+            return None;
+        }
+
+        matched.then_some((length, level))
+    }
+
+    fn prefix_equals(&self, target: &[u8]) -> bool {
+        usize::from(self.length.load(Ordering::Relaxed)) == target.len()
+            && target.iter().zip(self.prefix.iter()).all(|(byte, prefix)| *byte == prefix.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, target: &[u8], level: u8) {
+        self.version.fetch_add(1, Ordering::AcqRel);
+        for (slot, byte) in self.prefix.iter().zip(target.iter()) {
+            slot.store(*byte, Ordering::Relaxed);
+        }
+        self.length.store(target.len() as u8, Ordering::Relaxed);
+        self.level.store(level, Ordering::Relaxed);
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+static OVERRIDES: [TargetOverride; MAX_TARGET_OVERRIDES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: TargetOverride = TargetOverride::new();
+    [EMPTY; MAX_TARGET_OVERRIDES]
+};
+
+static OVERRIDE_COUNT: AtomicU8 = AtomicU8::new(0);
+
+pub fn max_level() -> Level {
+    Level::try_from(MAX_LEVEL.load(Ordering::Relaxed)).unwrap_or(DEFAULT_LEVEL)
+}
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level.into(), Ordering::Relaxed);
+    update_ceiling();
+}
+
+/// Serializes writers; readers never take it.
+static WRITER: AtomicBool = AtomicBool::new(false);
+
+fn with_writer_lock<R>(f: impl FnOnce() -> R) -> R {
+    while WRITER.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        core::hint::spin_loop();
+    }
+    let result = f();
+    WRITER.store(false, Ordering::Release);
+    result
+}
+
+/// Sets the level for every target starting with `target`, e.g. `interustception::device`.
+/// Returns `false` when the prefix is too long or all override slots are taken.
+pub fn set_target_level(target: &str, level: Level) -> bool {
+    let target = target.as_bytes();
+    if target.is_empty() || target.len() > MAX_TARGET_LENGTH {
+        return false;
+    }
+
+    with_writer_lock(|| store_target_level(target, level))
+}
+
+fn store_target_level(target: &[u8], level: Level) -> bool {
+    let slot = OVERRIDES.iter().find(|slot| slot.level.load(Ordering::Relaxed) != EMPTY_LEVEL && slot.prefix_equals(target))
+        .or_else(|| OVERRIDES.iter().find(|slot| slot.level.load(Ordering::Relaxed) == EMPTY_LEVEL));
+
+    let Some(slot) = slot else {
+        return false;
+    };
+
+    if slot.level.load(Ordering::Relaxed) == EMPTY_LEVEL {
+        OVERRIDE_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    slot.store(target, level.into());
+    update_ceiling();
+    true
+}
+
+pub fn clear_target_levels() {
+    with_writer_lock(|| {
+        for slot in &OVERRIDES {
+            slot.store(&[], EMPTY_LEVEL);
+        }
+        OVERRIDE_COUNT.store(0, Ordering::Relaxed);
+        update_ceiling();
+    });
+}
+
+fn update_ceiling() {
+    let ceiling = OVERRIDES.iter()
+        .map(|slot| slot.level.load(Ordering::Relaxed))
+        .filter(|level| *level != EMPTY_LEVEL)
+        .fold(MAX_LEVEL.load(Ordering::Relaxed), u8::max);
+    CEILING.store(ceiling, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled(level: Level, target: &str) -> bool {
+    let level = level as u8;
+    if level > CEILING.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    if OVERRIDE_COUNT.load(Ordering::Relaxed) == 0 {
+        return level <= MAX_LEVEL.load(Ordering::Relaxed);
+    }
+
+    // The longest matching prefix wins.
+    let limit = OVERRIDES.iter()
+        .filter_map(|slot| slot.matches(target))
+        .max_by_key(|(length, _)| *length)
+        .map_or_else(|| MAX_LEVEL.load(Ordering::Relaxed), |(_, level)| level);
+
+    level <= limit
+}
+
+pub const RING_CAPACITY: usize = 256;
+
+/// The most recent records, kept in nonpaged memory so support tools can fetch them without a debugger.
+pub static RING: SpinLock<LogRing<RING_CAPACITY>> = SpinLock::new(LogRing::new());
+
+pub fn write(level: Level, target: &str, args: Arguments) {
+    wdk::println!("[{}] [{}] {}", level.as_str(), target, args);
+    let module = target.strip_prefix(concat!(env!("CARGO_PKG_NAME"), "::")).unwrap_or(target);
+    RING.lock().push(time::system_time(), level.into(), module, args);
+}
+
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if level <= $crate::framework::log::STATIC_MAX_LEVEL && $crate::framework::log::enabled(level, $target) {
+            $crate::framework::log::write(level, $target, core::format_args!($($arg)+));
+        }
+    }};
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log!(target: core::module_path!(), $level, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::log!($crate::framework::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::log!($crate::framework::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::log!($crate::framework::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::log!($crate::framework::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)+) => { $crate::log!($crate::framework::log::Level::Trace, $($arg)+) };
+}
@@ -0,0 +1,372 @@
+//! Fixed-size ring of the most recent log records and their wire encoding.
+//!
+//! Everything here only depends on `core`, so the ring and its encoding can be exercised on the host.
+//!
+//! The encoding is a [`HEADER_SIZE`] byte header followed by `count` records of [`RECORD_SIZE`] bytes,
+//! oldest first. All integers are little endian.
+//!
+//! Header: `version: u32, record_size: u32, count: u32, reserved: u32, next_sequence: u64`.
+//!
+//! Record: `sequence: u64, timestamp: u64, level: u8, module_length: u8, message_length: u16,
+//! reserved: u32, module: [u8; MODULE_LENGTH], message: [u8; MESSAGE_LENGTH]`.
+//! Timestamps are system time in 100ns units since 1601, as returned by `KeQuerySystemTimePrecise`.
+
+use core::fmt::{Arguments, Write};
+
+pub const ENCODING_VERSION: u32 = 1;
+pub const MODULE_LENGTH: usize = 32;
+pub const MESSAGE_LENGTH: usize = 120;
+pub const HEADER_SIZE: usize = 24;
+pub const RECORD_SIZE: usize = 24 + MODULE_LENGTH + MESSAGE_LENGTH;
+
+#[derive(Debug, Copy, Clone)]
+pub struct LogRecord {
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub level: u8,
+    module_length: u8,
+    message_length: u16,
+    module: [u8; MODULE_LENGTH],
+    message: [u8; MESSAGE_LENGTH],
+}
+
+impl LogRecord {
+    pub const EMPTY: Self = Self {
+        sequence: 0,
+        timestamp: 0,
+        level: 0,
+        module_length: 0,
+        message_length: 0,
+        module: [0; MODULE_LENGTH],
+        message: [0; MESSAGE_LENGTH],
+    };
+
+    pub fn module(&self) -> &[u8] {
+        &self.module[..usize::from(self.module_length)]
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message[..usize::from(self.message_length)]
+    }
+
+    pub fn encode(&self, out: &mut [u8; RECORD_SIZE]) {
+        out[0..8].copy_from_slice(&self.sequence.to_le_bytes());
+        out[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        out[16] = self.level;
+        out[17] = self.module_length;
+        out[18..20].copy_from_slice(&self.message_length.to_le_bytes());
+        out[20..24].fill(0);
+        out[24..24 + MODULE_LENGTH].copy_from_slice(&self.module);
+        out[24 + MODULE_LENGTH..].copy_from_slice(&self.message);
+    }
+
+    pub fn decode(bytes: &[u8; RECORD_SIZE]) -> Self {
+        let mut record = Self::EMPTY;
+        record.sequence = u64::from_le_bytes(bytes[0..8].try_into().unwrap_or_default());
+        record.timestamp = u64::from_le_bytes(bytes[8..16].try_into().unwrap_or_default());
+        record.level = bytes[16];
+        record.module_length = bytes[17].min(MODULE_LENGTH as u8);
+        record.message_length = u16::from_le_bytes([bytes[18], bytes[19]]).min(MESSAGE_LENGTH as u16);
+        record.module.copy_from_slice(&bytes[24..24 + MODULE_LENGTH]);
+        record.message.copy_from_slice(&bytes[24 + MODULE_LENGTH..]);
+        record
+    }
+}
+
+/// Copies as much of the formatted text as fits, cutting on a UTF-8 character boundary.
+struct TruncatingWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let available = self.buffer.len() - self.length;
+        let mut take = s.len().min(available);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+
+        self.buffer[self.length..self.length + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.length += take;
+        Ok(())
+    }
+}
+
+fn copy_truncated(buffer: &mut [u8], args: Arguments) -> usize {
+    let mut writer = TruncatingWriter { buffer, length: 0 };
+    let _ = writer.write_fmt(args);
+    writer.length
+}
+
+/// Keeps the last `N` records, overwriting the oldest one when full.
+pub struct LogRing<const N: usize> {
+    records: [LogRecord; N],
+    next_sequence: u64,
+}
+
+impl<const N: usize> LogRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            records: [LogRecord::EMPTY; N],
+            next_sequence: 0,
+        }
+    }
+
+    pub fn push(&mut self, timestamp: u64, level: u8, module: &str, message: Arguments) {
+        if N == 0 {
+            return;
+        }
+
+        let record = &mut self.records[(self.next_sequence % N as u64) as usize];
+        record.sequence = self.next_sequence;
+        record.timestamp = timestamp;
+        record.level = level;
+        record.module_length = copy_truncated(&mut record.module, format_args!("{module}")) as u8;
+        record.message_length = copy_truncated(&mut record.message, message) as u16;
+
+        self.next_sequence += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.next_sequence.min(N as u64) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next_sequence == 0
+    }
+
+    /// Sequence number the next record will get. Clients use it to detect records they missed.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    pub fn clear(&mut self) {
+        self.next_sequence = 0;
+    }
+
+    /// Records from the oldest to the newest.
+    pub fn iter(&self) -> impl Iterator<Item = &LogRecord> {
+        let oldest = self.next_sequence - self.len() as u64;
+        (oldest..self.next_sequence).map(move |sequence| &self.records[(sequence % N as u64) as usize])
+    }
+
+    /// Encodes the header followed by as many of the newest records as fit in `out`.
+    /// Returns the number of bytes written, or `None` if not even the header fits.
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        if out.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let count = self.len().min((out.len() - HEADER_SIZE) / RECORD_SIZE);
+        let (header, body) = out.split_at_mut(HEADER_SIZE);
+
+        header[0..4].copy_from_slice(&ENCODING_VERSION.to_le_bytes());
+        header[4..8].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&(count as u32).to_le_bytes());
+        header[12..16].fill(0);
+        header[16..24].copy_from_slice(&self.next_sequence.to_le_bytes());
+
+        let skipped = self.len() - count;
+        for (record, chunk) in self.iter().skip(skipped).zip(body.chunks_exact_mut(RECORD_SIZE)) {
+            if let Ok(chunk) = <&mut [u8; RECORD_SIZE]>::try_from(chunk) {
+                record.encode(chunk);
+            }
+        }
+
+        Some(HEADER_SIZE + count * RECORD_SIZE)
+    }
+}
+
+impl<const N: usize> Default for LogRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages<const N: usize>(ring: &LogRing<N>) -> impl Iterator<Item = &[u8]> {
+        ring.iter().map(LogRecord::message)
+    }
+
+    #[test]
+    fn keeps_records_in_order_until_full() {
+        let mut ring = LogRing::<4>::new();
+        assert!(ring.is_empty());
+
+        for i in 0..3 {
+            ring.push(100 + i, 2, "module", format_args!("record {i}"));
+        }
+
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.next_sequence(), 3);
+        assert!(messages(&ring).eq([&b"record 0"[..], b"record 1", b"record 2"]));
+        assert!(ring.iter().map(|record| record.sequence).eq(0..3));
+        assert!(ring.iter().map(|record| record.timestamp).eq(100..103));
+    }
+
+    #[test]
+    fn overwrites_the_oldest_records_when_full() {
+        let mut ring = LogRing::<4>::new();
+        for i in 0..10 {
+            ring.push(i, 1, "module", format_args!("record {i}"));
+        }
+
+        assert_eq!(ring.len(), 4);
+        assert_eq!(ring.next_sequence(), 10);
+        assert!(ring.iter().map(|record| record.sequence).eq(6..10));
+        assert!(messages(&ring).eq([&b"record 6"[..], b"record 7", b"record 8", b"record 9"]));
+    }
+
+    #[test]
+    fn wraps_around_repeatedly() {
+        let mut ring = LogRing::<3>::new();
+        for i in 0..3 * 5 + 1 {
+            ring.push(i, 0, "m", format_args!("{i}"));
+            let oldest = (i + 1).saturating_sub(3);
+            assert!(ring.iter().map(|record| record.sequence).eq(oldest..=i));
+        }
+    }
+
+    #[test]
+    fn clear_keeps_nothing() {
+        let mut ring = LogRing::<2>::new();
+        ring.push(0, 0, "m", format_args!("gone"));
+        ring.clear();
+
+        assert!(ring.is_empty());
+        assert_eq!(ring.iter().count(), 0);
+        ring.push(0, 0, "m", format_args!("kept"));
+        assert!(messages(&ring).eq([&b"kept"[..]]));
+    }
+
+    #[test]
+    fn empty_ring_stores_nothing() {
+        let mut ring = LogRing::<0>::new();
+        ring.push(0, 0, "m", format_args!("dropped"));
+
+        assert!(ring.is_empty());
+        assert_eq!(ring.iter().count(), 0);
+    }
+
+    #[test]
+    fn truncates_long_modules_and_messages() {
+        let module = "m".repeat(MODULE_LENGTH + 5);
+        let message = "x".repeat(MESSAGE_LENGTH * 2);
+        let mut ring = LogRing::<1>::new();
+        ring.push(0, 0, &module, format_args!("{message}"));
+
+        let record = ring.iter().next().unwrap();
+        assert_eq!(record.module(), &module.as_bytes()[..MODULE_LENGTH]);
+        assert_eq!(record.message(), &message.as_bytes()[..MESSAGE_LENGTH]);
+    }
+
+    #[test]
+    fn truncates_across_several_arguments() {
+        let half = "y".repeat(MESSAGE_LENGTH / 2 + 1);
+        let mut ring = LogRing::<1>::new();
+        ring.push(0, 0, "m", format_args!("{half}{half}"));
+
+        let record = ring.iter().next().unwrap();
+        assert_eq!(record.message().len(), MESSAGE_LENGTH);
+        assert!(record.message().iter().all(|&byte| byte == b'y'));
+    }
+
+    #[test]
+    fn truncates_on_a_character_boundary() {
+        // One byte short of room for the last three byte character
+        let message = format!("{}€", "a".repeat(MESSAGE_LENGTH - 2));
+        let mut ring = LogRing::<1>::new();
+        ring.push(0, 0, "m", format_args!("{message}"));
+
+        let record = ring.iter().next().unwrap();
+        assert_eq!(record.message(), "a".repeat(MESSAGE_LENGTH - 2).as_bytes());
+        assert!(core::str::from_utf8(record.message()).is_ok());
+    }
+
+    #[test]
+    fn records_round_trip_through_their_encoding() {
+        let mut ring = LogRing::<1>::new();
+        ring.push(0x0123_4567_89AB_CDEF, 3, "interustception::device", format_args!("stroke {:#X}", 0x1E));
+        let record = ring.iter().next().unwrap();
+
+        let mut bytes = [0xFF; RECORD_SIZE];
+        record.encode(&mut bytes);
+        assert_eq!(bytes[0..8], 0u64.to_le_bytes());
+        assert_eq!(bytes[8..16], 0x0123_4567_89AB_CDEFu64.to_le_bytes());
+        assert_eq!(bytes[16], 3);
+        assert_eq!(usize::from(bytes[17]), "interustception::device".len());
+        assert_eq!(bytes[18..20], ("stroke 0x1E".len() as u16).to_le_bytes());
+        assert_eq!(bytes[20..24], [0; 4]);
+
+        let decoded = LogRecord::decode(&bytes);
+        assert_eq!(decoded.sequence, record.sequence);
+        assert_eq!(decoded.timestamp, record.timestamp);
+        assert_eq!(decoded.level, record.level);
+        assert_eq!(decoded.module(), b"interustception::device");
+        assert_eq!(decoded.message(), b"stroke 0x1E");
+    }
+
+    #[test]
+    fn decoding_clamps_lengths() {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[17] = u8::MAX;
+        bytes[18..20].copy_from_slice(&u16::MAX.to_le_bytes());
+
+        let record = LogRecord::decode(&bytes);
+        assert_eq!(record.module().len(), MODULE_LENGTH);
+        assert_eq!(record.message().len(), MESSAGE_LENGTH);
+    }
+
+    #[test]
+    fn encodes_the_header_and_every_record_that_fits() {
+        let mut ring = LogRing::<4>::new();
+        for i in 0..6 {
+            ring.push(i, 0, "m", format_args!("record {i}"));
+        }
+
+        let mut out = vec![0; HEADER_SIZE + 4 * RECORD_SIZE];
+        assert_eq!(ring.encode(&mut out), Some(out.len()));
+        assert_eq!(out[0..4], ENCODING_VERSION.to_le_bytes());
+        assert_eq!(out[4..8], (RECORD_SIZE as u32).to_le_bytes());
+        assert_eq!(out[8..12], 4u32.to_le_bytes());
+        assert_eq!(out[12..16], [0; 4]);
+        assert_eq!(out[16..24], 6u64.to_le_bytes());
+
+        let decoded = out[HEADER_SIZE..].chunks_exact(RECORD_SIZE)
+            .map(|chunk| LogRecord::decode(chunk.try_into().unwrap()).sequence);
+        assert!(decoded.eq(2..6));
+    }
+
+    #[test]
+    fn encodes_the_newest_records_when_short_of_room() {
+        let mut ring = LogRing::<4>::new();
+        for i in 0..4 {
+            ring.push(i, 0, "m", format_args!("record {i}"));
+        }
+
+        // Room for two records and part of a third
+        let mut out = vec![0; HEADER_SIZE + 2 * RECORD_SIZE + RECORD_SIZE / 2];
+        assert_eq!(ring.encode(&mut out), Some(HEADER_SIZE + 2 * RECORD_SIZE));
+        assert_eq!(out[8..12], 2u32.to_le_bytes());
+
+        let decoded = out[HEADER_SIZE..HEADER_SIZE + 2 * RECORD_SIZE].chunks_exact(RECORD_SIZE)
+            .map(|chunk| LogRecord::decode(chunk.try_into().unwrap()));
+        assert!(decoded.map(|record| record.sequence).eq(2..4));
+    }
+
+    #[test]
+    fn encoding_needs_room_for_the_header() {
+        let mut ring = LogRing::<1>::new();
+        ring.push(0, 0, "m", format_args!("record"));
+
+        assert_eq!(ring.encode(&mut [0; HEADER_SIZE - 1]), None);
+        let mut out = [0; HEADER_SIZE];
+        assert_eq!(ring.encode(&mut out), Some(HEADER_SIZE));
+        assert_eq!(out[8..12], 0u32.to_le_bytes());
+        assert_eq!(out[16..24], 1u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,63 @@
+use core::mem::MaybeUninit;
+use bytemuck::AnyBitPattern;
+use wdk_sys::WDFMEMORY;
+use wdk_sys::macros::call_unsafe_wdf_function_binding;
+use crate::framework::{ErrorCode, NtStatusError, Result};
+
+#[derive(Debug, Copy, Clone)]
+pub struct Memory {
+    handle: WDFMEMORY,
+}
+
+impl Memory {
+    pub fn new(handle: WDFMEMORY) -> Self {
+        Self {
+            handle
+        }
+    }
+
+    pub fn handle(&self) -> WDFMEMORY {
+        self.handle
+    }
+
+    pub fn size(&self) -> usize {
+        let mut size = 0usize;
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfMemoryGetBuffer,
+                self.handle,
+                &mut size,
+            )
+        };
+        size
+    }
+
+    /// Copies a `T` out of the memory object, starting at `offset`.
+    pub fn read<T: AnyBitPattern>(&self, offset: usize) -> Result<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfMemoryCopyToBuffer,
+                self.handle,
+                offset,
+                value.as_mut_ptr().cast(),
+                core::mem::size_of::<T>(),
+            )
+        }.check_status(ErrorCode::MemoryCopyFailed)?;
+
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Copies `value` into the memory object, starting at `offset`.
+    pub fn write<T: Copy>(&self, offset: usize, value: &T) -> Result<()> {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfMemoryCopyFromBuffer,
+                self.handle,
+                offset,
+                (value as *const T).cast_mut().cast(),
+                core::mem::size_of::<T>(),
+            )
+        }.check_status(ErrorCode::MemoryCopyFailed)
+    }
+}
@@ -5,9 +5,30 @@ pub mod wdf_object_context;
 pub mod utils;
 pub mod queue;
 pub mod pdo;
+pub mod object_attributes;
+pub mod memory;
+pub mod completion;
+pub mod io_target;
+pub mod nt_status;
+pub mod log;
+pub mod registry;
+pub mod log_ring;
+pub mod spin_lock;
+pub mod bounded_queue;
+pub mod time;
+pub mod control_device;
+pub mod file_object;
+pub mod security;
+pub mod timer;
+pub mod event;
 
 pub use queue::*;
 pub use driver::*;
 pub use device::*;
 pub use error::*;
 pub use wdf_object_context::*;
+pub use object_attributes::*;
+pub use memory::*;
+pub use completion::*;
+pub use io_target::*;
+pub use file_object::*;
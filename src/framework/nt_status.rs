@@ -0,0 +1,59 @@
+use core::fmt::{Display, Formatter};
+use wdk_sys::*;
+
+/// Symbolic name of the most common `NTSTATUS` values, as they appear in `ntstatus.h`.
+pub fn nt_status_name(status: NTSTATUS) -> Option<&'static str> {
+    Some(match status {
+        STATUS_SUCCESS => "STATUS_SUCCESS",
+        STATUS_PENDING => "STATUS_PENDING",
+        STATUS_TIMEOUT => "STATUS_TIMEOUT",
+        STATUS_BUFFER_OVERFLOW => "STATUS_BUFFER_OVERFLOW",
+        STATUS_NO_MORE_ENTRIES => "STATUS_NO_MORE_ENTRIES",
+        STATUS_UNSUCCESSFUL => "STATUS_UNSUCCESSFUL",
+        STATUS_NOT_IMPLEMENTED => "STATUS_NOT_IMPLEMENTED",
+        STATUS_INVALID_HANDLE => "STATUS_INVALID_HANDLE",
+        STATUS_INVALID_PARAMETER => "STATUS_INVALID_PARAMETER",
+        STATUS_INVALID_DEVICE_REQUEST => "STATUS_INVALID_DEVICE_REQUEST",
+        STATUS_END_OF_FILE => "STATUS_END_OF_FILE",
+        STATUS_NO_MEMORY => "STATUS_NO_MEMORY",
+        STATUS_ACCESS_DENIED => "STATUS_ACCESS_DENIED",
+        STATUS_BUFFER_TOO_SMALL => "STATUS_BUFFER_TOO_SMALL",
+        STATUS_OBJECT_NAME_NOT_FOUND => "STATUS_OBJECT_NAME_NOT_FOUND",
+        STATUS_OBJECT_NAME_COLLISION => "STATUS_OBJECT_NAME_COLLISION",
+        STATUS_SHARING_VIOLATION => "STATUS_SHARING_VIOLATION",
+        STATUS_INSUFFICIENT_RESOURCES => "STATUS_INSUFFICIENT_RESOURCES",
+        STATUS_NOT_SUPPORTED => "STATUS_NOT_SUPPORTED",
+        STATUS_INVALID_DEVICE_STATE => "STATUS_INVALID_DEVICE_STATE",
+        STATUS_IO_TIMEOUT => "STATUS_IO_TIMEOUT",
+        STATUS_CANCELLED => "STATUS_CANCELLED",
+        STATUS_DEVICE_NOT_READY => "STATUS_DEVICE_NOT_READY",
+        STATUS_DEVICE_NOT_CONNECTED => "STATUS_DEVICE_NOT_CONNECTED",
+        STATUS_DEVICE_DOES_NOT_EXIST => "STATUS_DEVICE_DOES_NOT_EXIST",
+        STATUS_DEVICE_BUSY => "STATUS_DEVICE_BUSY",
+        STATUS_DEVICE_REMOVED => "STATUS_DEVICE_REMOVED",
+        STATUS_DELETE_PENDING => "STATUS_DELETE_PENDING",
+        STATUS_REVISION_MISMATCH => "STATUS_REVISION_MISMATCH",
+        STATUS_INVALID_BUFFER_SIZE => "STATUS_INVALID_BUFFER_SIZE",
+        STATUS_INTEGER_OVERFLOW => "STATUS_INTEGER_OVERFLOW",
+        STATUS_NOT_FOUND => "STATUS_NOT_FOUND",
+        STATUS_OBJECT_TYPE_MISMATCH => "STATUS_OBJECT_TYPE_MISMATCH",
+        STATUS_PRIVILEGE_NOT_HELD => "STATUS_PRIVILEGE_NOT_HELD",
+        STATUS_INTERNAL_ERROR => "STATUS_INTERNAL_ERROR",
+        STATUS_DATA_ERROR => "STATUS_DATA_ERROR",
+        STATUS_UNMAPPABLE_CHARACTER => "STATUS_UNMAPPABLE_CHARACTER",
+        _ => return None,
+    })
+}
+
+/// Displays an `NTSTATUS` as `STATUS_NAME (0xC0000023)`, falling back to the bare code.
+#[derive(Debug, Copy, Clone)]
+pub struct NtStatusName(pub NTSTATUS);
+
+impl Display for NtStatusName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match nt_status_name(self.0) {
+            Some(name) => write!(f, "{name} ({:#010X})", self.0),
+            None => write!(f, "{:#010X}", self.0),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use wdk_sys::{PFN_WDF_OBJECT_CONTEXT_CLEANUP, PFN_WDF_OBJECT_CONTEXT_DESTROY, PWDF_OBJECT_ATTRIBUTES, WDF_EXECUTION_LEVEL, WDF_OBJECT_ATTRIBUTES, WDF_SYNCHRONIZATION_SCOPE, WDFOBJECT};
+use wdk_sys::_WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent;
+use wdk_sys::_WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent;
+use crate::framework::Context;
+use crate::init_object;
+
+/// Builder for `WDF_OBJECT_ATTRIBUTES`, shared by devices, queues, timers and memory objects.
+#[derive(Debug)]
+pub struct ObjectAttributes {
+    attrs: WDF_OBJECT_ATTRIBUTES,
+}
+
+impl ObjectAttributes {
+    pub fn new() -> Self {
+        let mut attrs = init_object!(WDF_OBJECT_ATTRIBUTES);
+        attrs.ExecutionLevel = WdfExecutionLevelInheritFromParent;
+        attrs.SynchronizationScope = WdfSynchronizationScopeInheritFromParent;
+
+        Self {
+            attrs
+        }
+    }
+
+    /// Attaches a `T` context to the object. The context is dropped in place when the object is
+    /// destroyed, unless a callback set with [`Self::with_destroy_callback`] takes over.
+    pub fn with_context<T: Context>(&mut self) -> &mut Self {
+        self.attrs.ContextTypeInfo = T::get_context_type_info();
+        if self.attrs.EvtDestroyCallback.is_none() {
+            self.attrs.EvtDestroyCallback = Some(drop_context::<T>);
+        }
+        self
+    }
+
+    pub fn with_parent(&mut self, parent: WDFOBJECT) -> &mut Self {
+        self.attrs.ParentObject = parent;
+        self
+    }
+
+    pub fn with_cleanup_callback(&mut self, callback: PFN_WDF_OBJECT_CONTEXT_CLEANUP) -> &mut Self {
+        self.attrs.EvtCleanupCallback = callback;
+        self
+    }
+
+    /// Installs a destroy callback in place of the one dropping the context, whether
+    /// [`Self::with_context`] is called before or after, as builders do when creating the object.
+    /// The callback is then responsible for calling [`drop_context`] itself.
+    pub fn with_destroy_callback(&mut self, callback: PFN_WDF_OBJECT_CONTEXT_DESTROY) -> &mut Self {
+        self.attrs.EvtDestroyCallback = callback;
+        self
+    }
+
+    pub fn with_execution_level(&mut self, level: WDF_EXECUTION_LEVEL) -> &mut Self {
+        self.attrs.ExecutionLevel = level;
+        self
+    }
+
+    pub fn with_synchronization_scope(&mut self, scope: WDF_SYNCHRONIZATION_SCOPE) -> &mut Self {
+        self.attrs.SynchronizationScope = scope;
+        self
+    }
+
+    pub fn as_mut_ptr(&mut self) -> PWDF_OBJECT_ATTRIBUTES {
+        core::ptr::addr_of_mut!(self.attrs)
+    }
+}
+
+impl Default for ObjectAttributes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs the Rust destructor of the `T` context attached to `object`.
+pub unsafe extern "C" fn drop_context<T: Context>(object: WDFOBJECT) {
+    let context = unsafe { T::get_context(object) };
+    if !context.is_null() {
+        unsafe { core::ptr::drop_in_place(context) };
+    }
+}
@@ -1,11 +1,10 @@
 use nt_string::unicode_string::{NtUnicodeStr, NtUnicodeString};
-use wdk_sys::{GUID, PWDFDEVICE_INIT, UNICODE_STRING, WDF_DEVICE_PNP_CAPABILITIES, WDF_OBJECT_ATTRIBUTES, WDFDEVICE, WDFDEVICE_INIT, WDFOBJECT};
-use wdk_sys::_WDF_EXECUTION_LEVEL::WdfExecutionLevelInheritFromParent;
-use wdk_sys::_WDF_SYNCHRONIZATION_SCOPE::WdfSynchronizationScopeInheritFromParent;
+use wdk_sys::{GUID, PWDFDEVICE_INIT, UNICODE_STRING, WDF_DEVICE_PNP_CAPABILITIES, WDFDEVICE, WDFDEVICE_INIT, WDFOBJECT};
 use wdk_sys::_WDF_TRI_STATE::WdfUseDefault;
 use wdk_sys::macros::call_unsafe_wdf_function_binding;
-use crate::framework::{Context, Device, ErrorCode, NtStatusError, Result};
-use crate::{dbg, init_object};
+use crate::framework::{assign_sddl, Context, Device, ErrorCode, FileObjectConfig, NtStatusError, ObjectAttributes, Result};
+use crate::framework::security::default_sddl;
+use crate::{init_object, log_trace};
 
 pub(crate) struct PdoBuilder {
     init: PWDFDEVICE_INIT,
@@ -14,6 +13,9 @@ pub(crate) struct PdoBuilder {
     instance_id: Option<NtUnicodeString>,
     device_text: Option<(NtUnicodeString, NtUnicodeStr<'static>, u32)>,
     allow_forwarding_request_to_parent: bool,
+    sddl: Option<NtUnicodeStr<'static>>,
+    file_object_config: Option<FileObjectConfig>,
+    attrs: ObjectAttributes,
 }
 
 impl PdoBuilder {
@@ -30,6 +32,9 @@ impl PdoBuilder {
             instance_id: None,
             device_text: None,
             allow_forwarding_request_to_parent: false,
+            sddl: None,
+            file_object_config: None,
+            attrs: ObjectAttributes::new(),
         }
     }
 
@@ -58,37 +63,55 @@ impl PdoBuilder {
         self
     }
 
+    /// Replaces [`default_sddl`], which raw PDOs otherwise get so user mode can't open them freely.
+    pub(crate) fn with_sddl(&mut self, sddl: NtUnicodeStr<'static>) -> &mut Self {
+        self.sddl = Some(sddl);
+        self
+    }
+
+    pub(crate) fn with_file_object_config(&mut self, config: FileObjectConfig) -> &mut Self {
+        self.file_object_config = Some(config);
+        self
+    }
+
+    pub(crate) fn attributes(&mut self) -> &mut ObjectAttributes {
+        &mut self.attrs
+    }
+
     pub fn build_with_context<T: Context>(&mut self) -> Result<PdoDevice<T>> {
-        dbg!(self.handle_class()?);
+        self.handle_class()?;
+
+        self.handle_device_id()?;
 
-        dbg!(self.handle_device_id()?);
+        self.handle_instance_id()?;
 
-        dbg!(self.handle_instance_id()?);
+        self.handle_device_text()?;
 
-        dbg!(self.handle_device_text()?);
+        assign_sddl(self.init, self.sddl.unwrap_or_else(default_sddl))?;
+
+        if let Some(config) = &mut self.file_object_config {
+            config.apply(self.init);
+        }
 
         if self.allow_forwarding_request_to_parent {
-            dbg!(unsafe {
+            unsafe {
                 call_unsafe_wdf_function_binding!(
                 WdfPdoInitAllowForwardingRequestToParent,
                 self.init,
             )
-            });
+            };
         }
 
-        let mut attrs = init_object!(WDF_OBJECT_ATTRIBUTES);
-        attrs.ExecutionLevel = WdfExecutionLevelInheritFromParent;
-        attrs.SynchronizationScope = WdfSynchronizationScopeInheritFromParent;
-        attrs.ContextTypeInfo = T::get_context_type_info();
+        self.attrs.with_context::<T>();
 
         let mut device_ptr = core::ptr::null_mut();
         let device = unsafe {
-            dbg!(call_unsafe_wdf_function_binding!(
+            call_unsafe_wdf_function_binding!(
             WdfDeviceCreate,
             &mut self.init as *mut *mut WDFDEVICE_INIT,
-            (&mut attrs) as *mut WDF_OBJECT_ATTRIBUTES,
+            self.attrs.as_mut_ptr(),
             &mut device_ptr,
-        )) }.check_status(ErrorCode::DeviceCreationFailed).map(|_| {
+        ) }.check_status(ErrorCode::DeviceCreationFailed).map(|_| {
             Device::<T>::new(unsafe { device_ptr.as_mut().expect("Device is null")})
         })?;
 
@@ -162,16 +185,16 @@ impl PdoBuilder {
 impl Drop for PdoBuilder {
     fn drop(&mut self) {
         if self.init.is_null() {
-            dbg!("Not dropping builder");
+            log_trace!("PdoBuilder consumed by WdfDeviceCreate, not freeing init");
             return;
         }
 
-        dbg!(unsafe {
+        unsafe {
             call_unsafe_wdf_function_binding!(
                 WdfDeviceInitFree,
                 self.init as *mut WDFDEVICE_INIT
             );
-        })
+        }
     }
 }
 
@@ -258,12 +281,12 @@ impl<'a, T: Context> PdoDevice<'a, T> {
 
 impl<'a, T: Context> Drop for PdoDevice<'a, T> {
     fn drop(&mut self) {
-        dbg!("dropping");
-        dbg!(unsafe {
+        log_trace!("Deleting unsaved PDO");
+        unsafe {
             call_unsafe_wdf_function_binding!(
                 WdfObjectDelete,
                 self.handle() as *mut _ as WDFOBJECT
             )
-        })
+        }
     }
 }
@@ -1,15 +1,25 @@
-use core::ptr::null_mut;
-use wdk_sys::_WDF_IO_QUEUE_DISPATCH_TYPE::{WdfIoQueueDispatchParallel, WdfIoQueueDispatchSequential};
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::ptr::{null_mut, NonNull};
+use bytemuck::AnyBitPattern;
+use wdk_sys::_MEMORY_CACHING_TYPE::MmCached;
+use wdk_sys::_MM_PAGE_PRIORITY::NormalPagePriority;
+use wdk_sys::_MODE::KernelMode;
+use wdk_sys::_WDF_IO_QUEUE_DISPATCH_TYPE::{WdfIoQueueDispatchManual, WdfIoQueueDispatchParallel, WdfIoQueueDispatchSequential};
+use wdk_sys::_WDF_REQUEST_TYPE::WdfRequestTypeDeviceControl;
 use wdk_sys::_WDF_TRI_STATE::WdfUseDefault;
-use wdk_sys::{NTSTATUS, PFN_WDF_IO_QUEUE_IO_DEVICE_CONTROL, PFN_WDF_IO_QUEUE_IO_INTERNAL_DEVICE_CONTROL, PFN_WDF_REQUEST_COMPLETION_ROUTINE, PVOID, ULONG, WDF_IO_QUEUE_CONFIG, WDF_NO_OBJECT_ATTRIBUTES, WDF_REQUEST_SEND_OPTIONS, WDFDEVICE, WDFIOTARGET, WDFMEMORY, WDFQUEUE, WDFREQUEST__};
+use wdk_sys::{ACCESS_MASK, FALSE, KPROCESSOR_MODE, MDL_MAPPED_TO_SYSTEM_VA, MDL_SOURCE_IS_NONPAGED_POOL, MdlMappingNoExecute, NTSTATUS, PFN_WDF_IO_QUEUE_IO_DEVICE_CONTROL, PFN_WDF_IO_QUEUE_IO_INTERNAL_DEVICE_CONTROL, PFN_WDF_REQUEST_CANCEL, PFN_WDF_REQUEST_COMPLETION_ROUTINE, PMDL, PVOID, STATUS_CANCELLED, STATUS_INSUFFICIENT_RESOURCES, ULONG, ULONG_PTR, WDF_IO_QUEUE_CONFIG, WDF_REQUEST_PARAMETERS, WDF_REQUEST_SEND_OPTIONS, WDFDEVICE, WDFOBJECT, WDFQUEUE, WDFREQUEST, WDFREQUEST__};
+use wdk::nt_success;
 use wdk_sys::macros::call_unsafe_wdf_function_binding;
+use wdk_sys::ntddk::MmMapLockedPagesSpecifyCache;
 use crate::foreign::ConnectData;
-use crate::framework::{Result, ErrorCode, NtStatusError, Device, Context};
+use crate::framework::{Result, Error, ErrorCode, NtStatusError, Device, Context, FileObject, HasContext, IoTarget, Memory, NoContext, ObjectAttributes};
+use crate::framework::completion::{Completion, CompletionCallback, completion_trampoline};
 use crate::init_object;
 
-#[repr(transparent)]
 pub struct QueueBuilder {
     pub config: WDF_IO_QUEUE_CONFIG,
+    attrs: ObjectAttributes,
 }
 
 impl QueueBuilder {
@@ -19,10 +29,15 @@ impl QueueBuilder {
         config.DispatchType = WdfIoQueueDispatchSequential;
 
         Self {
-            config
+            config,
+            attrs: ObjectAttributes::new(),
         }
     }
 
+    pub fn attributes(&mut self) -> &mut ObjectAttributes {
+        &mut self.attrs
+    }
+
     pub fn internal_device_control(&mut self, callback: PFN_WDF_IO_QUEUE_IO_INTERNAL_DEVICE_CONTROL) -> &mut Self {
         self.config.EvtIoInternalDeviceControl = callback;
         self
@@ -38,36 +53,60 @@ impl QueueBuilder {
         self
     }
 
+    /// Requests stay in the queue until the driver retrieves them.
+    pub fn manual_dispatch(&mut self) -> &mut Self {
+        self.config.DispatchType = WdfIoQueueDispatchManual;
+        self
+    }
+
     pub fn parallel_dispatch(&mut self) -> &mut Self {
         self.config.DispatchType = WdfIoQueueDispatchParallel;
         unsafe { self.config.Settings.Parallel }.NumberOfPresentedRequests = ULONG::MAX;
         self
     }
 
-    pub fn create(&mut self, device: WDFDEVICE) -> Result<Queue> {
+    pub fn create<D: Context>(&mut self, device: &mut Device<D>) -> Result<Queue<D>> {
+        self.create_with_context::<NoContext, D>(device)
+    }
+
+    pub fn create_with_context<T: Context, D: Context>(&mut self, device: &mut Device<D>) -> Result<Queue<D, T>> {
+        self.attrs.with_context::<T>();
+
         let mut queue_handle = null_mut() as WDFQUEUE;
         unsafe {
             call_unsafe_wdf_function_binding!(
                 WdfIoQueueCreate,
-                device,
+                device.handle(),
                 &mut self.config,
-                WDF_NO_OBJECT_ATTRIBUTES,
+                self.attrs.as_mut_ptr(),
                 &mut queue_handle,
             )
         }.check_status(ErrorCode::QueueCreationFailed).map(|_| {
-            Queue::new(queue_handle)
+            // Created just now with a `T` context, on a device with a `D` context
+            unsafe { Queue::new(queue_handle) }
         })
     }
 }
 
-pub struct Queue {
+/// A queue owned by a device with a `D` context, optionally carrying its own `T` context.
+pub struct Queue<D: Context, T: Context = NoContext> {
     pub queue: WDFQUEUE,
+    // Phantom fields tie the queue to the context types of itself and its device
+    device: PhantomData<D>,
+    context: PhantomData<T>,
 }
 
-impl Queue {
-    pub fn new(queue: WDFQUEUE) -> Self {
+impl<D: Context, T: Context> Queue<D, T> {
+    /// Wraps a queue handle received from the framework.
+    ///
+    /// # Safety
+    /// `queue` must be a live queue whose device carries a `D` context and which itself carries a
+    /// `T` context, unless `T` is [`NoContext`], which has no accessors. Nothing checks the types at runtime.
+    pub unsafe fn new(queue: WDFQUEUE) -> Self {
         Self {
-            queue
+            queue,
+            device: PhantomData,
+            context: PhantomData,
         }
     }
 
@@ -75,13 +114,42 @@ impl Queue {
         self.queue
     }
 
-    pub fn get_device<T: Context>(&self) -> Device<T> {
+    /// Takes the oldest request out of a manual queue, if any.
+    pub fn retrieve_next_request(&self) -> Option<Request<'static>> {
+        let mut request = null_mut();
+        let status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfIoQueueRetrieveNextRequest,
+                self.handle(),
+                &mut request,
+            )
+        };
+
+        if nt_success(status) {
+            unsafe { request.as_mut() }.map(Request::new)
+        } else {
+            None
+        }
+    }
+
+    pub fn device(&self) -> Device<D> {
         let device = unsafe {call_unsafe_wdf_function_binding!(
         WdfIoQueueGetDevice,
         self.handle()
     )};
 
-        Device::<T>::new(unsafe { device.as_mut().expect("Device can't be null") })
+        Device::<D>::new(unsafe { device.as_mut().expect("Device can't be null") })
+    }
+}
+
+impl<D: Context, T: HasContext> Queue<D, T> {
+    pub fn context(&self) -> &T {
+        // The queue carries a `T` context, as `new` requires
+        unsafe { &*T::get_context(self.queue as WDFOBJECT) }
+    }
+
+    pub fn context_mut(&mut self) -> &mut T {
+        unsafe { &mut *T::get_context(self.queue as WDFOBJECT) }
     }
 }
 
@@ -106,29 +174,61 @@ impl<'a> Request<'a> {
         };
     }
 
-    pub fn output_memory(&mut self) -> Result<WDFMEMORY> {
-        let mut output_memory = null_mut();
+    pub fn complete_with_information(&mut self, status: NTSTATUS, information: usize) {
         unsafe {
             call_unsafe_wdf_function_binding!(
-                WdfRequestRetrieveOutputMemory,
+                WdfRequestCompleteWithInformation,
                 self.handle,
-                &mut output_memory,
+                status,
+                information as ULONG_PTR,
+            )
+        };
+    }
+
+    pub fn handle(&mut self) -> WDFREQUEST {
+        self.handle as WDFREQUEST
+    }
+
+    /// The handle the request was sent on, if it came from user mode or another driver's open handle.
+    pub fn file_object(&mut self) -> Option<FileObject> {
+        let file_object = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestGetFileObject,
+                self.handle
+            )
+        };
+
+        (!file_object.is_null()).then(|| FileObject::new(file_object))
+    }
+
+    /// Access granted to the caller of a create request, after the I/O manager checked it
+    /// against the device's security descriptor.
+    pub fn granted_access(&mut self) -> ACCESS_MASK {
+        let mut parameters = init_object!(WDF_REQUEST_PARAMETERS);
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestGetParameters,
+                self.handle,
+                &mut parameters,
             )
-        }.check_status(ErrorCode::RequestOutputMemoryRetrievalFailed).map(|_| output_memory)
+        };
+
+        unsafe {
+            parameters.Parameters.Create.SecurityContext.as_ref()
+                .and_then(|security_context| security_context.AccessState.as_ref())
+                .map_or(0, |access_state| access_state.PreviouslyGrantedAccess)
+        }
     }
 
-    pub fn format_for_internal_ioctl(&mut self, io_target: WDFIOTARGET, io_control_code: u32, output_memory: WDFMEMORY) -> Result<()> {
+    pub fn output_memory(&mut self) -> Result<Memory> {
+        let mut output_memory = null_mut();
         unsafe {
             call_unsafe_wdf_function_binding!(
-                WdfIoTargetFormatRequestForInternalIoctl,
-                io_target,
+                WdfRequestRetrieveOutputMemory,
                 self.handle,
-                io_control_code,
-                null_mut(),
-                null_mut(),
-                output_memory,
-                null_mut(),)
-        }.check_status(ErrorCode::RequestFormatForInternalIoctlFailed).map(|_| ())
+                &mut output_memory,
+            )
+        }.check_status(ErrorCode::RequestOutputMemoryRetrievalFailed).map(|_| Memory::new(output_memory))
     }
 
     pub fn set_completion_callback(&mut self, callback: PFN_WDF_REQUEST_COMPLETION_ROUTINE, context: PVOID) {
@@ -142,7 +242,190 @@ impl<'a> Request<'a> {
         }
     }
 
-    pub fn send(&mut self, io_target: WDFIOTARGET, flags: u32) -> Result<()> {
+    /// Copies `value` to the start of the output buffer, returning the number of bytes written.
+    #[track_caller]
+    pub fn write_output<T: Copy>(&mut self, value: &T) -> Result<usize> {
+        let output = self.output_memory()?;
+        let required = core::mem::size_of::<T>();
+        if output.size() < required {
+            return Err(Error::buffer_too_small(ErrorCode::IoctlOutputTooSmall, required, output.size()));
+        }
+
+        output.write(0, value)?;
+        Ok(required)
+    }
+
+    /// The raw output buffer, which must be at least `minimum` bytes long.
+    pub fn output_buffer(&mut self, minimum: usize) -> Result<&mut [u8]> {
+        let mut buffer = null_mut();
+        let mut length = 0usize;
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestRetrieveOutputBuffer,
+                self.handle,
+                minimum,
+                &mut buffer,
+                &mut length,
+            )
+        }.check_status(ErrorCode::RequestOutputBufferRetrievalFailed)?;
+
+        Ok(unsafe { core::slice::from_raw_parts_mut(buffer.cast::<u8>(), length) })
+    }
+
+    /// The raw input buffer, which must be at least `minimum` bytes long.
+    pub fn input_buffer(&mut self, minimum: usize) -> Result<&[u8]> {
+        let mut buffer = null_mut();
+        let mut length = 0usize;
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestRetrieveInputBuffer,
+                self.handle,
+                minimum,
+                &mut buffer,
+                &mut length,
+            )
+        }.check_status(ErrorCode::RequestInputBufferRetrievalFailed)?;
+
+        Ok(unsafe { core::slice::from_raw_parts(buffer.cast::<u8>(), length) })
+    }
+
+    /// The control code of a device control request, `None` for other requests.
+    pub fn io_control_code(&mut self) -> Option<u32> {
+        let mut parameters = init_object!(WDF_REQUEST_PARAMETERS);
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestGetParameters,
+                self.handle,
+                &mut parameters,
+            )
+        };
+
+        (parameters.Type == WdfRequestTypeDeviceControl)
+            .then(|| unsafe { parameters.Parameters.DeviceIoControl.IoControlCode })
+    }
+
+    /// Hands a request seen in the caller's context on to the device's queues.
+    pub fn enqueue(&mut self, device: WDFDEVICE) -> Result<()> {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDeviceEnqueueRequest,
+                device,
+                self.handle,
+            )
+        }.check_status(ErrorCode::RequestEnqueueFailed)
+    }
+
+    /// The output buffer of a direct I/O request, mapped into system space, and its length. The
+    /// I/O manager keeps the pages locked and mapped until the request is completed.
+    pub fn output_direct_buffer(&mut self) -> Result<(NonNull<u8>, usize)> {
+        let mut mdl: PMDL = null_mut();
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestRetrieveOutputWdmMdl,
+                self.handle,
+                &mut mdl,
+            )
+        }.check_status(ErrorCode::RequestOutputMdlRetrievalFailed)?;
+
+        let mdl = unsafe { &mut *mdl };
+        // What MmGetSystemAddressForMdlSafe expands to
+        let address = if mdl.MdlFlags as u32 & (MDL_MAPPED_TO_SYSTEM_VA | MDL_SOURCE_IS_NONPAGED_POOL) != 0 {
+            mdl.MappedSystemVa
+        } else {
+            unsafe {
+                MmMapLockedPagesSpecifyCache(
+                    mdl,
+                    KernelMode as KPROCESSOR_MODE,
+                    MmCached,
+                    null_mut(),
+                    FALSE as u32,
+                    NormalPagePriority as u32 | MdlMappingNoExecute,
+                )
+            }
+        };
+
+        let address = NonNull::new(address.cast::<u8>())
+            .ok_or_else(|| Error::from_nt_status(STATUS_INSUFFICIENT_RESOURCES, ErrorCode::RequestOutputMdlMappingFailed))?;
+        Ok((address, mdl.ByteCount as usize))
+    }
+
+    /// Has `callback` complete the request if it's cancelled while the driver holds it. Fails with
+    /// `STATUS_CANCELLED` if it was cancelled already, leaving the driver to complete it.
+    pub fn mark_cancelable(&mut self, callback: PFN_WDF_REQUEST_CANCEL) -> Result<()> {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestMarkCancelableEx,
+                self.handle,
+                callback,
+            )
+        }.check_status(ErrorCode::RequestMarkCancelableFailed)
+    }
+
+    /// Takes back a request marked cancelable. Returns `false` if it's being cancelled, in which
+    /// case the cancel callback completes it.
+    pub fn unmark_cancelable(&mut self) -> bool {
+        let status = unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestUnmarkCancelable,
+                self.handle,
+            )
+        };
+        status != STATUS_CANCELLED
+    }
+
+    pub fn forward_to_queue(&mut self, queue: WDFQUEUE) -> Result<()> {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestForwardToIoQueue,
+                self.handle,
+                queue,
+            )
+        }.check_status(ErrorCode::RequestForwardFailed)
+    }
+
+    pub fn input_memory(&mut self) -> Result<Memory> {
+        let mut input_memory = null_mut();
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRequestRetrieveInputMemory,
+                self.handle,
+                &mut input_memory,
+            )
+        }.check_status(ErrorCode::RequestInputMemoryRetrievalFailed).map(|_| Memory::new(input_memory))
+    }
+
+    /// Copies a `T` from the start of the input buffer.
+    #[track_caller]
+    pub fn read_input<T: AnyBitPattern>(&mut self) -> Result<T> {
+        let input = self.input_memory()?;
+        let required = core::mem::size_of::<T>();
+        if input.size() < required {
+            return Err(Error::buffer_too_small(ErrorCode::IoctlInputInvalid, required, input.size()));
+        }
+
+        input.read(0)
+    }
+
+    /// Sends the request and calls `callback` with `context` once the lower driver completes it.
+    /// The callback owns the request from then on and must complete it.
+    /// `context` must outlive the request, which holds for contexts of the sending device.
+    pub fn send_with_completion<C>(&mut self, io_target: &IoTarget, context: &mut C, callback: CompletionCallback<C>) -> Result<()> {
+        let completion = Box::into_raw(Box::new(Completion {
+            callback,
+            context: context as *mut C,
+        }));
+
+        self.set_completion_callback(Some(completion_trampoline::<C>), completion.cast());
+
+        let res = self.send(io_target, 0);
+        if res.is_err() {
+            // The completion routine only runs for requests that were actually sent.
+            drop(unsafe { Box::from_raw(completion) });
+        }
+        res
+    }
+
+    pub fn send(&mut self, io_target: &IoTarget, flags: u32) -> Result<()> {
         let mut options = init_object!(WDF_REQUEST_SEND_OPTIONS);
         options.Flags = flags;
 
@@ -150,7 +433,7 @@ impl<'a> Request<'a> {
             call_unsafe_wdf_function_binding!(
                 WdfRequestSend,
                 self.handle,
-                io_target,
+                io_target.handle(),
                 &mut options as *mut _ as *mut _,
             )
         };
@@ -180,20 +463,21 @@ impl<'a> KeyboardConnectRequest<'a> {
         }
     }
 
-    pub fn connect_data(&mut self) -> Result<ConnectData> {
-        let mut connect_data = ConnectData::default();
+    /// The connect data in the request's buffer. The class driver reads it back after completion,
+    /// so changes made through this reference decide who the port driver calls.
+    pub fn connect_data(&mut self) -> Result<&mut ConnectData> {
+        let mut buffer = null_mut();
         let mut length = 0usize;
         unsafe {
             call_unsafe_wdf_function_binding!(
                         WdfRequestRetrieveInputBuffer,
                         self.handle,
                         core::mem::size_of::<ConnectData>(),
-                        core::ptr::addr_of_mut!(connect_data).cast(),
+                        &mut buffer,
                         &mut length,
                         )
-        }.check_status(ErrorCode::KeyboardConnectRequestRetrievalFailed).map(|_| {
-            let x = connect_data;
-            x
-        })
+        }.check_status(ErrorCode::KeyboardConnectRequestRetrievalFailed)?;
+
+        Ok(unsafe { &mut *buffer.cast::<ConnectData>() })
     }
 }
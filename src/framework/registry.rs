@@ -0,0 +1,100 @@
+use core::ptr::null_mut;
+use nt_string::unicode_string::NtUnicodeStr;
+use wdk_sys::{ACCESS_MASK, KEY_QUERY_VALUE, REG_BINARY, REG_SZ, ULONG, UNICODE_STRING, WDF_NO_OBJECT_ATTRIBUTES, WDFDRIVER, WDFKEY};
+use wdk_sys::macros::call_unsafe_wdf_function_binding;
+use crate::framework::{Error, ErrorCode, NtStatusError, Result};
+
+#[derive(Debug)]
+pub struct RegistryKey {
+    handle: WDFKEY,
+}
+
+impl RegistryKey {
+    /// Opens the driver's `Parameters` key under its service key.
+    pub fn open_driver_parameters(driver: WDFDRIVER, access: ACCESS_MASK) -> Result<Self> {
+        let mut handle = null_mut() as WDFKEY;
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfDriverOpenParametersRegistryKey,
+                driver,
+                access,
+                WDF_NO_OBJECT_ATTRIBUTES,
+                &mut handle,
+            )
+        }.check_status(ErrorCode::RegistryOpenFailed).map(|_| Self { handle })
+    }
+
+    pub fn open_driver_parameters_for_read(driver: WDFDRIVER) -> Result<Self> {
+        Self::open_driver_parameters(driver, KEY_QUERY_VALUE as ACCESS_MASK)
+    }
+
+    pub fn query_u32(&self, name: NtUnicodeStr) -> Result<u32> {
+        let mut value: ULONG = 0;
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRegistryQueryULong,
+                self.handle,
+                name.as_ptr() as *const UNICODE_STRING,
+                &mut value,
+            )
+        }.check_status(ErrorCode::RegistryQueryFailed).map(|_| value)
+    }
+
+    /// Reads a `REG_SZ` value into `buffer`, returning its length in UTF-16 code units without the terminating NUL.
+    pub fn query_string(&self, name: NtUnicodeStr, buffer: &mut [u16]) -> Result<usize> {
+        let mut length: ULONG = 0;
+        let mut value_type: ULONG = 0;
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRegistryQueryValue,
+                self.handle,
+                name.as_ptr() as *const UNICODE_STRING,
+                core::mem::size_of_val(buffer) as ULONG,
+                buffer.as_mut_ptr().cast(),
+                &mut length,
+                &mut value_type,
+            )
+        }.check_status(ErrorCode::RegistryQueryFailed)?;
+
+        if value_type != REG_SZ {
+            return Err(Error::invalid_buffer(ErrorCode::RegistryQueryFailed));
+        }
+
+        let length = length as usize / core::mem::size_of::<u16>();
+        Ok(buffer[..length].iter().position(|c| *c == 0).unwrap_or(length))
+    }
+
+    /// Reads a `REG_BINARY` value into `buffer`, returning its length in bytes.
+    pub fn query_binary(&self, name: NtUnicodeStr, buffer: &mut [u8]) -> Result<usize> {
+        let mut length: ULONG = 0;
+        let mut value_type: ULONG = 0;
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRegistryQueryValue,
+                self.handle,
+                name.as_ptr() as *const UNICODE_STRING,
+                buffer.len() as ULONG,
+                buffer.as_mut_ptr().cast(),
+                &mut length,
+                &mut value_type,
+            )
+        }.check_status(ErrorCode::RegistryQueryFailed)?;
+
+        if value_type != REG_BINARY {
+            return Err(Error::invalid_buffer(ErrorCode::RegistryQueryFailed));
+        }
+
+        Ok(length as usize)
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfRegistryClose,
+                self.handle,
+            )
+        };
+    }
+}
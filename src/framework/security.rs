@@ -0,0 +1,30 @@
+//! Security descriptors for the device objects the driver exposes to user mode.
+
+use alloc::boxed::Box;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use nt_string::nt_unicode_str;
+use nt_string::unicode_string::{NtUnicodeStr, NtUnicodeString};
+
+/// Full access for SYSTEM and Administrators, nobody else may open the device.
+/// Same as `SDDL_DEVOBJ_SYS_ALL_ADM_ALL`.
+pub const SDDL_SYSTEM_AND_ADMINISTRATORS: NtUnicodeStr<'static> = nt_unicode_str!("D:P(A;;GA;;;SY)(A;;GA;;;BA)");
+
+static DEFAULT_SDDL: AtomicPtr<NtUnicodeString> = AtomicPtr::new(null_mut());
+
+/// SDDL applied to devices that weren't given one explicitly.
+pub fn default_sddl() -> NtUnicodeStr<'static> {
+    match unsafe { DEFAULT_SDDL.load(Ordering::Acquire).as_ref() } {
+        Some(sddl) => **sddl,
+        None => SDDL_SYSTEM_AND_ADMINISTRATORS,
+    }
+}
+
+/// Replaces the default SDDL. Only call it from `DriverEntry`, before any device exists;
+/// the string then lives as long as the driver.
+pub fn set_default_sddl(sddl: NtUnicodeString) {
+    let previous = DEFAULT_SDDL.swap(Box::into_raw(Box::new(sddl)), Ordering::AcqRel);
+    if !previous.is_null() {
+        drop(unsafe { Box::from_raw(previous) });
+    }
+}
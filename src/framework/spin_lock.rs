@@ -0,0 +1,64 @@
+use core::cell::UnsafeCell;
+use core::fmt::{Debug, Formatter};
+use core::ops::{Deref, DerefMut};
+use wdk_sys::{KIRQL, KSPIN_LOCK};
+use wdk_sys::ntddk::{KeAcquireSpinLockRaiseToDpc, KeReleaseSpinLock};
+
+/// Data guarded by an executive spin lock. Usable from statics and zero-initialized contexts,
+/// since an all-zero `KSPIN_LOCK` is a released lock.
+/// Acquiring raises to `DISPATCH_LEVEL` until the guard is dropped.
+pub struct SpinLock<T> {
+    lock: UnsafeCell<KSPIN_LOCK>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            lock: UnsafeCell::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let old_irql = unsafe { KeAcquireSpinLockRaiseToDpc(self.lock.get()) };
+        SpinLockGuard {
+            lock: self,
+            old_irql,
+        }
+    }
+}
+
+impl<T> Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SpinLock").finish_non_exhaustive()
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    old_irql: KIRQL,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { KeReleaseSpinLock(self.lock.lock.get(), self.old_irql) };
+    }
+}
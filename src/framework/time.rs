@@ -0,0 +1,49 @@
+use core::ptr::null_mut;
+use wdk_sys::LARGE_INTEGER;
+use wdk_sys::ntddk::{KeQueryPerformanceCounter, KeQuerySystemTimePrecise};
+
+/// Current system time in 100ns units since January 1, 1601 (UTC).
+pub fn system_time() -> u64 {
+    let mut time = LARGE_INTEGER::default();
+    unsafe {
+        KeQuerySystemTimePrecise(&mut time);
+        time.QuadPart as u64
+    }
+}
+
+/// Current value of the high-resolution performance counter, as returned by `QueryPerformanceCounter`.
+pub fn performance_counter() -> u64 {
+    unsafe { KeQueryPerformanceCounter(null_mut()).QuadPart as u64 }
+}
+
+/// Ticks per second of [`performance_counter`], fixed at boot.
+pub fn performance_frequency() -> u64 {
+    let mut frequency = LARGE_INTEGER::default();
+    unsafe {
+        KeQueryPerformanceCounter(&mut frequency);
+        frequency.QuadPart as u64
+    }
+}
+
+/// Source of the current time, so deadline logic can run against a clock tests control.
+pub trait Clock {
+    /// Current time in ticks.
+    fn now(&self) -> u64;
+
+    /// Ticks per second.
+    fn frequency(&self) -> u64;
+}
+
+/// The high-resolution performance counter, the clock captured strokes are timestamped with.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PerformanceCounterClock;
+
+impl Clock for PerformanceCounterClock {
+    fn now(&self) -> u64 {
+        performance_counter()
+    }
+
+    fn frequency(&self) -> u64 {
+        performance_frequency()
+    }
+}
@@ -0,0 +1,50 @@
+use core::ptr::null_mut;
+use wdk_sys::{PFN_WDF_TIMER, WDF_TIMER_CONFIG, WDFOBJECT, WDFTIMER};
+use wdk_sys::macros::call_unsafe_wdf_function_binding;
+use crate::framework::{Context, Device, ErrorCode, NtStatusError, ObjectAttributes, Result};
+use crate::init_object;
+
+/// A periodic framework timer, deleted along with the device it was created on.
+/// The callback runs at `DISPATCH_LEVEL`.
+#[derive(Debug)]
+pub struct Timer {
+    handle: WDFTIMER,
+}
+
+impl Timer {
+    pub fn create_periodic<D: Context>(device: &mut Device<D>, period_ms: u32, callback: PFN_WDF_TIMER) -> Result<Self> {
+        let mut config = init_object!(WDF_TIMER_CONFIG);
+        config.EvtTimerFunc = callback;
+        config.Period = period_ms;
+
+        let mut attributes = ObjectAttributes::new();
+        attributes.with_parent(device.handle() as WDFOBJECT);
+
+        let mut handle: WDFTIMER = null_mut();
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfTimerCreate,
+                &mut config,
+                attributes.as_mut_ptr(),
+                &mut handle,
+            )
+        }.check_status(ErrorCode::TimerCreateFailed).map(|_| Self { handle })
+    }
+
+    pub fn handle(&self) -> WDFTIMER {
+        self.handle
+    }
+
+    /// Fires the first time after `due_ms`, then every period.
+    pub fn start(&self, due_ms: u32) {
+        // Negative due times are relative, in 100ns units
+        let due_time = -(i64::from(due_ms) * 10_000);
+        unsafe {
+            call_unsafe_wdf_function_binding!(
+                WdfTimerStart,
+                self.handle,
+                due_time,
+            )
+        };
+    }
+}
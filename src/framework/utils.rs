@@ -1,4 +1,7 @@
+use bytemuck::AnyBitPattern;
 use wdk_sys::ntddk::DbgBreakPointWithStatus;
+use crate::log_debug;
+
 #[macro_export]
 macro_rules! kernel_callback {
     (fn $fn_name:ident( $($params:tt)* ) -> $ret_type:ty {
@@ -28,6 +31,18 @@ macro_rules! driver_entry { (fn ($driver:ident, $registry_path:ident) { $($body:
 }
 
 
+/// Marks plain `repr(C)` records, made of integers only, as valid for any bit pattern, so they
+/// may be read from whatever bytes a client or another driver hands over.
+#[macro_export]
+macro_rules! any_bit_pattern {
+    ($($type:ty),* $(,)?) => {
+        $(
+            unsafe impl bytemuck::Zeroable for $type {}
+            unsafe impl bytemuck::AnyBitPattern for $type {}
+        )*
+    };
+}
+
 #[macro_export]
 macro_rules! init_object {
     ($type:ty) => {{
@@ -37,52 +52,32 @@ macro_rules! init_object {
     }};
 }
 
-pub const fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
-    (device_type << 16) | (access << 14) | (function << 2) | method
-}
-
-pub const DEBUG: bool = true;
+/// Copies `value` into `buffer` at `offset`, which need not be aligned. Returns `false` if it doesn't fit.
+pub fn write_to_buffer<T: Copy>(buffer: &mut [u8], offset: usize, value: &T) -> bool {
+    if offset.checked_add(core::mem::size_of::<T>()).map_or(true, |end| end > buffer.len()) {
+        return false;
+    }
 
-#[macro_export]
-macro_rules! debug_print {
-    ($($arg:tt)*) => {
-        if $crate::framework::utils::DEBUG {
-            wdk::println!($($arg)*);
-        }
-    };
+    unsafe { core::ptr::write_unaligned(buffer.as_mut_ptr().add(offset).cast::<T>(), *value) };
+    true
 }
 
-pub(crate) use debug_print;
+/// Reads a `T` from `buffer` at `offset`, which need not be aligned.
+pub fn read_from_buffer<T: AnyBitPattern>(buffer: &[u8], offset: usize) -> Option<T> {
+    if offset.checked_add(core::mem::size_of::<T>()).map_or(true, |end| end > buffer.len()) {
+        return None;
+    }
 
+    Some(unsafe { core::ptr::read_unaligned(buffer.as_ptr().add(offset).cast::<T>()) })
+}
 
-#[macro_export]
-macro_rules! dbg {
-    // NOTE: We cannot use `concat!` to make a static string as a format argument
-    // of `eprintln!` because `file!` could contain a `{` or
-    // `$val` expression could be a block (`{ .. }`), in which case the `eprintln!`
-    // will be malformed.
-    () => {
-        $crate::framework::utils::debug_print!("[WAWAWA] [{}:{}]", core::file!(), core::line!())
-    };
-    ($val:expr $(,)?) => {
-        // Use of `match` here is intentional because it affects the lifetimes
-        // of temporaries - https://stackoverflow.com/a/48732525/1063961
-        match $val {
-            tmp => {
-                $crate::framework::utils::debug_print!("[WAWAWA] [{}:{}] {} = {:#?}",
-                    core::file!(), core::line!(), core::stringify!($val), &tmp);
-                tmp
-            }
-        }
-    };
-    ($($val:expr),+ $(,)?) => {
-        ($($crate::dbg!($val)),+,)
-    };
+pub const fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
+    (device_type << 16) | (access << 14) | (function << 2) | method
 }
 
 fn breakpoint() {
-    if DEBUG {
-        debug_print!("Breakpoint");
+    if cfg!(debug_assertions) {
+        log_debug!("Breakpoint");
         unsafe { DbgBreakPointWithStatus(0) };
     }
 }
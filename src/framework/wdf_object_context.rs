@@ -66,6 +66,8 @@ macro_rules! wdf_declare_context_type_with_name {
                     $casting_function(handle)
                 }
             }
+
+            impl crate::framework::HasContext for $context_type {}
         }
     };
 }
@@ -86,3 +88,20 @@ pub(crate) trait Context {
 
     unsafe fn get_context(handle: WDFOBJECT) -> *mut Self;
 }
+
+/// A context type declared with [`wdf_declare_context_type!`], which objects may actually carry.
+pub(crate) trait HasContext: Context {}
+
+/// Marker for objects created without a typed context.
+#[derive(Debug)]
+pub struct NoContext;
+
+impl Context for NoContext {
+    fn get_context_type_info() -> PCWDF_OBJECT_CONTEXT_TYPE_INFO {
+        core::ptr::null()
+    }
+
+    unsafe fn get_context(_handle: WDFOBJECT) -> *mut Self {
+        core::ptr::null_mut()
+    }
+}
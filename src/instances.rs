@@ -0,0 +1,83 @@
+//! Every filter device currently attached, addressable by the id clients see.
+
+use wdk_sys::WDFDEVICE;
+use crate::DeviceContext;
+use crate::framework::Device;
+use crate::framework::spin_lock::SpinLock;
+use crate::protocol::{DeviceEntry, HARDWARE_ID_LENGTH};
+
+pub const MAX_INSTANCES: usize = 32;
+
+#[derive(Copy, Clone)]
+pub struct Instance {
+    pub id: u32,
+    pub device: WDFDEVICE,
+    pub hardware_id: [u16; HARDWARE_ID_LENGTH],
+}
+
+// The handle is only used to reach the device's context while the table is locked,
+// and entries are removed in the device's cleanup callback before it goes away.
+unsafe impl Send for Instance {}
+
+impl Instance {
+    pub fn entry(&self) -> DeviceEntry {
+        DeviceEntry {
+            device_id: self.id,
+            hardware_id: self.hardware_id,
+        }
+    }
+}
+
+pub struct Instances {
+    entries: [Option<Instance>; MAX_INSTANCES],
+}
+
+impl Instances {
+    const fn new() -> Self {
+        Self {
+            entries: [None; MAX_INSTANCES],
+        }
+    }
+
+    pub fn add(&mut self, instance: Instance) -> bool {
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some(instance);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Instance> {
+        self.entries.iter_mut()
+            .find(|entry| entry.is_some_and(|instance| instance.id == id))
+            .and_then(Option::take)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Instance> {
+        self.iter().find(|instance| instance.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Instance> {
+        self.entries.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Runs `f` on the device with the given id. The table stays locked, and the IRQL at
+    /// `DISPATCH_LEVEL`, until `f` returns, so the device can't be removed in the meantime.
+    pub fn with_device<R>(&self, id: u32, f: impl FnOnce(&mut Device<DeviceContext>) -> R) -> Option<R> {
+        let instance = self.get(id)?;
+        let mut device = Device::<DeviceContext>::new(unsafe { instance.device.as_mut() }?);
+        Some(f(&mut device))
+    }
+}
+
+pub static ATTACHED: SpinLock<Instances> = SpinLock::new(Instances::new());
@@ -0,0 +1,226 @@
+//! Rules dropping strokes before they reach the system, e.g. the Windows keys or Alt+Tab on a kiosk.
+//!
+//! A rule matches a make code and prefix, optionally only while some modifiers are held. Modifiers
+//! are tracked per keyboard across batches. Whether a key is dropped is decided when it goes down:
+//! its repeats and its break follow, whatever the modifiers are by then, so the system never sees
+//! half a press.
+
+use crate::keyboard::key_set::KeySet;
+use crate::keyboard::scan_code::ScanCode;
+use crate::keyboard::sequence::RawStroke;
+
+/// Either Control key. Modifier masks don't distinguish left and right.
+pub const MODIFIER_CONTROL: u16 = 0x01;
+pub const MODIFIER_SHIFT: u16 = 0x02;
+pub const MODIFIER_ALT: u16 = 0x04;
+pub const MODIFIER_WIN: u16 = 0x08;
+
+/// The physical keys behind each modifier, in the order of the `MODIFIER_*` bits.
+const MODIFIER_KEYS: [(ScanCode, ScanCode); 4] = [
+    (ScanCode::new(0x1D), ScanCode::e0(0x1D)),
+    (ScanCode::new(0x2A), ScanCode::new(0x36)),
+    (ScanCode::new(0x38), ScanCode::e0(0x38)),
+    (ScanCode::e0(0x5B), ScanCode::e0(0x5C)),
+];
+
+/// A key to drop, as stored in the `BlockRules` registry value and exchanged through `ControlSetBlockRules`.
+/// `Debug` is in [`crate::privacy`].
+#[repr(C)]
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+pub struct BlockRule {
+    pub make_code: u16,
+    /// `KEY_E0` or `KEY_E1` if the key has that prefix, as in `KEYBOARD_INPUT_DATA::Flags`.
+    pub flags: u16,
+    /// `MODIFIER_*` bits that must all be held; others may be held too.
+    pub modifiers: u16,
+    /// Index plus one of the driver's target the rule is restricted to, 0 for every keyboard.
+    pub target: u16,
+}
+
+impl BlockRule {
+    pub const fn key(scan_code: ScanCode) -> Self {
+        Self { make_code: scan_code.code as u16, flags: scan_code.flags(), modifiers: 0, target: 0 }
+    }
+
+    pub const fn with_modifiers(scan_code: ScanCode, modifiers: u16) -> Self {
+        Self { make_code: scan_code.code as u16, flags: scan_code.flags(), modifiers, target: 0 }
+    }
+
+    pub const fn for_target(self, target: u16) -> Self {
+        Self { target, ..self }
+    }
+
+    /// `targets` has bit `i` set when the stroke falls under target `i`.
+    pub fn matches(&self, scan_code: ScanCode, modifiers: u16, targets: u32) -> bool {
+        let targeted = self.target == 0 || (self.target <= 32 && targets & 1 << (self.target - 1) != 0);
+        targeted
+            && ScanCode::from_input(self.make_code, self.flags) == Some(scan_code)
+            && modifiers & self.modifiers == self.modifiers
+    }
+}
+
+/// What a keyboard has held, carried from one batch of strokes to the next.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BlockState {
+    held: KeySet,
+    blocked: KeySet,
+}
+
+impl BlockState {
+    pub const fn new() -> Self {
+        Self { held: KeySet::new(), blocked: KeySet::new() }
+    }
+
+    /// `MODIFIER_*` bits of the modifiers held.
+    pub fn modifiers(&self) -> u16 {
+        MODIFIER_KEYS.iter().enumerate()
+            .filter(|(_, (left, right))| self.held.contains(*left) || self.held.contains(*right))
+            .fold(0, |modifiers, (i, _)| modifiers | 1 << i)
+    }
+
+    /// Whether `stroke` must be dropped. Must see every stroke of the keyboard, dropped or not, in order.
+    /// `targets` are the targets the stroke falls under, as for [`BlockRule::matches`].
+    pub fn should_block(&mut self, rules: &[BlockRule], targets: u32, stroke: RawStroke) -> bool {
+        let Some(scan_code) = stroke.scan_code() else {
+            return false;
+        };
+
+        if stroke.is_break() {
+            self.held.remove(scan_code);
+            return self.blocked.remove(scan_code);
+        }
+
+        // Repeats follow the first make
+        if !self.held.insert(scan_code) {
+            return self.blocked.contains(scan_code);
+        }
+
+        let modifiers = self.modifiers();
+        let blocked = rules.iter().any(|rule| rule.matches(scan_code, modifiers, targets));
+        if blocked {
+            self.blocked.insert(scan_code);
+        }
+        blocked
+    }
+
+    /// Forgets what was held, e.g. when the keyboard reconnects.
+    pub const fn reset(&mut self) {
+        self.held.clear();
+        self.blocked.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEFT_ALT: ScanCode = ScanCode::new(0x38);
+    const RIGHT_ALT: ScanCode = ScanCode::e0(0x38);
+    const LEFT_CONTROL: ScanCode = ScanCode::new(0x1D);
+    const LEFT_SHIFT: ScanCode = ScanCode::new(0x2A);
+    const LEFT_WIN: ScanCode = ScanCode::e0(0x5B);
+    const TAB: ScanCode = ScanCode::new(0x0F);
+    const ESCAPE: ScanCode = ScanCode::new(0x01);
+    const RULES: &[BlockRule] = &[
+        BlockRule::key(LEFT_WIN),
+        BlockRule::with_modifiers(TAB, MODIFIER_ALT),
+        BlockRule::key(ESCAPE).for_target(2),
+    ];
+
+    /// Feeds `strokes` in order, returning which were blocked.
+    fn blocked(strokes: &[RawStroke]) -> Vec<bool> {
+        blocked_on(strokes, 0)
+    }
+
+    fn blocked_on(strokes: &[RawStroke], targets: u32) -> Vec<bool> {
+        let mut state = BlockState::new();
+        strokes.iter().map(|&stroke| state.should_block(RULES, targets, stroke)).collect()
+    }
+
+    fn make(scan_code: ScanCode) -> RawStroke {
+        RawStroke::make(scan_code)
+    }
+
+    fn release(scan_code: ScanCode) -> RawStroke {
+        RawStroke::release(scan_code)
+    }
+
+    #[test]
+    fn blocked_keys_lose_make_and_break() {
+        assert_eq!(blocked(&[make(LEFT_WIN), release(LEFT_WIN)]), [true, true]);
+        // 5B without the E0 flag isn't the Windows key
+        assert_eq!(blocked(&[make(ScanCode::new(0x5B))]), [false]);
+    }
+
+    #[test]
+    fn modifier_rules() {
+        assert_eq!(blocked(&[make(TAB), release(TAB)]), [false, false]);
+        assert_eq!(
+            blocked(&[make(LEFT_ALT), make(TAB), make(TAB), release(TAB), release(LEFT_ALT)]),
+            [false, true, true, true, false],
+        );
+        // The right Alt counts as Alt
+        assert_eq!(blocked(&[make(RIGHT_ALT), make(TAB)]), [false, true]);
+    }
+
+    #[test]
+    fn whether_a_key_is_blocked_is_decided_when_it_goes_down() {
+        // Releasing Alt before Tab still drops Tab's repeats and break
+        assert_eq!(
+            blocked(&[make(LEFT_ALT), make(TAB), release(LEFT_ALT), make(TAB), release(TAB)]),
+            [false, true, false, true, true],
+        );
+        // Pressing Alt while Tab is held doesn't drop the repeats and break of a Tab the system saw go down
+        assert_eq!(
+            blocked(&[make(TAB), make(LEFT_ALT), make(TAB), release(TAB), release(LEFT_ALT)]),
+            [false; 5],
+        );
+    }
+
+    #[test]
+    fn extra_modifiers_still_match() {
+        let rules = [BlockRule::with_modifiers(TAB, MODIFIER_ALT)];
+        let mut state = BlockState::new();
+        for modifier in [LEFT_CONTROL, LEFT_SHIFT, LEFT_ALT] {
+            assert!(!state.should_block(&rules, 0, make(modifier)));
+        }
+        assert_eq!(state.modifiers(), MODIFIER_CONTROL | MODIFIER_SHIFT | MODIFIER_ALT);
+        assert!(state.should_block(&rules, 0, make(TAB)));
+
+        // All the rule's modifiers must be held
+        let rules = [BlockRule::with_modifiers(TAB, MODIFIER_CONTROL | MODIFIER_ALT)];
+        let mut state = BlockState::new();
+        state.should_block(&rules, 0, make(LEFT_ALT));
+        assert!(!state.should_block(&rules, 0, make(TAB)));
+    }
+
+    #[test]
+    fn targeted_rules_only_apply_to_their_target() {
+        // The rule is restricted to the second target
+        assert_eq!(blocked(&[make(ESCAPE), release(ESCAPE)]), [false, false]);
+        assert_eq!(blocked_on(&[make(ESCAPE), release(ESCAPE)], 0b01), [false, false]);
+        assert_eq!(blocked_on(&[make(ESCAPE), release(ESCAPE)], 0b10), [true, true]);
+        // Untargeted rules apply whatever the targets
+        assert_eq!(blocked_on(&[make(LEFT_WIN)], 0b11), [true]);
+
+        let beyond = BlockRule::key(ESCAPE).for_target(33);
+        assert!(!beyond.matches(ESCAPE, 0, u32::MAX));
+    }
+
+    #[test]
+    fn rules_with_invalid_make_codes_match_nothing() {
+        let rule = BlockRule { make_code: 0x100, flags: 0, modifiers: 0, target: 0 };
+        assert!(!rule.matches(ScanCode::new(0), 0, 0));
+    }
+
+    #[test]
+    fn reset_forgets_held_keys() {
+        let mut state = BlockState::new();
+        assert!(state.should_block(RULES, 0, make(LEFT_WIN)));
+        state.should_block(RULES, 0, make(LEFT_ALT));
+        state.reset();
+
+        assert_eq!(state.modifiers(), 0);
+        assert!(!state.should_block(RULES, 0, release(LEFT_WIN)));
+    }
+}
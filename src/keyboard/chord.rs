@@ -0,0 +1,159 @@
+//! Detects a chord: a set of keys all held down on one keyboard, whatever order they went down in.
+
+use crate::keyboard::key_set::KeySet;
+use crate::keyboard::scan_code::ScanCode;
+use crate::keyboard::sequence::RawStroke;
+
+pub const MAX_CHORD_KEYS: usize = 4;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Chord {
+    keys: [ScanCode; MAX_CHORD_KEYS],
+    len: usize,
+}
+
+impl Chord {
+    /// A chord that never fires.
+    pub const EMPTY: Self = Self { keys: [ScanCode::new(0); MAX_CHORD_KEYS], len: 0 };
+
+    /// Returns `None` for more than [`MAX_CHORD_KEYS`] keys.
+    pub const fn new(keys: &[ScanCode]) -> Option<Self> {
+        if keys.len() > MAX_CHORD_KEYS {
+            return None;
+        }
+
+        let mut chord = Self::EMPTY;
+        while chord.len < keys.len() {
+            chord.keys[chord.len] = keys[chord.len];
+            chord.len += 1;
+        }
+        Some(chord)
+    }
+
+    pub const fn keys(&self) -> &[ScanCode] {
+        self.keys.split_at(self.len).0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn contains(&self, scan_code: ScanCode) -> bool {
+        self.keys().contains(&scan_code)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ChordDetector {
+    held: KeySet,
+}
+
+impl ChordDetector {
+    pub const fn new() -> Self {
+        Self { held: KeySet::new() }
+    }
+
+    /// Whether `stroke` completes `chord`: it's the first make of one of its keys and the others are held.
+    /// Repeats don't fire again; the key has to be released first.
+    pub fn feed(&mut self, chord: &Chord, stroke: RawStroke) -> bool {
+        let Some(scan_code) = stroke.scan_code() else {
+            return false;
+        };
+
+        if stroke.is_break() {
+            self.held.remove(scan_code);
+            return false;
+        }
+
+        if !self.held.insert(scan_code) || chord.is_empty() || !chord.contains(scan_code) {
+            return false;
+        }
+
+        chord.keys().iter().all(|&key| self.held.contains(key))
+    }
+
+    pub const fn reset(&mut self) {
+        self.held.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTROL: ScanCode = ScanCode::new(0x1D);
+    const ALT: ScanCode = ScanCode::new(0x38);
+    const F12: ScanCode = ScanCode::new(0x58);
+    const RIGHT_CONTROL: ScanCode = ScanCode::e0(0x1D);
+
+    fn chord() -> Chord {
+        Chord::new(&[CONTROL, ALT, F12]).unwrap()
+    }
+
+    /// Feeds `strokes` in order, returning which fired.
+    fn fired(chord: &Chord, strokes: &[RawStroke]) -> Vec<bool> {
+        let mut detector = ChordDetector::new();
+        strokes.iter().map(|&stroke| detector.feed(chord, stroke)).collect()
+    }
+
+    fn make(scan_code: ScanCode) -> RawStroke {
+        RawStroke::make(scan_code)
+    }
+
+    fn release(scan_code: ScanCode) -> RawStroke {
+        RawStroke::release(scan_code)
+    }
+
+    #[test]
+    fn fires_on_the_key_completing_the_chord_in_any_order() {
+        assert_eq!(fired(&chord(), &[make(CONTROL), make(ALT), make(F12)]), [false, false, true]);
+        assert_eq!(fired(&chord(), &[make(F12), make(ALT), make(CONTROL)]), [false, false, true]);
+    }
+
+    #[test]
+    fn repeats_do_not_fire_again() {
+        assert_eq!(
+            fired(&chord(), &[make(CONTROL), make(ALT), make(F12), make(F12), make(ALT)]),
+            [false, false, true, false, false],
+        );
+        // Releasing and pressing a key again does
+        assert_eq!(
+            fired(&chord(), &[make(CONTROL), make(ALT), make(F12), release(F12), make(F12)]),
+            [false, false, true, false, true],
+        );
+    }
+
+    #[test]
+    fn every_key_must_be_held() {
+        assert_eq!(fired(&chord(), &[make(CONTROL), release(CONTROL), make(ALT), make(F12)]), [false; 4]);
+        // The other side's key isn't the one in the chord
+        assert_eq!(fired(&chord(), &[make(RIGHT_CONTROL), make(ALT), make(F12)]), [false; 3]);
+    }
+
+    #[test]
+    fn other_keys_held_along_do_not_matter() {
+        assert_eq!(
+            fired(&chord(), &[make(RIGHT_CONTROL), make(CONTROL), make(ALT), make(F12)]),
+            [false, false, false, true],
+        );
+        // Keys outside the chord don't complete it
+        assert_eq!(fired(&chord(), &[make(CONTROL), make(ALT), make(F12), make(ScanCode::new(0x1E))]), [false, false, true, false]);
+    }
+
+    #[test]
+    fn reset_forgets_held_keys() {
+        let mut detector = ChordDetector::new();
+        detector.feed(&chord(), make(CONTROL));
+        detector.feed(&chord(), make(ALT));
+        detector.reset();
+        assert!(!detector.feed(&chord(), make(F12)));
+    }
+
+    #[test]
+    fn empty_and_oversized_chords() {
+        assert_eq!(fired(&Chord::EMPTY, &[make(CONTROL), make(ALT), make(F12)]), [false; 3]);
+        assert!(Chord::new(&[]).unwrap().is_empty());
+        assert_eq!(Chord::new(&[CONTROL, ALT, F12, RIGHT_CONTROL]).unwrap().keys(), [CONTROL, ALT, F12, RIGHT_CONTROL]);
+        assert!(Chord::new(&[CONTROL, ALT, F12, RIGHT_CONTROL, ScanCode::new(0x01)]).is_none());
+    }
+}
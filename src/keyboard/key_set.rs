@@ -0,0 +1,76 @@
+//! A set of keys, one bit per make code and prefix.
+
+use crate::keyboard::scan_code::{Prefix, ScanCode};
+
+const WORDS_PER_PREFIX: usize = 256 / 64;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct KeySet {
+    bits: [u64; 3 * WORDS_PER_PREFIX],
+}
+
+impl KeySet {
+    pub const fn new() -> Self {
+        Self { bits: [0; 3 * WORDS_PER_PREFIX] }
+    }
+
+    const fn position(scan_code: ScanCode) -> (usize, u64) {
+        let plane = match scan_code.prefix {
+            Prefix::None => 0,
+            Prefix::E0 => 1,
+            Prefix::E1 => 2,
+        };
+        let index = plane * 256 + scan_code.code as usize;
+        (index / 64, 1 << (index % 64))
+    }
+
+    pub const fn contains(&self, scan_code: ScanCode) -> bool {
+        let (word, bit) = Self::position(scan_code);
+        self.bits[word] & bit != 0
+    }
+
+    /// Returns whether the key wasn't in the set yet.
+    pub const fn insert(&mut self, scan_code: ScanCode) -> bool {
+        let (word, bit) = Self::position(scan_code);
+        let inserted = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        inserted
+    }
+
+    /// Returns whether the key was in the set.
+    pub const fn remove(&mut self, scan_code: ScanCode) -> bool {
+        let (word, bit) = Self::position(scan_code);
+        let removed = self.bits[word] & bit != 0;
+        self.bits[word] &= !bit;
+        removed
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        let mut i = 0;
+        while i < self.bits.len() {
+            if self.bits[i] != 0 {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    pub const fn clear(&mut self) {
+        self.bits = [0; 3 * WORDS_PER_PREFIX];
+    }
+
+    /// The keys in the set, plain codes first, then E0 and E1 ones.
+    pub fn iter(&self) -> impl Iterator<Item = ScanCode> + '_ {
+        (0..self.bits.len() * 64)
+            .filter(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+            .map(|index| {
+                let code = (index % 256) as u8;
+                match index / 256 {
+                    0 => ScanCode::new(code),
+                    1 => ScanCode::e0(code),
+                    _ => ScanCode::e1(code),
+                }
+            })
+    }
+}
@@ -0,0 +1,179 @@
+//! The keys physically held on a keyboard, as a 512-bit map of make code × E0 prefix.
+//!
+//! Bit `code + 256 * e0` is set while the key is held. Strokes go through the same [`Decoder`]
+//! the filter uses, so fake shifts don't show up and Pause, the only E1 key, takes the slot of
+//! `E0 45`, which no keyboard sends.
+
+use crate::keyboard::scan_code::{Prefix, ScanCode};
+use crate::keyboard::sequence::{Decoder, KeyEvent, RawStroke};
+
+pub const KEY_STATE_WORDS: usize = 512 / 64;
+
+const PAUSE: ScanCode = ScanCode::e1(0x1D);
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct PressedKeys {
+    bits: [u64; KEY_STATE_WORDS],
+}
+
+impl PressedKeys {
+    pub const fn new() -> Self {
+        Self { bits: [0; KEY_STATE_WORDS] }
+    }
+
+    /// The bit of a key, `None` for E1 keys other than Pause.
+    pub const fn index(scan_code: ScanCode) -> Option<usize> {
+        match scan_code.prefix {
+            Prefix::None => Some(scan_code.code as usize),
+            Prefix::E0 => Some(256 + scan_code.code as usize),
+            Prefix::E1 if scan_code.const_eq(PAUSE) => Some(256 + 0x45),
+            Prefix::E1 => None,
+        }
+    }
+
+    pub const fn contains(&self, scan_code: ScanCode) -> bool {
+        match Self::index(scan_code) {
+            Some(index) => self.bits[index / 64] & 1 << (index % 64) != 0,
+            None => false,
+        }
+    }
+
+    pub const fn apply(&mut self, event: KeyEvent) {
+        let Some(index) = Self::index(event.scan_code) else {
+            return;
+        };
+
+        if event.pressed {
+            self.bits[index / 64] |= 1 << (index % 64);
+        } else {
+            self.bits[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    /// Adds the keys held in `other`.
+    pub fn union(&mut self, other: &Self) {
+        for (bits, other_bits) in self.bits.iter_mut().zip(other.bits) {
+            *bits |= other_bits;
+        }
+    }
+
+    pub const fn bits(&self) -> [u64; KEY_STATE_WORDS] {
+        self.bits
+    }
+}
+
+/// Follows a keyboard's strokes, which may split sequences across service callback batches.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct KeyState {
+    decoder: Decoder,
+    pressed: PressedKeys,
+}
+
+impl KeyState {
+    pub const fn new() -> Self {
+        Self { decoder: Decoder::new(), pressed: PressedKeys::new() }
+    }
+
+    pub fn feed(&mut self, stroke: RawStroke) {
+        for event in self.decoder.feed(stroke).as_slice() {
+            self.pressed.apply(*event);
+        }
+    }
+
+    pub const fn pressed(&self) -> &PressedKeys {
+        &self.pressed
+    }
+
+    pub const fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: ScanCode = ScanCode::new(0x1E);
+    const LEFT_CONTROL: ScanCode = ScanCode::new(0x1D);
+    const RIGHT_CONTROL: ScanCode = ScanCode::e0(0x1D);
+    const HOME: ScanCode = ScanCode::e0(0x47);
+
+    fn fed(strokes: &[RawStroke]) -> PressedKeys {
+        let mut state = KeyState::new();
+        for &stroke in strokes {
+            state.feed(stroke);
+        }
+        *state.pressed()
+    }
+
+    #[test]
+    fn plain_and_e0_keys_take_separate_bits() {
+        let pressed = fed(&[RawStroke::make(A), RawStroke::make(RIGHT_CONTROL)]);
+        assert!(pressed.contains(A) && pressed.contains(RIGHT_CONTROL));
+        assert!(!pressed.contains(LEFT_CONTROL));
+
+        let mut bits = [0; KEY_STATE_WORDS];
+        bits[0] = 1 << 0x1E;
+        bits[4] = 1 << 0x1D;
+        assert_eq!(pressed.bits(), bits);
+    }
+
+    #[test]
+    fn breaks_clear_keys_and_repeats_change_nothing() {
+        assert_eq!(fed(&[RawStroke::make(A), RawStroke::make(A)]), fed(&[RawStroke::make(A)]));
+        assert_eq!(fed(&[RawStroke::make(A), RawStroke::make(A), RawStroke::release(A)]), PressedKeys::new());
+        // A break without a make is harmless
+        assert_eq!(fed(&[RawStroke::release(A)]), PressedKeys::new());
+    }
+
+    #[test]
+    fn fake_shifts_are_not_keys() {
+        let pressed = fed(&[RawStroke::make(ScanCode::e0(0x2A)), RawStroke::make(HOME)]);
+        assert!(pressed.contains(HOME));
+        assert!(!pressed.contains(ScanCode::e0(0x2A)) && !pressed.contains(ScanCode::new(0x2A)));
+    }
+
+    #[test]
+    fn pause_takes_the_e0_45_slot_once_both_halves_arrived() {
+        const PAUSE_SECOND: ScanCode = ScanCode::new(0x45);
+
+        assert_eq!(fed(&[RawStroke::make(PAUSE)]), PressedKeys::new());
+
+        let pressed = fed(&[RawStroke::make(PAUSE), RawStroke::make(PAUSE_SECOND)]);
+        assert!(pressed.contains(PAUSE) && pressed.contains(ScanCode::e0(0x45)));
+        assert!(!pressed.contains(PAUSE_SECOND));
+
+        let released = fed(&[
+            RawStroke::make(PAUSE), RawStroke::make(PAUSE_SECOND), RawStroke::release(PAUSE), RawStroke::release(PAUSE_SECOND),
+        ]);
+        assert_eq!(released, PressedKeys::new());
+    }
+
+    #[test]
+    fn other_e1_keys_have_no_bit() {
+        assert_eq!(PressedKeys::index(ScanCode::e1(0x1E)), None);
+        assert!(!fed(&[RawStroke::make(ScanCode::e1(0x1E))]).contains(ScanCode::e1(0x1E)));
+        assert_eq!(PressedKeys::index(ScanCode::e0(0xFF)), Some(511));
+    }
+
+    #[test]
+    fn union_holds_the_keys_of_both() {
+        let mut union = fed(&[RawStroke::make(A)]);
+        union.union(&fed(&[RawStroke::make(HOME)]));
+        assert!(union.contains(A) && union.contains(HOME));
+        assert!(!union.contains(LEFT_CONTROL));
+    }
+
+    #[test]
+    fn reset_forgets_everything() {
+        let mut state = KeyState::new();
+        state.feed(RawStroke::make(A));
+        state.feed(RawStroke::make(PAUSE));
+        state.reset();
+
+        assert_eq!(*state.pressed(), PressedKeys::new());
+        // The held back half of Pause went too
+        state.feed(RawStroke::make(ScanCode::new(0x45)));
+        assert!(state.pressed().contains(ScanCode::new(0x45)));
+    }
+}
@@ -0,0 +1,337 @@
+//! Keyboard layout tables: which character each key produces in each shift state, and dead keys.
+//!
+//! The tables follow the `LAYOUT` and `DEADKEY` sections of the Microsoft Keyboard Layout Creator
+//! (KLC) sources of the Windows layouts, restricted to the shift states text injection uses.
+//! Tab, Enter and Backspace are added since scripts type them too.
+
+use crate::keyboard::scan_code::ScanCode;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShiftState {
+    Base = 0,
+    Shift = 1,
+    /// Control+Alt, or the right Alt key on layouts that have AltGr.
+    AltGr = 2,
+    ShiftAltGr = 3,
+}
+
+impl ShiftState {
+    pub const ALL: [ShiftState; 4] = [ShiftState::Base, ShiftState::Shift, ShiftState::AltGr, ShiftState::ShiftAltGr];
+
+    pub const fn has_shift(self) -> bool {
+        matches!(self, ShiftState::Shift | ShiftState::ShiftAltGr)
+    }
+
+    pub const fn has_alt_gr(self) -> bool {
+        matches!(self, ShiftState::AltGr | ShiftState::ShiftAltGr)
+    }
+}
+
+/// Output of a key without a character in some shift state.
+pub const NO_CHARACTER: u16 = 0;
+
+#[derive(Debug, Copy, Clone)]
+pub struct LayoutKey {
+    pub scan_code: ScanCode,
+    /// UTF-16 code unit per [`ShiftState`], [`NO_CHARACTER`] if none.
+    pub outputs: [u16; 4],
+    /// Bit per [`ShiftState`] whose output is a dead key: it only produces a character combined with the next one.
+    pub dead: u8,
+}
+
+impl LayoutKey {
+    pub const fn output(&self, state: ShiftState) -> u16 {
+        self.outputs[state as usize]
+    }
+
+    pub const fn is_dead(&self, state: ShiftState) -> bool {
+        self.dead & (1 << state as u8) != 0
+    }
+}
+
+/// `accent` followed by `base` produces `composed`.
+#[derive(Debug, Copy, Clone)]
+pub struct DeadKey {
+    pub accent: u16,
+    pub base: u16,
+    pub composed: u16,
+}
+
+#[derive(Debug)]
+pub struct Layout {
+    pub name: &'static str,
+    /// Keyboard layout identifier, e.g. `0x0409` for `00000409`.
+    pub id: u32,
+    pub keys: &'static [LayoutKey],
+    pub dead_keys: &'static [DeadKey],
+}
+
+impl Layout {
+    /// The key and shift state producing `character` directly, without a dead key.
+    pub fn find(&self, character: u16) -> Option<(ScanCode, ShiftState)> {
+        if character == NO_CHARACTER {
+            return None;
+        }
+        self.find_output(character, false)
+    }
+
+    /// The key and shift state of the dead key for `accent`.
+    pub fn find_dead(&self, accent: u16) -> Option<(ScanCode, ShiftState)> {
+        self.find_output(accent, true)
+    }
+
+    fn find_output(&self, output: u16, dead: bool) -> Option<(ScanCode, ShiftState)> {
+        self.keys.iter().find_map(|key| {
+            ShiftState::ALL.into_iter()
+                .find(|&state| key.output(state) == output && key.is_dead(state) == dead)
+                .map(|state| (key.scan_code, state))
+        })
+    }
+
+    /// The dead key combination composing `character`.
+    pub fn find_composition(&self, character: u16) -> Option<&DeadKey> {
+        self.dead_keys.iter().find(|dead_key| dead_key.composed == character)
+    }
+}
+
+const fn key(code: u8, base: char, shift: char) -> LayoutKey {
+    LayoutKey { scan_code: ScanCode::new(code), outputs: [base as u16, shift as u16, NO_CHARACTER, NO_CHARACTER], dead: 0 }
+}
+
+const fn alt_gr(code: u8, base: char, shift: char, alt_gr: char) -> LayoutKey {
+    LayoutKey { scan_code: ScanCode::new(code), outputs: [base as u16, shift as u16, alt_gr as u16, NO_CHARACTER], dead: 0 }
+}
+
+const fn dead(code: u8, base: char, shift: char, dead: u8) -> LayoutKey {
+    LayoutKey { scan_code: ScanCode::new(code), outputs: [base as u16, shift as u16, NO_CHARACTER, NO_CHARACTER], dead }
+}
+
+const fn control(code: u8, output: char) -> LayoutKey {
+    LayoutKey { scan_code: ScanCode::new(code), outputs: [output as u16, NO_CHARACTER, NO_CHARACTER, NO_CHARACTER], dead: 0 }
+}
+
+const fn compose(accent: char, base: char, composed: char) -> DeadKey {
+    DeadKey { accent: accent as u16, base: base as u16, composed: composed as u16 }
+}
+
+const BASE_DEAD: u8 = 1 << ShiftState::Base as u8;
+const SHIFT_DEAD: u8 = 1 << ShiftState::Shift as u8;
+
+/// US (`kbdus.klc`).
+pub const US: Layout = Layout {
+    name: "US",
+    id: 0x0409,
+    keys: &[
+        key(0x29, '`', '~'),
+        key(0x02, '1', '!'),
+        key(0x03, '2', '@'),
+        key(0x04, '3', '#'),
+        key(0x05, '4', '$'),
+        key(0x06, '5', '%'),
+        key(0x07, '6', '^'),
+        key(0x08, '7', '&'),
+        key(0x09, '8', '*'),
+        key(0x0A, '9', '('),
+        key(0x0B, '0', ')'),
+        key(0x0C, '-', '_'),
+        key(0x0D, '=', '+'),
+        key(0x10, 'q', 'Q'),
+        key(0x11, 'w', 'W'),
+        key(0x12, 'e', 'E'),
+        key(0x13, 'r', 'R'),
+        key(0x14, 't', 'T'),
+        key(0x15, 'y', 'Y'),
+        key(0x16, 'u', 'U'),
+        key(0x17, 'i', 'I'),
+        key(0x18, 'o', 'O'),
+        key(0x19, 'p', 'P'),
+        key(0x1A, '[', '{'),
+        key(0x1B, ']', '}'),
+        key(0x2B, '\\', '|'),
+        key(0x1E, 'a', 'A'),
+        key(0x1F, 's', 'S'),
+        key(0x20, 'd', 'D'),
+        key(0x21, 'f', 'F'),
+        key(0x22, 'g', 'G'),
+        key(0x23, 'h', 'H'),
+        key(0x24, 'j', 'J'),
+        key(0x25, 'k', 'K'),
+        key(0x26, 'l', 'L'),
+        key(0x27, ';', ':'),
+        key(0x28, '\'', '"'),
+        key(0x2C, 'z', 'Z'),
+        key(0x2D, 'x', 'X'),
+        key(0x2E, 'c', 'C'),
+        key(0x2F, 'v', 'V'),
+        key(0x30, 'b', 'B'),
+        key(0x31, 'n', 'N'),
+        key(0x32, 'm', 'M'),
+        key(0x33, ',', '<'),
+        key(0x34, '.', '>'),
+        key(0x35, '/', '?'),
+        key(0x39, ' ', ' '),
+        control(0x0F, '\t'),
+        control(0x1C, '\r'),
+        control(0x0E, '\u{8}'),
+    ],
+    dead_keys: &[],
+};
+
+/// German (`kbdgr.klc`).
+pub const GERMAN: Layout = Layout {
+    name: "German",
+    id: 0x0407,
+    keys: &[
+        dead(0x29, '^', '°', BASE_DEAD),
+        key(0x02, '1', '!'),
+        alt_gr(0x03, '2', '"', '²'),
+        alt_gr(0x04, '3', '§', '³'),
+        key(0x05, '4', '$'),
+        key(0x06, '5', '%'),
+        key(0x07, '6', '&'),
+        alt_gr(0x08, '7', '/', '{'),
+        alt_gr(0x09, '8', '(', '['),
+        alt_gr(0x0A, '9', ')', ']'),
+        alt_gr(0x0B, '0', '=', '}'),
+        alt_gr(0x0C, 'ß', '?', '\\'),
+        dead(0x0D, '´', '`', BASE_DEAD | SHIFT_DEAD),
+        alt_gr(0x10, 'q', 'Q', '@'),
+        key(0x11, 'w', 'W'),
+        alt_gr(0x12, 'e', 'E', '€'),
+        key(0x13, 'r', 'R'),
+        key(0x14, 't', 'T'),
+        key(0x15, 'z', 'Z'),
+        key(0x16, 'u', 'U'),
+        key(0x17, 'i', 'I'),
+        key(0x18, 'o', 'O'),
+        key(0x19, 'p', 'P'),
+        key(0x1A, 'ü', 'Ü'),
+        alt_gr(0x1B, '+', '*', '~'),
+        key(0x1E, 'a', 'A'),
+        key(0x1F, 's', 'S'),
+        key(0x20, 'd', 'D'),
+        key(0x21, 'f', 'F'),
+        key(0x22, 'g', 'G'),
+        key(0x23, 'h', 'H'),
+        key(0x24, 'j', 'J'),
+        key(0x25, 'k', 'K'),
+        key(0x26, 'l', 'L'),
+        key(0x27, 'ö', 'Ö'),
+        key(0x28, 'ä', 'Ä'),
+        key(0x2B, '#', '\''),
+        alt_gr(0x56, '<', '>', '|'),
+        key(0x2C, 'y', 'Y'),
+        key(0x2D, 'x', 'X'),
+        key(0x2E, 'c', 'C'),
+        key(0x2F, 'v', 'V'),
+        key(0x30, 'b', 'B'),
+        key(0x31, 'n', 'N'),
+        alt_gr(0x32, 'm', 'M', 'µ'),
+        key(0x33, ',', ';'),
+        key(0x34, '.', ':'),
+        key(0x35, '-', '_'),
+        key(0x39, ' ', ' '),
+        control(0x0F, '\t'),
+        control(0x1C, '\r'),
+        control(0x0E, '\u{8}'),
+    ],
+    dead_keys: &[
+        compose('^', 'a', 'â'), compose('^', 'e', 'ê'), compose('^', 'i', 'î'), compose('^', 'o', 'ô'), compose('^', 'u', 'û'),
+        compose('^', 'A', 'Â'), compose('^', 'E', 'Ê'), compose('^', 'I', 'Î'), compose('^', 'O', 'Ô'), compose('^', 'U', 'Û'),
+        compose('^', ' ', '^'),
+        compose('´', 'a', 'á'), compose('´', 'e', 'é'), compose('´', 'i', 'í'), compose('´', 'o', 'ó'), compose('´', 'u', 'ú'), compose('´', 'y', 'ý'),
+        compose('´', 'A', 'Á'), compose('´', 'E', 'É'), compose('´', 'I', 'Í'), compose('´', 'O', 'Ó'), compose('´', 'U', 'Ú'), compose('´', 'Y', 'Ý'),
+        compose('´', ' ', '´'),
+        compose('`', 'a', 'à'), compose('`', 'e', 'è'), compose('`', 'i', 'ì'), compose('`', 'o', 'ò'), compose('`', 'u', 'ù'),
+        compose('`', 'A', 'À'), compose('`', 'E', 'È'), compose('`', 'I', 'Ì'), compose('`', 'O', 'Ò'), compose('`', 'U', 'Ù'),
+        compose('`', ' ', '`'),
+    ],
+};
+
+pub const LAYOUTS: &[&Layout] = &[&US, &GERMAN];
+
+pub fn by_id(id: u32) -> Option<&'static Layout> {
+    LAYOUTS.iter().copied().find(|layout| layout.id == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(character: char) -> u16 {
+        character as u16
+    }
+
+    #[test]
+    fn layouts_by_id() {
+        assert_eq!(by_id(0x0409).map(|layout| layout.name), Some("US"));
+        assert_eq!(by_id(0x0407).map(|layout| layout.name), Some("German"));
+        assert!(by_id(0x040C).is_none());
+        assert!(by_id(0).is_none());
+    }
+
+    #[test]
+    fn ids_and_scan_codes_are_unique() {
+        for (i, layout) in LAYOUTS.iter().enumerate() {
+            assert!(LAYOUTS[i + 1..].iter().all(|other| other.id != layout.id), "{}", layout.name);
+            for (j, key) in layout.keys.iter().enumerate() {
+                assert!(layout.keys[j + 1..].iter().all(|other| other.scan_code != key.scan_code), "{} {:?}", layout.name, key.scan_code);
+            }
+        }
+    }
+
+    #[test]
+    fn every_composition_is_typeable() {
+        for layout in LAYOUTS {
+            for composition in layout.dead_keys {
+                assert!(layout.find_dead(composition.accent).is_some(), "{} accent {:#X}", layout.name, composition.accent);
+                assert!(layout.find(composition.base).is_some(), "{} base {:#X}", layout.name, composition.base);
+                assert_eq!(layout.find_composition(composition.composed).map(|found| found.base), Some(composition.base));
+            }
+        }
+    }
+
+    #[test]
+    fn every_output_is_found_on_a_key_producing_it() {
+        for layout in LAYOUTS {
+            for key in layout.keys {
+                for state in ShiftState::ALL.into_iter().filter(|&state| key.output(state) != NO_CHARACTER) {
+                    let output = key.output(state);
+                    let found = if key.is_dead(state) { layout.find_dead(output) } else { layout.find(output) };
+                    let (scan_code, found_state) = found.unwrap();
+                    let found_key = layout.keys.iter().find(|key| key.scan_code == scan_code).unwrap();
+                    assert_eq!(found_key.output(found_state), output, "{} {:?}", layout.name, key.scan_code);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shift_states() {
+        assert_eq!(US.find(utf16('a')), Some((ScanCode::new(0x1E), ShiftState::Base)));
+        assert_eq!(US.find(utf16('A')), Some((ScanCode::new(0x1E), ShiftState::Shift)));
+        assert_eq!(GERMAN.find(utf16('@')), Some((ScanCode::new(0x10), ShiftState::AltGr)));
+        assert_eq!(GERMAN.find(utf16('€')), Some((ScanCode::new(0x12), ShiftState::AltGr)));
+        assert!(ShiftState::ShiftAltGr.has_shift() && ShiftState::ShiftAltGr.has_alt_gr());
+        assert!(!ShiftState::Base.has_shift() && !ShiftState::Base.has_alt_gr());
+    }
+
+    #[test]
+    fn dead_keys_are_only_found_as_such() {
+        // The circumflex is a dead key on German and a plain character on US
+        assert_eq!(GERMAN.find(utf16('^')), None);
+        assert_eq!(GERMAN.find_dead(utf16('^')), Some((ScanCode::new(0x29), ShiftState::Base)));
+        assert_eq!(US.find(utf16('^')), Some((ScanCode::new(0x07), ShiftState::Shift)));
+        assert_eq!(US.find_dead(utf16('^')), None);
+        assert!(US.dead_keys.is_empty());
+    }
+
+    #[test]
+    fn missing_characters() {
+        assert_eq!(US.find(NO_CHARACTER), None);
+        assert_eq!(US.find(utf16('ä')), None);
+        assert_eq!(US.find_composition(utf16('ê')).map(|found| found.accent), None);
+        assert_eq!(GERMAN.find_composition(utf16('ê')).map(|found| found.accent), Some(utf16('^')));
+        assert_eq!(GERMAN.find_composition(utf16('a')).map(|found| found.accent), None);
+    }
+}
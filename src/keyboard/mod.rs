@@ -0,0 +1,12 @@
+//! Keyboard knowledge that doesn't depend on the kernel: scan code tables, sequence decoding and layouts.
+//!
+//! Everything here only depends on `core`, so client libraries can share it and it can be exercised on the host.
+
+pub mod block;
+pub mod chord;
+pub mod key_set;
+pub mod key_state;
+pub mod layout;
+pub mod scan_code;
+pub mod sequence;
+pub mod text;
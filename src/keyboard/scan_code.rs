@@ -0,0 +1,469 @@
+//! Scan code set 1 ↔ Windows virtual key ↔ HID usage tables.
+//!
+//! Numpad keys map to their NumLock-on virtual keys (`VK_NUMPAD0`…), the cursor block to the
+//! E0-prefixed codes. A virtual key produced by more than one key (`VK_RETURN`, `VK_SNAPSHOT`,
+//! `VK_OEM_5`) maps back to the first entry in [`KEYS`].
+
+/// `KEY_E0` and `KEY_E1` of `KEYBOARD_INPUT_DATA::Flags`.
+const FLAG_E0: u16 = 0x02;
+const FLAG_E1: u16 = 0x04;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Prefix {
+    None,
+    E0,
+    E1,
+}
+
+/// A set 1 make code together with the prefix the port driver reported it with. `Debug` is in
+/// [`crate::privacy`].
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ScanCode {
+    pub code: u8,
+    pub prefix: Prefix,
+}
+
+impl ScanCode {
+    pub const fn new(code: u8) -> Self {
+        Self { code, prefix: Prefix::None }
+    }
+
+    pub const fn e0(code: u8) -> Self {
+        Self { code, prefix: Prefix::E0 }
+    }
+
+    pub const fn e1(code: u8) -> Self {
+        Self { code, prefix: Prefix::E1 }
+    }
+
+    /// From the `MakeCode` and `Flags` of a `KEYBOARD_INPUT_DATA`. Returns `None` for make codes above 0xFF.
+    pub const fn from_input(make_code: u16, flags: u16) -> Option<Self> {
+        if make_code > 0xFF {
+            return None;
+        }
+
+        let prefix = if flags & FLAG_E1 != 0 {
+            Prefix::E1
+        } else if flags & FLAG_E0 != 0 {
+            Prefix::E0
+        } else {
+            Prefix::None
+        };
+
+        Some(Self { code: make_code as u8, prefix })
+    }
+
+    /// The prefix bits of `KEYBOARD_INPUT_DATA::Flags`.
+    pub const fn flags(self) -> u16 {
+        match self.prefix {
+            Prefix::None => 0,
+            Prefix::E0 => FLAG_E0,
+            Prefix::E1 => FLAG_E1,
+        }
+    }
+
+    pub const fn const_eq(self, other: Self) -> bool {
+        self.code == other.code && self.prefix as u8 == other.prefix as u8
+    }
+}
+
+pub const PAGE_GENERIC_DESKTOP: u16 = 0x01;
+pub const PAGE_KEYBOARD: u16 = 0x07;
+pub const PAGE_CONSUMER: u16 = 0x0C;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct HidUsage {
+    pub page: u16,
+    pub id: u16,
+}
+
+impl HidUsage {
+    /// For keys without a HID equivalent.
+    pub const NONE: Self = Self { page: 0, id: 0 };
+
+    pub const fn keyboard(id: u16) -> Self {
+        Self { page: PAGE_KEYBOARD, id }
+    }
+
+    pub const fn consumer(id: u16) -> Self {
+        Self { page: PAGE_CONSUMER, id }
+    }
+
+    pub const fn generic_desktop(id: u16) -> Self {
+        Self { page: PAGE_GENERIC_DESKTOP, id }
+    }
+
+    pub const fn is_none(self) -> bool {
+        self.page == 0
+    }
+
+    pub const fn const_eq(self, other: Self) -> bool {
+        self.page == other.page && self.id == other.id
+    }
+}
+
+/// Virtual key code, 0 for keys without one.
+pub type VirtualKey = u8;
+
+pub const VK_NONE: VirtualKey = 0;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Key {
+    pub name: &'static str,
+    pub scan_code: ScanCode,
+    pub virtual_key: VirtualKey,
+    pub usage: HidUsage,
+}
+
+const fn key(name: &'static str, code: u8, virtual_key: VirtualKey, usage: u16) -> Key {
+    Key { name, scan_code: ScanCode::new(code), virtual_key, usage: HidUsage::keyboard(usage) }
+}
+
+const fn extended(name: &'static str, code: u8, virtual_key: VirtualKey, usage: u16) -> Key {
+    Key { name, scan_code: ScanCode::e0(code), virtual_key, usage: HidUsage::keyboard(usage) }
+}
+
+const fn media(name: &'static str, code: u8, virtual_key: VirtualKey, usage: u16) -> Key {
+    Key { name, scan_code: ScanCode::e0(code), virtual_key, usage: HidUsage::consumer(usage) }
+}
+
+const fn system(name: &'static str, code: u8, virtual_key: VirtualKey, usage: u16) -> Key {
+    Key { name, scan_code: ScanCode::e0(code), virtual_key, usage: HidUsage::generic_desktop(usage) }
+}
+
+pub const KEYS: &[Key] = &[
+    key("Escape", 0x01, 0x1B, 0x29),
+    key("1", 0x02, b'1', 0x1E),
+    key("2", 0x03, b'2', 0x1F),
+    key("3", 0x04, b'3', 0x20),
+    key("4", 0x05, b'4', 0x21),
+    key("5", 0x06, b'5', 0x22),
+    key("6", 0x07, b'6', 0x23),
+    key("7", 0x08, b'7', 0x24),
+    key("8", 0x09, b'8', 0x25),
+    key("9", 0x0A, b'9', 0x26),
+    key("0", 0x0B, b'0', 0x27),
+    key("Minus", 0x0C, 0xBD, 0x2D),
+    key("Equals", 0x0D, 0xBB, 0x2E),
+    key("Backspace", 0x0E, 0x08, 0x2A),
+    key("Tab", 0x0F, 0x09, 0x2B),
+    key("Q", 0x10, b'Q', 0x14),
+    key("W", 0x11, b'W', 0x1A),
+    key("E", 0x12, b'E', 0x08),
+    key("R", 0x13, b'R', 0x15),
+    key("T", 0x14, b'T', 0x17),
+    key("Y", 0x15, b'Y', 0x1C),
+    key("U", 0x16, b'U', 0x18),
+    key("I", 0x17, b'I', 0x0C),
+    key("O", 0x18, b'O', 0x12),
+    key("P", 0x19, b'P', 0x13),
+    key("LeftBracket", 0x1A, 0xDB, 0x2F),
+    key("RightBracket", 0x1B, 0xDD, 0x30),
+    key("Enter", 0x1C, 0x0D, 0x28),
+    key("LeftControl", 0x1D, 0xA2, 0xE0),
+    key("A", 0x1E, b'A', 0x04),
+    key("S", 0x1F, b'S', 0x16),
+    key("D", 0x20, b'D', 0x07),
+    key("F", 0x21, b'F', 0x09),
+    key("G", 0x22, b'G', 0x0A),
+    key("H", 0x23, b'H', 0x0B),
+    key("J", 0x24, b'J', 0x0D),
+    key("K", 0x25, b'K', 0x0E),
+    key("L", 0x26, b'L', 0x0F),
+    key("Semicolon", 0x27, 0xBA, 0x33),
+    key("Apostrophe", 0x28, 0xDE, 0x34),
+    key("Grave", 0x29, 0xC0, 0x35),
+    key("LeftShift", 0x2A, 0xA0, 0xE1),
+    key("Backslash", 0x2B, 0xDC, 0x31),
+    key("Z", 0x2C, b'Z', 0x1D),
+    key("X", 0x2D, b'X', 0x1B),
+    key("C", 0x2E, b'C', 0x06),
+    key("V", 0x2F, b'V', 0x19),
+    key("B", 0x30, b'B', 0x05),
+    key("N", 0x31, b'N', 0x11),
+    key("M", 0x32, b'M', 0x10),
+    key("Comma", 0x33, 0xBC, 0x36),
+    key("Period", 0x34, 0xBE, 0x37),
+    key("Slash", 0x35, 0xBF, 0x38),
+    key("RightShift", 0x36, 0xA1, 0xE5),
+    key("NumpadMultiply", 0x37, 0x6A, 0x55),
+    key("LeftAlt", 0x38, 0xA4, 0xE2),
+    key("Space", 0x39, 0x20, 0x2C),
+    key("CapsLock", 0x3A, 0x14, 0x39),
+    key("F1", 0x3B, 0x70, 0x3A),
+    key("F2", 0x3C, 0x71, 0x3B),
+    key("F3", 0x3D, 0x72, 0x3C),
+    key("F4", 0x3E, 0x73, 0x3D),
+    key("F5", 0x3F, 0x74, 0x3E),
+    key("F6", 0x40, 0x75, 0x3F),
+    key("F7", 0x41, 0x76, 0x40),
+    key("F8", 0x42, 0x77, 0x41),
+    key("F9", 0x43, 0x78, 0x42),
+    key("F10", 0x44, 0x79, 0x43),
+    key("NumLock", 0x45, 0x90, 0x53),
+    key("ScrollLock", 0x46, 0x91, 0x47),
+    key("Numpad7", 0x47, 0x67, 0x5F),
+    key("Numpad8", 0x48, 0x68, 0x60),
+    key("Numpad9", 0x49, 0x69, 0x61),
+    key("NumpadSubtract", 0x4A, 0x6D, 0x56),
+    key("Numpad4", 0x4B, 0x64, 0x5C),
+    key("Numpad5", 0x4C, 0x65, 0x5D),
+    key("Numpad6", 0x4D, 0x66, 0x5E),
+    key("NumpadAdd", 0x4E, 0x6B, 0x57),
+    key("Numpad1", 0x4F, 0x61, 0x59),
+    key("Numpad2", 0x50, 0x62, 0x5A),
+    key("Numpad3", 0x51, 0x63, 0x5B),
+    key("Numpad0", 0x52, 0x60, 0x62),
+    key("NumpadDecimal", 0x53, 0x6E, 0x63),
+    // Alt+PrintScreen
+    key("SysRq", 0x54, 0x2C, 0x9A),
+    key("IntlBackslash", 0x56, 0xE2, 0x64),
+    key("F11", 0x57, 0x7A, 0x44),
+    key("F12", 0x58, 0x7B, 0x45),
+    key("NumpadEquals", 0x59, 0x0C, 0x67),
+    key("F13", 0x64, 0x7C, 0x68),
+    key("F14", 0x65, 0x7D, 0x69),
+    key("F15", 0x66, 0x7E, 0x6A),
+    key("F16", 0x67, 0x7F, 0x6B),
+    key("F17", 0x68, 0x80, 0x6C),
+    key("F18", 0x69, 0x81, 0x6D),
+    key("F19", 0x6A, 0x82, 0x6E),
+    key("F20", 0x6B, 0x83, 0x6F),
+    key("F21", 0x6C, 0x84, 0x70),
+    key("F22", 0x6D, 0x85, 0x71),
+    key("F23", 0x6E, 0x86, 0x72),
+    key("KanaMode", 0x70, 0xF2, 0x88),
+    key("IntlRo", 0x73, 0xC1, 0x87),
+    key("F24", 0x76, 0x87, 0x73),
+    key("Convert", 0x79, 0x1C, 0x8A),
+    key("NonConvert", 0x7B, 0x1D, 0x8B),
+    key("IntlYen", 0x7D, 0xDC, 0x89),
+    key("NumpadComma", 0x7E, 0xC2, 0x85),
+    key("Hanja", 0xF1, 0x19, 0x91),
+    key("Hangul", 0xF2, 0x15, 0x90),
+
+    extended("NumpadEnter", 0x1C, 0x0D, 0x58),
+    extended("RightControl", 0x1D, 0xA3, 0xE4),
+    extended("NumpadDivide", 0x35, 0x6F, 0x54),
+    extended("PrintScreen", 0x37, 0x2C, 0x46),
+    extended("RightAlt", 0x38, 0xA5, 0xE6),
+    extended("Home", 0x47, 0x24, 0x4A),
+    extended("ArrowUp", 0x48, 0x26, 0x52),
+    extended("PageUp", 0x49, 0x21, 0x4B),
+    extended("ArrowLeft", 0x4B, 0x25, 0x50),
+    extended("ArrowRight", 0x4D, 0x27, 0x4F),
+    extended("End", 0x4F, 0x23, 0x4D),
+    extended("ArrowDown", 0x50, 0x28, 0x51),
+    extended("PageDown", 0x51, 0x22, 0x4E),
+    extended("Insert", 0x52, 0x2D, 0x49),
+    extended("Delete", 0x53, 0x2E, 0x4C),
+    extended("LeftMeta", 0x5B, 0x5B, 0xE3),
+    extended("RightMeta", 0x5C, 0x5C, 0xE7),
+    extended("ContextMenu", 0x5D, 0x5D, 0x65),
+    // Ctrl+Pause; HID has no separate usage for it
+    Key { name: "Break", scan_code: ScanCode::e0(0x46), virtual_key: 0x03, usage: HidUsage::NONE },
+    Key { name: "Pause", scan_code: ScanCode::e1(0x1D), virtual_key: 0x13, usage: HidUsage::keyboard(0x48) },
+
+    system("Power", 0x5E, VK_NONE, 0x81),
+    system("Sleep", 0x5F, 0x5F, 0x82),
+    system("WakeUp", 0x63, VK_NONE, 0x83),
+
+    media("MediaTrackPrevious", 0x10, 0xB1, 0xB6),
+    media("MediaTrackNext", 0x19, 0xB0, 0xB5),
+    media("AudioVolumeMute", 0x20, 0xAD, 0xE2),
+    media("LaunchApp2", 0x21, 0xB7, 0x192),
+    media("MediaPlayPause", 0x22, 0xB3, 0xCD),
+    media("MediaStop", 0x24, 0xB2, 0xB7),
+    media("AudioVolumeDown", 0x2E, 0xAE, 0xEA),
+    media("AudioVolumeUp", 0x30, 0xAF, 0xE9),
+    media("BrowserHome", 0x32, 0xAC, 0x223),
+    media("BrowserSearch", 0x65, 0xAA, 0x221),
+    media("BrowserFavorites", 0x66, 0xAB, 0x22A),
+    media("BrowserRefresh", 0x67, 0xA8, 0x227),
+    media("BrowserStop", 0x68, 0xA9, 0x226),
+    media("BrowserForward", 0x69, 0xA7, 0x225),
+    media("BrowserBack", 0x6A, 0xA6, 0x224),
+    media("LaunchApp1", 0x6B, 0xB6, 0x194),
+    media("LaunchMail", 0x6C, 0xB4, 0x18A),
+    media("MediaSelect", 0x6D, 0xB5, 0x183),
+];
+
+pub const fn by_scan_code(scan_code: ScanCode) -> Option<&'static Key> {
+    let mut i = 0;
+    while i < KEYS.len() {
+        if KEYS[i].scan_code.const_eq(scan_code) {
+            return Some(&KEYS[i]);
+        }
+        i += 1;
+    }
+    None
+}
+
+pub const fn by_virtual_key(virtual_key: VirtualKey) -> Option<&'static Key> {
+    if virtual_key == VK_NONE {
+        return None;
+    }
+
+    let mut i = 0;
+    while i < KEYS.len() {
+        if KEYS[i].virtual_key == virtual_key {
+            return Some(&KEYS[i]);
+        }
+        i += 1;
+    }
+    None
+}
+
+pub const fn by_usage(usage: HidUsage) -> Option<&'static Key> {
+    if usage.is_none() {
+        return None;
+    }
+
+    let mut i = 0;
+    while i < KEYS.len() {
+        if KEYS[i].usage.const_eq(usage) {
+            return Some(&KEYS[i]);
+        }
+        i += 1;
+    }
+    None
+}
+
+pub const fn scan_code_to_virtual_key(scan_code: ScanCode) -> Option<VirtualKey> {
+    match by_scan_code(scan_code) {
+        Some(key) if key.virtual_key != VK_NONE => Some(key.virtual_key),
+        _ => None,
+    }
+}
+
+pub const fn scan_code_to_usage(scan_code: ScanCode) -> Option<HidUsage> {
+    match by_scan_code(scan_code) {
+        Some(key) if !key.usage.is_none() => Some(key.usage),
+        _ => None,
+    }
+}
+
+pub const fn virtual_key_to_scan_code(virtual_key: VirtualKey) -> Option<ScanCode> {
+    match by_virtual_key(virtual_key) {
+        Some(key) => Some(key.scan_code),
+        None => None,
+    }
+}
+
+pub const fn usage_to_scan_code(usage: HidUsage) -> Option<ScanCode> {
+    match by_usage(usage) {
+        Some(key) => Some(key.scan_code),
+        None => None,
+    }
+}
+
+pub const fn virtual_key_to_usage(virtual_key: VirtualKey) -> Option<HidUsage> {
+    match virtual_key_to_scan_code(virtual_key) {
+        Some(scan_code) => scan_code_to_usage(scan_code),
+        None => None,
+    }
+}
+
+pub const fn usage_to_virtual_key(usage: HidUsage) -> Option<VirtualKey> {
+    match usage_to_scan_code(usage) {
+        Some(scan_code) => scan_code_to_virtual_key(scan_code),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_codes_and_usages_identify_one_key() {
+        for (i, key) in KEYS.iter().enumerate() {
+            for other in &KEYS[i + 1..] {
+                assert_ne!(key.scan_code, other.scan_code, "{} and {} share a scan code", key.name, other.name);
+                if !key.usage.is_none() {
+                    assert_ne!(key.usage, other.usage, "{} and {} share a HID usage", key.name, other.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_scan_code_round_trips() {
+        for key in KEYS {
+            assert_eq!(by_scan_code(key.scan_code), Some(key), "{}", key.name);
+            assert_eq!(ScanCode::from_input(u16::from(key.scan_code.code), key.scan_code.flags()), Some(key.scan_code), "{}", key.name);
+
+            let usage = (!key.usage.is_none()).then_some(key.usage);
+            assert_eq!(scan_code_to_usage(key.scan_code), usage, "{}", key.name);
+            let virtual_key = (key.virtual_key != VK_NONE).then_some(key.virtual_key);
+            assert_eq!(scan_code_to_virtual_key(key.scan_code), virtual_key, "{}", key.name);
+        }
+    }
+
+    #[test]
+    fn every_usage_round_trips() {
+        for key in KEYS.iter().filter(|key| !key.usage.is_none()) {
+            assert_eq!(by_usage(key.usage), Some(key), "{}", key.name);
+            assert_eq!(usage_to_scan_code(key.usage), Some(key.scan_code), "{}", key.name);
+        }
+    }
+
+    #[test]
+    fn every_virtual_key_maps_back_to_a_key_producing_it() {
+        for key in KEYS.iter().filter(|key| key.virtual_key != VK_NONE) {
+            let scan_code = virtual_key_to_scan_code(key.virtual_key).unwrap();
+            assert_eq!(scan_code_to_virtual_key(scan_code), Some(key.virtual_key), "{}", key.name);
+        }
+    }
+
+    #[test]
+    fn shared_virtual_keys_map_to_the_first_key() {
+        assert_eq!(virtual_key_to_scan_code(0x0D), Some(ScanCode::new(0x1C)));
+        assert_eq!(virtual_key_to_scan_code(0x2C), Some(ScanCode::new(0x54)));
+        assert_eq!(virtual_key_to_scan_code(0xDC), Some(ScanCode::new(0x2B)));
+    }
+
+    #[test]
+    fn unmapped_scan_codes() {
+        assert_eq!(by_scan_code(ScanCode::new(0x00)), None);
+        assert_eq!(by_scan_code(ScanCode::new(0x55)), None);
+        // The fake Shift some keyboards send around the cursor block
+        assert_eq!(by_scan_code(ScanCode::e0(0x2A)), None);
+        assert_eq!(by_scan_code(ScanCode::e1(0x45)), None);
+        assert_eq!(ScanCode::from_input(0x100, 0), None);
+
+        // Keys without a virtual key or without a usage
+        assert_eq!(scan_code_to_virtual_key(ScanCode::e0(0x5E)), None);
+        assert_eq!(scan_code_to_usage(ScanCode::e0(0x5E)), Some(HidUsage::generic_desktop(0x81)));
+        assert_eq!(scan_code_to_usage(ScanCode::e0(0x46)), None);
+        assert_eq!(scan_code_to_virtual_key(ScanCode::e0(0x46)), Some(0x03));
+    }
+
+    #[test]
+    fn unmapped_virtual_keys_and_usages() {
+        assert_eq!(virtual_key_to_scan_code(VK_NONE), None);
+        assert_eq!(virtual_key_to_scan_code(0x07), None);
+        assert_eq!(virtual_key_to_scan_code(0xFF), None);
+        assert_eq!(usage_to_scan_code(HidUsage::NONE), None);
+        assert_eq!(usage_to_scan_code(HidUsage::keyboard(0x00)), None);
+        assert_eq!(usage_to_scan_code(HidUsage::keyboard(0xE8)), None);
+        assert_eq!(usage_to_scan_code(HidUsage { page: 0x08, id: 0x01 }), None);
+    }
+
+    #[test]
+    fn consumer_page_is_told_apart_from_the_keyboard_page() {
+        assert_eq!(usage_to_scan_code(HidUsage::consumer(0xE9)), Some(ScanCode::e0(0x30)));
+        assert_eq!(usage_to_scan_code(HidUsage::consumer(0x223)), Some(ScanCode::e0(0x32)));
+        // Mute and LeftAlt share the id 0xE2 on different pages
+        assert_eq!(usage_to_scan_code(HidUsage::consumer(0xE2)), Some(ScanCode::e0(0x20)));
+        assert_eq!(usage_to_scan_code(HidUsage::keyboard(0xE2)), Some(ScanCode::new(0x38)));
+
+        assert_eq!(usage_to_scan_code(HidUsage::keyboard(0xCD)), None);
+        assert_eq!(usage_to_scan_code(HidUsage::consumer(0x29)), None);
+        assert_eq!(usage_to_scan_code(HidUsage::consumer(0x1234)), None);
+
+        assert_eq!(virtual_key_to_usage(0xAF), Some(HidUsage::consumer(0xE9)));
+        assert_eq!(usage_to_virtual_key(HidUsage::consumer(0x223)), Some(0xAC));
+        assert_eq!(usage_to_virtual_key(HidUsage::consumer(0x1234)), None);
+    }
+}
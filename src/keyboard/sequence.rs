@@ -0,0 +1,492 @@
+//! Turns the raw set 1 strokes the port driver delivers into logical key events, and back.
+//!
+//! The port driver already folds the E0 and E1 prefix bytes into the stroke flags, but the
+//! multi-stroke sequences remain:
+//!
+//! - Pause is `E1 1D 45` immediately followed by `E1 9D C5`, with no separate break.
+//! - PrintScreen without modifiers is wrapped in a fake left shift, `E0 2A E0 37` … `E0 B7 E0 AA`.
+//! - The cursor block and the numpad divide key are wrapped in fake shifts undoing the real ones
+//!   (`E0 AA` … `E0 2A`) while a shift is held, and, for the cursor block, in a fake left shift
+//!   while NumLock is on.
+//!
+//! Fake shifts always carry the E0 flag, which real shifts never do, so the decoder drops them.
+//! Pause decodes to [`ScanCode::e1(0x1D)`](ScanCode::e1), the entry in the scan code tables.
+
+use crate::keyboard::scan_code::{Prefix, ScanCode};
+
+/// `KEY_BREAK` of `KEYBOARD_INPUT_DATA::Flags`.
+const FLAG_BREAK: u16 = 0x01;
+
+const PAUSE: ScanCode = ScanCode::e1(0x1D);
+const PAUSE_SECOND: ScanCode = ScanCode::new(0x45);
+const LEFT_SHIFT: ScanCode = ScanCode::new(0x2A);
+const RIGHT_SHIFT: ScanCode = ScanCode::new(0x36);
+const FAKE_LEFT_SHIFT: ScanCode = ScanCode::e0(0x2A);
+const FAKE_RIGHT_SHIFT: ScanCode = ScanCode::e0(0x36);
+const PRINT_SCREEN: ScanCode = ScanCode::e0(0x37);
+const NUMPAD_DIVIDE: ScanCode = ScanCode::e0(0x35);
+
+/// `MakeCode` and `Flags` of a `KEYBOARD_INPUT_DATA`. `Debug` is in [`crate::privacy`].
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct RawStroke {
+    pub make_code: u16,
+    pub flags: u16,
+}
+
+impl RawStroke {
+    pub const fn make(scan_code: ScanCode) -> Self {
+        Self { make_code: scan_code.code as u16, flags: scan_code.flags() }
+    }
+
+    pub const fn release(scan_code: ScanCode) -> Self {
+        Self { make_code: scan_code.code as u16, flags: scan_code.flags() | FLAG_BREAK }
+    }
+
+    pub const fn is_break(self) -> bool {
+        self.flags & FLAG_BREAK != 0
+    }
+
+    pub const fn scan_code(self) -> Option<ScanCode> {
+        ScanCode::from_input(self.make_code, self.flags)
+    }
+}
+
+/// A key going down or up. `Debug` is in [`crate::privacy`].
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub scan_code: ScanCode,
+    pub pressed: bool,
+}
+
+impl KeyEvent {
+    const EMPTY: Self = Self { scan_code: ScanCode::new(0), pressed: false };
+
+    pub const fn pressed(scan_code: ScanCode) -> Self {
+        Self { scan_code, pressed: true }
+    }
+
+    pub const fn released(scan_code: ScanCode) -> Self {
+        Self { scan_code, pressed: false }
+    }
+
+    pub const fn const_eq(self, other: Self) -> bool {
+        self.scan_code.const_eq(other.scan_code) && self.pressed == other.pressed
+    }
+}
+
+/// Events produced by a single stroke: a held back stroke may be released along with the current one.
+#[derive(Debug, Copy, Clone)]
+pub struct Events {
+    events: [KeyEvent; 2],
+    len: usize,
+}
+
+impl Events {
+    const fn new() -> Self {
+        Self { events: [KeyEvent::EMPTY; 2], len: 0 }
+    }
+
+    const fn push(&mut self, event: KeyEvent) {
+        self.events[self.len] = event;
+        self.len += 1;
+    }
+
+    pub const fn as_slice(&self) -> &[KeyEvent] {
+        self.events.split_at(self.len).0
+    }
+}
+
+/// Strokes produced for a single event: up to two fake shifts around the key, or the two halves of Pause.
+#[derive(Debug, Copy, Clone)]
+pub struct Strokes {
+    strokes: [RawStroke; 4],
+    len: usize,
+}
+
+impl Strokes {
+    const fn new() -> Self {
+        Self { strokes: [RawStroke { make_code: 0, flags: 0 }; 4], len: 0 }
+    }
+
+    const fn push(&mut self, stroke: RawStroke) {
+        self.strokes[self.len] = stroke;
+        self.len += 1;
+    }
+
+    pub const fn as_slice(&self) -> &[RawStroke] {
+        self.strokes.split_at(self.len).0
+    }
+}
+
+const fn is_fake_shift(scan_code: ScanCode) -> bool {
+    scan_code.const_eq(FAKE_LEFT_SHIFT) || scan_code.const_eq(FAKE_RIGHT_SHIFT)
+}
+
+/// Insert, Delete, Home, End, PageUp, PageDown and the arrows, which share make codes with the numpad.
+const fn is_cursor_key(scan_code: ScanCode) -> bool {
+    matches!(scan_code.prefix, Prefix::E0)
+        && matches!(scan_code.code, 0x47 | 0x48 | 0x49 | 0x4B | 0x4D | 0x4F | 0x50 | 0x51 | 0x52 | 0x53)
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Decoder {
+    /// The first half of Pause, waiting for its `45`. `Some(true)` for the make half.
+    pending_pause: Option<bool>,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self { pending_pause: None }
+    }
+
+    /// Whether a sequence is in progress. Sequences may span service callback batches.
+    pub const fn is_idle(&self) -> bool {
+        self.pending_pause.is_none()
+    }
+
+    pub fn feed(&mut self, stroke: RawStroke) -> Events {
+        let mut events = Events::new();
+        let Some(scan_code) = stroke.scan_code() else {
+            return events;
+        };
+        let pressed = !stroke.is_break();
+
+        if let Some(pending_pressed) = self.pending_pause.take() {
+            if scan_code == PAUSE_SECOND && pressed == pending_pressed {
+                events.push(KeyEvent { scan_code: PAUSE, pressed });
+                return events;
+            }
+
+            // Not a Pause sequence after all, pass the lone half on
+            events.push(KeyEvent { scan_code: PAUSE, pressed: pending_pressed });
+        }
+
+        if scan_code == PAUSE {
+            self.pending_pause = Some(pressed);
+        } else if !is_fake_shift(scan_code) {
+            events.push(KeyEvent { scan_code, pressed });
+        }
+
+        events
+    }
+
+    /// Abandons any sequence in progress, returning what was held back.
+    pub const fn reset(&mut self) -> Events {
+        let mut events = Events::new();
+        if let Some(pressed) = self.pending_pause.take() {
+            events.push(KeyEvent { scan_code: PAUSE, pressed });
+        }
+        events
+    }
+}
+
+const LEFT_SHIFT_HELD: u8 = 0x01;
+const RIGHT_SHIFT_HELD: u8 = 0x02;
+
+/// Bit tracking a held Control or Alt key.
+const fn control_or_alt_bit(scan_code: ScanCode) -> Option<u8> {
+    match (scan_code.code, scan_code.prefix) {
+        (0x1D, Prefix::None) => Some(0x01),
+        (0x1D, Prefix::E0) => Some(0x02),
+        (0x38, Prefix::None) => Some(0x04),
+        (0x38, Prefix::E0) => Some(0x08),
+        _ => None,
+    }
+}
+
+/// Produces the strokes a real keyboard would send for each event.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Encoder {
+    fake_shifts: bool,
+    num_lock: bool,
+    shifts: u8,
+    /// Held Control and Alt keys, one bit per key.
+    others: u8,
+}
+
+impl Encoder {
+    /// An encoder without fake shifts, which Windows doesn't need.
+    pub const fn new() -> Self {
+        Self { fake_shifts: false, num_lock: false, shifts: 0, others: 0 }
+    }
+
+    /// An encoder adding fake shifts like a PS/2 keyboard does.
+    pub const fn with_fake_shifts(num_lock: bool) -> Self {
+        Self { fake_shifts: true, num_lock, shifts: 0, others: 0 }
+    }
+
+    /// The keyboard follows the NumLock LED the host sets, not the NumLock key, so callers set it.
+    pub const fn set_num_lock(&mut self, num_lock: bool) {
+        self.num_lock = num_lock;
+    }
+
+    pub fn encode(&mut self, event: KeyEvent) -> Strokes {
+        let mut strokes = Strokes::new();
+        let scan_code = event.scan_code;
+
+        if scan_code == PAUSE {
+            if event.pressed {
+                strokes.push(RawStroke::make(PAUSE));
+                strokes.push(RawStroke::make(PAUSE_SECOND));
+            } else {
+                strokes.push(RawStroke::release(PAUSE));
+                strokes.push(RawStroke::release(PAUSE_SECOND));
+            }
+            return strokes;
+        }
+
+        let undo_shifts = self.fake_shifts && self.shifts != 0 && (is_cursor_key(scan_code) || scan_code == NUMPAD_DIVIDE);
+        let add_shift = self.fake_shifts && !undo_shifts
+            && ((is_cursor_key(scan_code) && self.num_lock)
+                || (scan_code == PRINT_SCREEN && self.shifts == 0 && self.others == 0));
+
+        if event.pressed {
+            if undo_shifts {
+                self.push_fake_shifts(&mut strokes, false);
+            } else if add_shift {
+                strokes.push(RawStroke::make(FAKE_LEFT_SHIFT));
+            }
+            strokes.push(RawStroke::make(scan_code));
+        } else {
+            strokes.push(RawStroke::release(scan_code));
+            if undo_shifts {
+                self.push_fake_shifts(&mut strokes, true);
+            } else if add_shift {
+                strokes.push(RawStroke::release(FAKE_LEFT_SHIFT));
+            }
+        }
+
+        self.track_modifiers(event);
+        strokes
+    }
+
+    /// Fake makes or breaks of the shifts currently held.
+    const fn push_fake_shifts(&self, strokes: &mut Strokes, make: bool) {
+        if self.shifts & LEFT_SHIFT_HELD != 0 {
+            strokes.push(if make { RawStroke::make(FAKE_LEFT_SHIFT) } else { RawStroke::release(FAKE_LEFT_SHIFT) });
+        }
+        if self.shifts & RIGHT_SHIFT_HELD != 0 {
+            strokes.push(if make { RawStroke::make(FAKE_RIGHT_SHIFT) } else { RawStroke::release(FAKE_RIGHT_SHIFT) });
+        }
+    }
+
+    fn track_modifiers(&mut self, event: KeyEvent) {
+        let scan_code = event.scan_code;
+        let (field, bit) = if scan_code == LEFT_SHIFT {
+            (&mut self.shifts, LEFT_SHIFT_HELD)
+        } else if scan_code == RIGHT_SHIFT {
+            (&mut self.shifts, RIGHT_SHIFT_HELD)
+        } else if let Some(bit) = control_or_alt_bit(scan_code) {
+            (&mut self.others, bit)
+        } else {
+            return;
+        };
+
+        if event.pressed {
+            *field |= bit;
+        } else {
+            *field &= !bit;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOME: ScanCode = ScanCode::e0(0x47);
+    const ARROW_UP: ScanCode = ScanCode::e0(0x48);
+    const NUM_LOCK: ScanCode = ScanCode::new(0x45);
+    const LEFT_CONTROL: ScanCode = ScanCode::new(0x1D);
+    const RIGHT_CONTROL: ScanCode = ScanCode::e0(0x1D);
+    const LEFT_ALT: ScanCode = ScanCode::new(0x38);
+    const A: ScanCode = ScanCode::new(0x1E);
+
+    fn make(scan_code: ScanCode) -> RawStroke {
+        RawStroke::make(scan_code)
+    }
+
+    fn release(scan_code: ScanCode) -> RawStroke {
+        RawStroke::release(scan_code)
+    }
+
+    fn decode(strokes: &[RawStroke]) -> Vec<KeyEvent> {
+        let mut decoder = Decoder::new();
+        let events = strokes.iter().flat_map(|&stroke| decoder.feed(stroke).as_slice().to_vec()).collect();
+        assert!(decoder.is_idle());
+        events
+    }
+
+    fn encode(mut encoder: Encoder, events: &[KeyEvent]) -> Vec<RawStroke> {
+        events.iter().flat_map(|&event| encoder.encode(event).as_slice().to_vec()).collect()
+    }
+
+    /// Events pressing `modifiers` in order, tapping `key` and releasing them in reverse.
+    fn tap_with(modifiers: &[ScanCode], key: ScanCode) -> Vec<KeyEvent> {
+        let mut events: Vec<_> = modifiers.iter().copied().map(KeyEvent::pressed).collect();
+        events.extend([KeyEvent::pressed(key), KeyEvent::released(key)]);
+        events.extend(modifiers.iter().rev().copied().map(KeyEvent::released));
+        events
+    }
+
+    #[test]
+    fn pause() {
+        let strokes = [make(PAUSE), make(PAUSE_SECOND), release(PAUSE), release(PAUSE_SECOND)];
+        let events = tap_with(&[], PAUSE);
+
+        assert_eq!(decode(&strokes), events);
+        assert_eq!(encode(Encoder::new(), &events), strokes);
+        assert_eq!(encode(Encoder::with_fake_shifts(true), &events), strokes);
+    }
+
+    #[test]
+    fn pause_split_across_batches() {
+        let mut decoder = Decoder::new();
+        assert!(decoder.feed(make(PAUSE)).as_slice().is_empty());
+        assert!(!decoder.is_idle());
+
+        assert_eq!(decoder.feed(make(PAUSE_SECOND)).as_slice(), [KeyEvent::pressed(PAUSE)]);
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn lone_pause_halves_are_passed_on() {
+        assert_eq!(decode(&[make(PAUSE), make(A)]), [KeyEvent::pressed(PAUSE), KeyEvent::pressed(A)]);
+        // A break of 45 doesn't complete the make half
+        assert_eq!(
+            decode(&[make(PAUSE), release(PAUSE_SECOND)]),
+            [KeyEvent::pressed(PAUSE), KeyEvent::released(PAUSE_SECOND)],
+        );
+
+        let mut decoder = Decoder::new();
+        decoder.feed(release(PAUSE));
+        assert_eq!(decoder.reset().as_slice(), [KeyEvent::released(PAUSE)]);
+        assert!(decoder.is_idle());
+        assert!(decoder.reset().as_slice().is_empty());
+    }
+
+    #[test]
+    fn num_lock_is_not_pause() {
+        assert_eq!(decode(&[make(NUM_LOCK), release(NUM_LOCK)]), tap_with(&[], NUM_LOCK));
+    }
+
+    #[test]
+    fn print_screen_alone_gets_a_fake_shift() {
+        let strokes = [make(FAKE_LEFT_SHIFT), make(PRINT_SCREEN), release(PRINT_SCREEN), release(FAKE_LEFT_SHIFT)];
+        let events = tap_with(&[], PRINT_SCREEN);
+
+        assert_eq!(decode(&strokes), events);
+        assert_eq!(encode(Encoder::with_fake_shifts(false), &events), strokes);
+        assert_eq!(encode(Encoder::with_fake_shifts(true), &events), strokes);
+        assert_eq!(encode(Encoder::new(), &events), [make(PRINT_SCREEN), release(PRINT_SCREEN)]);
+    }
+
+    #[test]
+    fn print_screen_with_modifiers_goes_bare() {
+        for modifier in [LEFT_SHIFT, RIGHT_SHIFT, LEFT_CONTROL, RIGHT_CONTROL, LEFT_ALT] {
+            let strokes = [make(modifier), make(PRINT_SCREEN), release(PRINT_SCREEN), release(modifier)];
+            let events = tap_with(&[modifier], PRINT_SCREEN);
+
+            assert_eq!(decode(&strokes), events, "{modifier:?}");
+            assert_eq!(encode(Encoder::with_fake_shifts(false), &events), strokes, "{modifier:?}");
+        }
+    }
+
+    #[test]
+    fn print_screen_gets_its_fake_shift_back_once_modifiers_are_released() {
+        let mut events = tap_with(&[LEFT_CONTROL], A);
+        events.extend(tap_with(&[], PRINT_SCREEN));
+
+        assert_eq!(encode(Encoder::with_fake_shifts(false), &events), [
+            make(LEFT_CONTROL), make(A), release(A), release(LEFT_CONTROL),
+            make(FAKE_LEFT_SHIFT), make(PRINT_SCREEN), release(PRINT_SCREEN), release(FAKE_LEFT_SHIFT),
+        ]);
+    }
+
+    #[test]
+    fn cursor_block_with_num_lock_gets_a_fake_shift() {
+        for key in [HOME, ARROW_UP, ScanCode::e0(0x52), ScanCode::e0(0x53)] {
+            let strokes = [make(FAKE_LEFT_SHIFT), make(key), release(key), release(FAKE_LEFT_SHIFT)];
+            let events = tap_with(&[], key);
+
+            assert_eq!(decode(&strokes), events, "{key:?}");
+            assert_eq!(encode(Encoder::with_fake_shifts(true), &events), strokes, "{key:?}");
+            assert_eq!(encode(Encoder::with_fake_shifts(false), &events), [make(key), release(key)], "{key:?}");
+            assert_eq!(encode(Encoder::new(), &events), [make(key), release(key)], "{key:?}");
+        }
+    }
+
+    #[test]
+    fn numpad_keys_never_get_fake_shifts() {
+        let numpad_home = ScanCode::new(0x47);
+        let events = tap_with(&[], numpad_home);
+        assert_eq!(encode(Encoder::with_fake_shifts(true), &events), [make(numpad_home), release(numpad_home)]);
+    }
+
+    #[test]
+    fn num_lock_follows_what_callers_set() {
+        let mut encoder = Encoder::with_fake_shifts(false);
+        assert_eq!(encoder.encode(KeyEvent::pressed(HOME)).as_slice(), [make(HOME)]);
+        assert_eq!(encoder.encode(KeyEvent::released(HOME)).as_slice(), [release(HOME)]);
+
+        encoder.set_num_lock(true);
+        assert_eq!(encoder.encode(KeyEvent::pressed(HOME)).as_slice(), [make(FAKE_LEFT_SHIFT), make(HOME)]);
+        assert_eq!(encoder.encode(KeyEvent::released(HOME)).as_slice(), [release(HOME), release(FAKE_LEFT_SHIFT)]);
+
+        // The NumLock key itself doesn't change it
+        let mut encoder = Encoder::with_fake_shifts(false);
+        encoder.encode(KeyEvent::pressed(NUM_LOCK));
+        encoder.encode(KeyEvent::released(NUM_LOCK));
+        assert_eq!(encoder.encode(KeyEvent::pressed(HOME)).as_slice(), [make(HOME)]);
+    }
+
+    #[test]
+    fn shifted_cursor_block_undoes_the_shift() {
+        let strokes = [
+            make(LEFT_SHIFT), release(FAKE_LEFT_SHIFT), make(HOME), release(HOME), make(FAKE_LEFT_SHIFT), release(LEFT_SHIFT),
+        ];
+        let events = tap_with(&[LEFT_SHIFT], HOME);
+
+        assert_eq!(decode(&strokes), events);
+        // With NumLock on too, undoing the shift wins over adding one
+        assert_eq!(encode(Encoder::with_fake_shifts(true), &events), strokes);
+        assert_eq!(encode(Encoder::with_fake_shifts(false), &events), strokes);
+    }
+
+    #[test]
+    fn shifted_numpad_divide_undoes_the_shift() {
+        let strokes = [
+            make(RIGHT_SHIFT), release(FAKE_RIGHT_SHIFT), make(NUMPAD_DIVIDE), release(NUMPAD_DIVIDE), make(FAKE_RIGHT_SHIFT), release(RIGHT_SHIFT),
+        ];
+        let events = tap_with(&[RIGHT_SHIFT], NUMPAD_DIVIDE);
+
+        assert_eq!(decode(&strokes), events);
+        assert_eq!(encode(Encoder::with_fake_shifts(false), &events), strokes);
+        assert_eq!(encode(Encoder::new(), &events), [make(RIGHT_SHIFT), make(NUMPAD_DIVIDE), release(NUMPAD_DIVIDE), release(RIGHT_SHIFT)]);
+    }
+
+    #[test]
+    fn numpad_divide_alone_goes_bare() {
+        let events = tap_with(&[], NUMPAD_DIVIDE);
+        assert_eq!(encode(Encoder::with_fake_shifts(true), &events), [make(NUMPAD_DIVIDE), release(NUMPAD_DIVIDE)]);
+    }
+
+    #[test]
+    fn both_shifts_are_undone() {
+        let strokes = [
+            make(LEFT_SHIFT), make(RIGHT_SHIFT),
+            release(FAKE_LEFT_SHIFT), release(FAKE_RIGHT_SHIFT), make(NUMPAD_DIVIDE),
+            release(NUMPAD_DIVIDE), make(FAKE_LEFT_SHIFT), make(FAKE_RIGHT_SHIFT),
+            release(RIGHT_SHIFT), release(LEFT_SHIFT),
+        ];
+        let events = tap_with(&[LEFT_SHIFT, RIGHT_SHIFT], NUMPAD_DIVIDE);
+
+        assert_eq!(decode(&strokes), events);
+        assert_eq!(encode(Encoder::with_fake_shifts(false), &events), strokes);
+    }
+
+    #[test]
+    fn unmappable_strokes_produce_nothing() {
+        assert!(decode(&[RawStroke { make_code: 0x100, flags: 0 }]).is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! Compiles UTF-16 text into the key events typing it on a given [`Layout`].
+//!
+//! Each character is typed with its own modifiers, pressed before and released after the key, so
+//! the sequence doesn't depend on what was held before. Characters only reachable through a dead
+//! key are typed as the dead key followed by the base character. CapsLock is assumed to be off,
+//! and `\n` is typed as Enter.
+
+use crate::keyboard::layout::{Layout, ShiftState};
+use crate::keyboard::scan_code::ScanCode;
+use crate::keyboard::sequence::KeyEvent;
+
+const LEFT_SHIFT: ScanCode = ScanCode::new(0x2A);
+/// Right Alt, which Windows turns into AltGr on layouts having it.
+const RIGHT_ALT: ScanCode = ScanCode::e0(0x38);
+
+/// Most events a single character takes: two keys with shift and AltGr each.
+pub const MAX_EVENTS_PER_CHARACTER: usize = 12;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TextError {
+    /// The character at `index` can't be typed on the layout.
+    Unmappable { index: usize },
+    /// The events for the character at `index` don't fit in the output.
+    OutputTooSmall { index: usize },
+}
+
+/// Writes the events typing `text` into `events`, returning how many were written.
+pub fn compile(text: &[u16], layout: &Layout, events: &mut [KeyEvent]) -> Result<usize, TextError> {
+    let mut len = 0;
+    for (index, &unit) in text.iter().enumerate() {
+        let character = if unit == '\n' as u16 { '\r' as u16 } else { unit };
+        let keys = if let Some(key) = layout.find(character) {
+            [Some(key), None]
+        } else if let Some(composition) = layout.find_composition(character) {
+            match (layout.find_dead(composition.accent), layout.find(composition.base)) {
+                (Some(accent), Some(base)) => [Some(accent), Some(base)],
+                _ => return Err(TextError::Unmappable { index }),
+            }
+        } else {
+            return Err(TextError::Unmappable { index });
+        };
+
+        for (scan_code, state) in keys.into_iter().flatten() {
+            len = type_key(scan_code, state, events, len).ok_or(TextError::OutputTooSmall { index })?;
+        }
+    }
+
+    Ok(len)
+}
+
+/// Appends the events typing `scan_code` in `state` at `len`, returning the new length.
+fn type_key(scan_code: ScanCode, state: ShiftState, events: &mut [KeyEvent], len: usize) -> Option<usize> {
+    let modifiers = [(state.has_shift(), LEFT_SHIFT), (state.has_alt_gr(), RIGHT_ALT)];
+    let modifiers = modifiers.iter().filter(|(held, _)| *held).map(|&(_, modifier)| modifier);
+
+    let sequence = modifiers.clone().map(KeyEvent::pressed)
+        .chain([KeyEvent::pressed(scan_code), KeyEvent::released(scan_code)])
+        .chain(modifiers.rev().map(KeyEvent::released));
+
+    let mut end = len;
+    for event in sequence {
+        *events.get_mut(end)? = event;
+        end += 1;
+    }
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::layout::{GERMAN, US};
+
+    const SHIFT_DOWN: KeyEvent = KeyEvent::pressed(LEFT_SHIFT);
+    const SHIFT_UP: KeyEvent = KeyEvent::released(LEFT_SHIFT);
+    const ALT_GR_DOWN: KeyEvent = KeyEvent::pressed(RIGHT_ALT);
+    const ALT_GR_UP: KeyEvent = KeyEvent::released(RIGHT_ALT);
+
+    fn compiled(text: &str, layout: &Layout) -> Result<Vec<KeyEvent>, TextError> {
+        let text: Vec<u16> = text.encode_utf16().collect();
+        let mut events = vec![KeyEvent::released(ScanCode::new(0)); text.len() * MAX_EVENTS_PER_CHARACTER];
+        let len = compile(&text, layout, &mut events)?;
+        events.truncate(len);
+        Ok(events)
+    }
+
+    fn down(code: u8) -> KeyEvent {
+        KeyEvent::pressed(ScanCode::new(code))
+    }
+
+    fn up(code: u8) -> KeyEvent {
+        KeyEvent::released(ScanCode::new(code))
+    }
+
+    #[test]
+    fn plain_and_shifted_characters() {
+        assert_eq!(compiled("aZ", &US), Ok(vec![down(0x1E), up(0x1E), SHIFT_DOWN, down(0x2C), up(0x2C), SHIFT_UP]));
+        // Y and Z swap places on German
+        assert_eq!(compiled("z", &GERMAN), Ok(vec![down(0x15), up(0x15)]));
+    }
+
+    #[test]
+    fn alt_gr_characters() {
+        assert_eq!(compiled("@", &GERMAN), Ok(vec![ALT_GR_DOWN, down(0x10), up(0x10), ALT_GR_UP]));
+        assert_eq!(compiled("@", &US), Ok(vec![SHIFT_DOWN, down(0x03), up(0x03), SHIFT_UP]));
+        assert_eq!(compiled("µ", &GERMAN), Ok(vec![ALT_GR_DOWN, down(0x32), up(0x32), ALT_GR_UP]));
+    }
+
+    #[test]
+    fn dead_key_compositions() {
+        // "ê" is the circumflex dead key followed by e, "È" shift+grave followed by shift+E
+        assert_eq!(compiled("ê", &GERMAN), Ok(vec![down(0x29), up(0x29), down(0x12), up(0x12)]));
+        assert_eq!(compiled("È", &GERMAN), Ok(vec![
+            SHIFT_DOWN, down(0x0D), up(0x0D), SHIFT_UP, SHIFT_DOWN, down(0x12), up(0x12), SHIFT_UP,
+        ]));
+        // A lone circumflex is the dead key followed by space
+        assert_eq!(compiled("^", &GERMAN), Ok(vec![down(0x29), up(0x29), down(0x39), up(0x39)]));
+    }
+
+    #[test]
+    fn characters_are_typed_independently() {
+        // Nothing carries over from "ê" to "e", nor from the shifted "E" to "a"
+        let expected = [
+            &compiled("ê", &GERMAN).unwrap()[..],
+            &compiled("e", &GERMAN).unwrap(),
+            &compiled("E", &GERMAN).unwrap(),
+            &compiled("a", &GERMAN).unwrap(),
+        ].concat();
+        assert_eq!(compiled("êeEa", &GERMAN), Ok(expected));
+    }
+
+    #[test]
+    fn control_characters() {
+        assert_eq!(compiled("\n", &US), Ok(vec![down(0x1C), up(0x1C)]));
+        assert_eq!(compiled("\r", &US), Ok(vec![down(0x1C), up(0x1C)]));
+        assert_eq!(compiled("\t", &GERMAN), Ok(vec![down(0x0F), up(0x0F)]));
+    }
+
+    #[test]
+    fn empty_text_types_nothing() {
+        assert_eq!(compiled("", &US), Ok(vec![]));
+    }
+
+    #[test]
+    fn unmappable_characters() {
+        assert_eq!(compiled("aä", &US), Err(TextError::Unmappable { index: 1 }));
+        // Characters outside the BMP are surrogate pairs, which no layout has
+        assert_eq!(compiled("a😀", &GERMAN), Err(TextError::Unmappable { index: 1 }));
+        assert_eq!(compile(&[0xD83D], &GERMAN, &mut []), Err(TextError::Unmappable { index: 0 }));
+        assert_eq!(compile(&[0], &US, &mut []), Err(TextError::Unmappable { index: 0 }));
+    }
+
+    #[test]
+    fn output_too_small() {
+        let mut events = [KeyEvent::released(ScanCode::new(0)); 5];
+        assert_eq!(compile(&['a' as u16, 'A' as u16], &US, &mut events), Err(TextError::OutputTooSmall { index: 1 }));
+        assert_eq!(compile(&['A' as u16], &US, &mut events[..3]), Err(TextError::OutputTooSmall { index: 0 }));
+        assert_eq!(compile(&['A' as u16], &US, &mut events[..4]), Ok(4));
+    }
+
+    #[test]
+    fn no_character_takes_more_than_the_maximum() {
+        for layout in crate::keyboard::layout::LAYOUTS {
+            for key in layout.keys {
+                for output in key.outputs {
+                    let mut events = [KeyEvent::released(ScanCode::new(0)); MAX_EVENTS_PER_CHARACTER];
+                    assert!(matches!(compile(&[output], layout, &mut events), Ok(_) | Err(TextError::Unmappable { .. })));
+                }
+            }
+            for composition in layout.dead_keys {
+                let mut events = [KeyEvent::released(ScanCode::new(0)); MAX_EVENTS_PER_CHARACTER];
+                assert!(compile(&[composition.composed], layout, &mut events).is_ok(), "{:#X}", composition.composed);
+            }
+        }
+    }
+}
@@ -28,7 +28,7 @@
 //!    would not need any additional explicit synchronization, just a
 //!    strategy for managing multiple requests outstanding.
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![cfg_attr(feature = "nightly", feature(hint_must_use))]
 //#![deny(warnings)]
 //#![deny(clippy::all)]
@@ -37,23 +37,48 @@
 #![warn(clippy::cargo)]
 #![allow(clippy::missing_safety_doc)]
 
+mod access;
+mod batching;
+mod blocking;
+mod capture;
+mod capture_ring;
+mod control;
 mod device;
 mod driver;
+mod escape;
+mod forwarding;
+mod instances;
+mod keyboard;
 
 #[cfg(not(test))]
 extern crate wdk_panic;
 extern crate alloc;
 
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU32};
 #[cfg(not(test))]
 use wdk_alloc::WDKAllocator;
 use wdk_sys::{*};
 
 mod foreign;
+mod protocol;
+mod privacy;
+mod ring;
+mod shared_ring;
+mod statistics;
+mod targeting;
+mod watchdog;
 
 mod framework;
 
 use crate::foreign::{ConnectData, KeyboardAttributes};
+use crate::framework::spin_lock::SpinLock;
+use crate::keyboard::block::BlockState;
+use crate::keyboard::chord::ChordDetector;
+use crate::keyboard::key_set::KeySet;
+use crate::keyboard::key_state::KeyState;
+use crate::protocol::HARDWARE_ID_LENGTH;
+use crate::statistics::DeviceStatistics;
 
 #[cfg(not(test))]
 #[global_allocator]
@@ -80,16 +105,57 @@ impl Default for ConnectData {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug)]
 pub struct DeviceContext {
     raw_pdo_queue: WDFQUEUE,
+    /// Id clients use to address this keyboard through the control device.
+    instance: u32,
+    /// First hardware ID, NUL padded, matched by hardware ID targets.
+    hardware_id: [u16; HARDWARE_ID_LENGTH],
+    /// Interception style `FILTER_KEY_*` flags of the strokes to capture.
+    filter: AtomicU16,
+    /// How long captured strokes may wait to be read, `CAPTURE_TIMEOUT_DEFAULT` for the registry value.
+    capture_timeout: AtomicU32,
+    /// Set by the watchdog when captured strokes weren't read in time; nothing is captured until the filter is set again.
+    stalled: AtomicBool,
+    /// The handle that set `filter`; closing it resets the filter.
+    filter_owner: AtomicPtr<WDFFILEOBJECT__>,
     upper_connect_data: ConnectData,
+    /// Keys held, carried across `service_callback` batches to evaluate the block rules.
+    block_state: BlockState,
+    /// Keys held, carried across batches to detect the escape chord.
+    chord_detector: ChordDetector,
+    /// Keys whose make the class driver consumed and whose break it hasn't yet.
+    delivered: SpinLock<KeySet>,
+    /// Keys physically held, whatever was captured or blocked, for `ControlGetKeyState`.
+    key_state: SpinLock<KeyState>,
 
     keyboard_attributes: KeyboardAttributes,
+
+    statistics: DeviceStatistics,
 }
 wdf_declare_context_type!(DeviceContext);
 
 
+#[derive(Debug)]
+pub struct ControlContext {
+    read_queue: WDFQUEUE,
+    /// `WaitNotification` requests, all completed by the next notification.
+    notify_queue: WDFQUEUE,
+}
+wdf_declare_context_type!(ControlContext);
+
+
+/// Kept for every handle opened on the raw PDOs and the control device.
+#[derive(Debug)]
+pub struct FileContext {
+    granted_access: ACCESS_MASK,
+    /// A `ReadFormat`, chosen by the client through `ControlSetReadFormat`.
+    read_format: u32,
+}
+wdf_declare_context_type!(FileContext);
+
+
 #[derive(Debug, Copy, Clone)]
 pub struct PdoContext {
     instance: u32,
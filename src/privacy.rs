@@ -0,0 +1,200 @@
+//! Keeps keystroke contents out of logs.
+//!
+//! The `Debug` output of [`KeyboardInputData`] and the other types carrying keys, [`ScanCode`],
+//! [`RawStroke`], [`KeyEvent`] and [`BlockRule`], only shows scan codes when the
+//! `insecure-keystroke-logging` feature is enabled, which is refused outside debug builds, and
+//! batches are logged as a [`StrokeSummary`]. A test fails on any `Debug` formatting in a log
+//! macro that isn't listed as safe in it.
+
+use core::fmt::{Debug, Display, Formatter};
+use crate::foreign::{KEY_BREAK, KEY_E0, KEY_E1, KeyboardInputData};
+use crate::keyboard::block::BlockRule;
+use crate::keyboard::scan_code::ScanCode;
+use crate::keyboard::sequence::{KeyEvent, RawStroke};
+
+#[cfg(all(feature = "insecure-keystroke-logging", not(debug_assertions)))]
+compile_error!("insecure-keystroke-logging logs every keystroke and may only be enabled in debug builds");
+
+/// Whether stroke contents may be written to logs.
+pub const LOG_KEYSTROKES: bool = cfg!(feature = "insecure-keystroke-logging");
+
+impl Debug for KeyboardInputData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut debug = f.debug_struct("KeyboardInputData");
+        debug.field("unit_id", &self.unit_id);
+        if LOG_KEYSTROKES {
+            debug.field("make_code", &self.make_code);
+        }
+        debug.field("flags", &self.flags);
+        if LOG_KEYSTROKES {
+            debug.field("extra_information", &self.extra_information);
+        }
+        debug.finish_non_exhaustive()
+    }
+}
+
+impl Debug for ScanCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut debug = f.debug_struct("ScanCode");
+        if LOG_KEYSTROKES {
+            debug.field("code", &self.code);
+        }
+        debug.field("prefix", &self.prefix);
+        debug.finish_non_exhaustive()
+    }
+}
+
+impl Debug for RawStroke {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut debug = f.debug_struct("RawStroke");
+        if LOG_KEYSTROKES {
+            debug.field("make_code", &self.make_code);
+        }
+        debug.field("flags", &self.flags);
+        debug.finish_non_exhaustive()
+    }
+}
+
+impl Debug for KeyEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("KeyEvent")
+            .field("scan_code", &self.scan_code)
+            .field("pressed", &self.pressed)
+            .finish()
+    }
+}
+
+impl Debug for BlockRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut debug = f.debug_struct("BlockRule");
+        if LOG_KEYSTROKES {
+            debug.field("make_code", &self.make_code);
+        }
+        debug.field("flags", &self.flags);
+        debug.field("modifiers", &self.modifiers);
+        debug.field("target", &self.target);
+        debug.finish_non_exhaustive()
+    }
+}
+
+/// Counts and flags of a batch of strokes, safe to log in any build.
+pub struct StrokeSummary<'a>(pub &'a [KeyboardInputData]);
+
+impl Display for StrokeSummary<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let count = |mask: u16| self.0.iter().filter(|stroke| stroke.flags & mask != 0).count();
+        let breaks = count(KEY_BREAK);
+
+        write!(f, "{} strokes ({} make, {} break, {} E0, {} E1)",
+               self.0.len(), self.0.len() - breaks, breaks, count(KEY_E0), count(KEY_E1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const LOG_MACROS: [&str; 5] = ["log_error", "log_warn", "log_info", "log_debug", "log_trace"];
+
+    /// `Debug` placeholders allowed in log macros, by file, along with why they're safe.
+    const SAFE_DEBUG: &[(&str, &str)] = &[
+        // Unmappable characters are reported by index
+        ("control.rs", "{e:?}"),
+        // `KeyboardInputData`, redacted above
+        ("device.rs", "{input_data:?}"),
+        // `KeyboardAttributes`: the keyboard's mode and counts of keys and indicators
+        ("device.rs", "{attributes:?}"),
+    ];
+
+    #[test]
+    fn key_contents_are_redacted() {
+        if LOG_KEYSTROKES {
+            return;
+        }
+
+        let a = ScanCode::new(0x1E);
+        assert_eq!(format!("{a:?}"), "ScanCode { prefix: None, .. }");
+        assert_eq!(format!("{:?}", RawStroke::release(a)), "RawStroke { flags: 1, .. }");
+        assert_eq!(format!("{:?}", KeyEvent::pressed(a)), "KeyEvent { scan_code: ScanCode { prefix: None, .. }, pressed: true }");
+        assert_eq!(format!("{:?}", BlockRule::key(a)), "BlockRule { flags: 0, modifiers: 0, target: 0, .. }");
+        let stroke = KeyboardInputData { make_code: 0x1E, extra_information: 7, ..KeyboardInputData::EMPTY };
+        assert_eq!(format!("{stroke:?}"), "KeyboardInputData { unit_id: 0, flags: 0, .. }");
+    }
+
+    /// The arguments of the log macro invocation starting at `start`, up to its closing parenthesis.
+    fn invocation(source: &str, start: usize) -> &str {
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+        for (offset, c) in source[start..].char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_string => escaped = true,
+                '"' => in_string = !in_string,
+                '(' if !in_string => depth += 1,
+                ')' if !in_string => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return &source[start..=start + offset];
+                    }
+                }
+                _ => {}
+            }
+        }
+        &source[start..]
+    }
+
+    /// The `{…:?}` and `{…:#?}` placeholders in `text`.
+    fn debug_placeholders(text: &str) -> Vec<&str> {
+        let mut placeholders = Vec::new();
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            rest = &rest[open..];
+            let Some(close) = rest.find('}') else {
+                break;
+            };
+            let placeholder = &rest[..=close];
+            if placeholder.ends_with("?}") && !placeholder.starts_with("{{") {
+                placeholders.push(placeholder);
+            }
+            rest = &rest[close + 1..];
+        }
+        placeholders
+    }
+
+    fn visit(dir: &Path, found: &mut Vec<String>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                visit(&path, found);
+                continue;
+            }
+            if path.extension().is_none_or(|extension| extension != "rs") {
+                continue;
+            }
+
+            let source = std::fs::read_to_string(&path).unwrap();
+            let file = path.file_name().unwrap().to_str().unwrap();
+            for name in LOG_MACROS {
+                let needle = format!("{name}!(");
+                for (start, _) in source.match_indices(&needle) {
+                    for placeholder in debug_placeholders(invocation(&source, start)) {
+                        if !SAFE_DEBUG.contains(&(file, placeholder)) {
+                            found.push(format!("{}: {placeholder} in {name}!", path.display()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Types carrying keys are redacted, but a new one may not be: every `Debug` formatting in a
+    /// log has to be vetted and listed in `SAFE_DEBUG`.
+    #[test]
+    fn logs_only_debug_format_vetted_values() {
+        let mut found = Vec::new();
+        visit(&Path::new(env!("CARGO_MANIFEST_DIR")).join("src"), &mut found);
+        assert!(found.is_empty(), "Unvetted Debug formatting in logs:\n{}", found.join("\n"));
+    }
+}
@@ -0,0 +1,302 @@
+//! Structures exchanged with user mode clients through the PDO and control device IOCTLs.
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use crate::any_bit_pattern;
+use crate::foreign::KeyboardInputData;
+use crate::ring::ring_size;
+
+pub use crate::keyboard::block::BlockRule;
+pub use crate::keyboard::key_state::KEY_STATE_WORDS;
+pub use crate::ring::RingHeader;
+
+pub const LOG_TARGET_LENGTH: usize = 48;
+
+/// Input of `PdoSetLogLevel`. A target starting with a NUL byte sets the global level,
+/// otherwise the level applies to every module path starting with `target`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct LogLevelRequest {
+    pub level: u32,
+    pub target: [u8; LOG_TARGET_LENGTH],
+}
+
+/// Output of `PdoGetStatistics`. `last_input_time` is system time in 100ns units, 0 if no input was seen.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KeyboardStatistics {
+    pub strokes_seen: u64,
+    pub strokes_passed: u64,
+    pub strokes_captured: u64,
+    /// Always 0: strokes the class driver doesn't take are left to the port driver, which delivers them again.
+    pub strokes_dropped: u64,
+    pub strokes_injected: u64,
+    pub partial_consumptions: u64,
+    pub connects: u32,
+    pub disconnects: u32,
+    pub last_input_time: u64,
+    pub strokes_blocked: u64,
+    pub stalls: u64,
+    pub keys_released: u64,
+}
+
+pub const HARDWARE_ID_LENGTH: usize = 128;
+
+/// One entry of `ControlListDevices`. `hardware_id` is the keyboard's first hardware ID, NUL padded.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceEntry {
+    pub device_id: u32,
+    pub hardware_id: [u16; HARDWARE_ID_LENGTH],
+}
+
+/// Output of `ControlListDevices`, followed by `count` [`DeviceEntry`] records.
+/// `total` is the number of attached devices, which is larger than `count` when the buffer was too small.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DeviceListHeader {
+    pub total: u32,
+    pub count: u32,
+}
+
+pub const FILTER_KEY_NONE: u16 = 0x0000;
+pub const FILTER_KEY_ALL: u16 = 0xFFFF;
+pub const FILTER_KEY_DOWN: u16 = 0x0001;
+pub const FILTER_KEY_UP: u16 = 0x0002;
+pub const FILTER_KEY_E0: u16 = 0x0004;
+pub const FILTER_KEY_E1: u16 = 0x0008;
+
+/// Input of `ControlSetFilter` and output of `ControlGetFilter`.
+/// `filter` uses the Interception `FILTER_KEY_*` flags. A non-zero `target` addresses the filter of
+/// the target at index `target - 1` instead of the device's own, and `device_id` is ignored.
+/// A keyboard captures the strokes matching its own filter or that of any target it falls under.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DeviceFilter {
+    pub device_id: u32,
+    pub filter: u16,
+    pub target: u16,
+}
+
+/// A stroke together with the filter instance it was captured on or should be injected into.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceStroke {
+    pub device_id: u32,
+    pub stroke: KeyboardInputData,
+}
+
+impl DeviceStroke {
+    pub const EMPTY: Self = Self {
+        device_id: 0,
+        stroke: KeyboardInputData::EMPTY,
+    };
+}
+
+/// Output of `ControlWrite`, whose input is an array of [`DeviceStroke`], if its output buffer has room:
+/// how many records were injected. Writing stops at the driver's write limit, or at the first record
+/// the class driver doesn't take.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WriteResponse {
+    pub injected: u32,
+}
+
+/// A captured stroke with the performance counter value taken when the port driver delivered it.
+/// Convert `timestamp` to time using the frequency returned by `ControlSetReadFormat`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct TimedStroke {
+    pub data: KeyboardInputData,
+    pub timestamp: u64,
+    pub device: u32,
+    pub reserved: u32,
+}
+
+impl TimedStroke {
+    pub const EMPTY: Self = Self {
+        data: KeyboardInputData::EMPTY,
+        timestamp: 0,
+        device: 0,
+        reserved: 0,
+    };
+}
+
+impl From<TimedStroke> for DeviceStroke {
+    fn from(stroke: TimedStroke) -> Self {
+        Self {
+            device_id: stroke.device,
+            stroke: stroke.data,
+        }
+    }
+}
+
+/// Records returned by `ControlRead`, chosen per handle. New handles start with [`ReadFormat::Legacy`].
+/// A read returns as many records as fit its output buffer, up to the driver's read limit; the
+/// number returned is the length completed divided by the record size.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum ReadFormat {
+    /// [`DeviceStroke`] records.
+    Legacy = 0,
+    /// [`TimedStroke`] records.
+    Timed = 1,
+}
+
+impl ReadFormat {
+    pub const fn record_size(self) -> usize {
+        match self {
+            ReadFormat::Legacy => core::mem::size_of::<DeviceStroke>(),
+            ReadFormat::Timed => core::mem::size_of::<TimedStroke>(),
+        }
+    }
+}
+
+/// Input of `ControlSetReadFormat`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReadFormatRequest {
+    pub format: u32,
+}
+
+/// Output of `ControlSetReadFormat`, describing the format now in effect.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReadFormatResponse {
+    pub format: u32,
+    pub record_size: u32,
+    /// Ticks per second of [`TimedStroke::timestamp`].
+    pub timestamp_frequency: u64,
+}
+
+/// Most UTF-16 code units a single `ControlWriteText` types.
+pub const MAX_TEXT_LENGTH: usize = 256;
+
+/// Input of `ControlWriteText`, followed by `length` UTF-16 code units of text.
+/// `layout_id` is the keyboard layout identifier the text is typed with, e.g. `0x0409` for US.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TextInjectionHeader {
+    pub device_id: u32,
+    pub layout_id: u32,
+    pub length: u32,
+    pub reserved: u32,
+}
+
+/// Input of `ControlSetBlockRules` and output of `ControlGetBlockRules`, followed by `count` [`BlockRule`] records.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BlockRulesHeader {
+    pub count: u32,
+    pub reserved: u32,
+}
+
+pub const MAX_TARGETS: usize = 8;
+
+/// Matches no keyboard; unused table entries.
+pub const TARGET_NONE: u32 = 0;
+/// Keyboards whose first hardware ID matches the `hardware_id` glob, e.g. `HID\VID_046D&PID_C31C*`.
+pub const TARGET_HARDWARE_ID: u32 = 1;
+/// Strokes whose `UnitId` is `value`.
+pub const TARGET_UNIT_ID: u32 = 2;
+/// The keyboard whose instance, the id used by the raw PDOs and the control device, is `value`.
+pub const TARGET_INSTANCE: u32 = 3;
+
+/// One entry of the target table, as stored in the `Targets` registry value and exchanged through `ControlSetTargets`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceTarget {
+    pub kind: u32,
+    pub value: u32,
+    /// NUL terminated glob for [`TARGET_HARDWARE_ID`], where `*` matches any run of characters and `?` any one.
+    pub hardware_id: [u16; HARDWARE_ID_LENGTH],
+}
+
+impl DeviceTarget {
+    pub const EMPTY: Self = Self {
+        kind: TARGET_NONE,
+        value: 0,
+        hardware_id: [0; HARDWARE_ID_LENGTH],
+    };
+}
+
+/// Input of `ControlSetTargets` and output of `ControlGetTargets`, followed by `count` [`DeviceTarget`] records.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TargetsHeader {
+    pub count: u32,
+    pub reserved: u32,
+}
+
+/// The escape chord was typed on `device_id`: every capture filter was dropped. Block rules stay.
+pub const NOTIFICATION_ESCAPE: u32 = 1;
+/// Strokes captured on `device_id` weren't read in time: they were forwarded and the keyboard passes
+/// strokes through until its filter is set again.
+pub const NOTIFICATION_STALLED: u32 = 2;
+
+/// Output of `ControlWaitNotification`, which stays pending until something happens.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Notification {
+    pub kind: u32,
+    pub device_id: u32,
+    /// Performance counter value when it happened.
+    pub timestamp: u64,
+}
+
+/// Use the `CaptureTimeout` registry value, 1000 ms if absent.
+pub const CAPTURE_TIMEOUT_DEFAULT: u32 = 0;
+/// Keep captured strokes until they are read, however long that takes.
+pub const CAPTURE_TIMEOUT_NEVER: u32 = u32::MAX;
+
+/// Input of `ControlSetCaptureTimeout` and output of `ControlGetCaptureTimeout`: how long strokes
+/// captured on `device_id` may wait to be read, in milliseconds.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CaptureTimeout {
+    pub device_id: u32,
+    pub timeout_ms: u32,
+}
+
+/// `device_id` of `ControlGetKeyState` asking for the keys held on any keyboard.
+pub const DEVICE_ID_ALL: u32 = u32::MAX;
+
+/// Input and output of `ControlGetKeyState`. Bit `make_code + 256 * e0` of `pressed`, counting from
+/// the low bit of the first word, is set while the key is physically held. Pause uses `E0 45`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DeviceKeyState {
+    pub device_id: u32,
+    pub reserved: u32,
+    pub pressed: [u64; KEY_STATE_WORDS],
+}
+
+/// Records in each ring mapped by `ControlMapRing`.
+pub const SHARED_RING_CAPACITY: u32 = 256;
+
+/// Where the rings sit in the output buffer of `ControlMapRing`, which must be at least
+/// `SHARED_RING_SIZE` bytes long and start on a `SHARED_RING_ALIGNMENT` boundary. Each starts with a
+/// [`RingHeader`]; the capture ring holds [`TimedStroke`] records the driver produces, the injection
+/// ring [`DeviceStroke`] records the client produces and `ControlSubmitRing` injects. The injection
+/// ring starts on a cache line of its own.
+pub const SHARED_RING_ALIGNMENT: usize = 64;
+pub const SHARED_RING_CAPTURE_OFFSET: usize = 0;
+pub const SHARED_RING_INJECT_OFFSET: usize =
+    (SHARED_RING_CAPTURE_OFFSET + ring_size(SHARED_RING_CAPACITY, core::mem::size_of::<TimedStroke>())).next_multiple_of(SHARED_RING_ALIGNMENT);
+pub const SHARED_RING_SIZE: usize = SHARED_RING_INJECT_OFFSET + ring_size(SHARED_RING_CAPACITY, core::mem::size_of::<DeviceStroke>());
+
+/// Input of `ControlMapRing`: a handle to an event, opened with `EVENT_MODIFY_STATE`, which the
+/// driver sets once the rings are set up, and after writing captured strokes into the capture ring.
+/// The request stays pending while the rings are mapped. It completes with `STATUS_IO_TIMEOUT` when
+/// the client left strokes unread past their keyboard's capture timeout, and `STATUS_CANCELLED` when
+/// the escape chord was typed, the driver having forwarded the strokes it hadn't consumed.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RingMapRequest {
+    pub event: u64,
+}
+
+// Records clients send in, which are read whatever bytes they hold
+any_bit_pattern!(
+    LogLevelRequest, DeviceFilter, DeviceStroke, ReadFormatRequest, TextInjectionHeader, BlockRulesHeader, BlockRule,
+    DeviceTarget, TargetsHeader, CaptureTimeout, DeviceKeyState, RingMapRequest,
+);
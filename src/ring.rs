@@ -0,0 +1,431 @@
+//! A single-producer single-consumer ring of fixed-size records in memory shared with a client.
+//!
+//! The memory starts with a [`RingHeader`] followed by `capacity` records, a power of two. `head`
+//! counts the records the producer published and `tail` those the consumer is done with, both
+//! wrapping, so record `n` lives in slot `n % capacity` and the ring holds `head - tail` of them.
+//! The producer writes a record, then publishes it by storing `head` with release ordering. The
+//! consumer loads `head` with acquire ordering, reads the records, then hands their slots back by
+//! storing `tail` with release ordering, which the producer loads with acquire ordering before
+//! writing over them.
+//!
+//! Each side keeps its own counter to itself and only reads the other's from the header, checking
+//! it, so a misbehaving peer can garble records but never make the other side step outside the ring.
+//!
+//! Only depends on `core`, so clients can share it and its protocol can be checked on the host.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub const RING_MAGIC: u32 = u32::from_le_bytes(*b"KRng");
+pub const RING_VERSION: u32 = 1;
+pub const MAX_RING_CAPACITY: u32 = 1 << 16;
+
+/// Start of a ring. `head` and `tail` sit on cache lines of their own, as the two sides write them.
+#[repr(C)]
+#[derive(Debug)]
+pub struct RingHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub capacity: u32,
+    pub record_size: u32,
+    /// Records the producer couldn't publish as the ring was full.
+    pub overflows: AtomicU32,
+    reserved: [u32; 11],
+    pub head: AtomicU32,
+    head_padding: [u32; 15],
+    pub tail: AtomicU32,
+    tail_padding: [u32; 15],
+}
+
+pub const RING_HEADER_SIZE: usize = core::mem::size_of::<RingHeader>();
+
+/// Bytes taken by a ring of `capacity` records of `record_size` bytes.
+pub const fn ring_size(capacity: u32, record_size: usize) -> usize {
+    RING_HEADER_SIZE + capacity as usize * record_size
+}
+
+pub const fn is_valid_capacity(capacity: u32) -> bool {
+    capacity.is_power_of_two() && capacity <= MAX_RING_CAPACITY
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RingError {
+    Full,
+    Empty,
+    /// The counters can't both be right: the peer wrote garbage into the header.
+    Corrupt,
+}
+
+/// Records in the ring according to the two counters.
+pub const fn used(head: u32, tail: u32, capacity: u32) -> Result<u32, RingError> {
+    let used = head.wrapping_sub(tail);
+    if used > capacity {
+        Err(RingError::Corrupt)
+    } else {
+        Ok(used)
+    }
+}
+
+/// Slot of the record a counter value refers to.
+pub const fn slot(counter: u32, capacity: u32) -> usize {
+    (counter & (capacity - 1)) as usize
+}
+
+/// Writes the header of an empty ring of `capacity` `T` records at `memory`.
+///
+/// # Safety
+/// `memory` must be valid for writes of [`ring_size`] bytes and aligned for [`RingHeader`] and `T`.
+pub unsafe fn init<T>(memory: NonNull<u8>, capacity: u32) {
+    debug_assert!(is_valid_capacity(capacity));
+    unsafe {
+        memory.cast::<RingHeader>().write(RingHeader {
+            magic: RING_MAGIC,
+            version: RING_VERSION,
+            capacity,
+            record_size: core::mem::size_of::<T>() as u32,
+            overflows: AtomicU32::new(0),
+            reserved: [0; 11],
+            head: AtomicU32::new(0),
+            head_padding: [0; 15],
+            tail: AtomicU32::new(0),
+            tail_padding: [0; 15],
+        });
+    }
+}
+
+/// The capacity of the ring of `T` records at `memory`, if its header describes one fitting in `len` bytes.
+///
+/// # Safety
+/// `memory` must be valid for reads of `len` bytes and aligned for [`RingHeader`].
+pub unsafe fn validate<T>(memory: NonNull<u8>, len: usize) -> Option<u32> {
+    if len < RING_HEADER_SIZE {
+        return None;
+    }
+
+    let header = unsafe { memory.cast::<RingHeader>().as_ref() };
+    let valid = header.magic == RING_MAGIC
+        && header.version == RING_VERSION
+        && header.record_size as usize == core::mem::size_of::<T>()
+        && is_valid_capacity(header.capacity)
+        && ring_size(header.capacity, core::mem::size_of::<T>()) <= len;
+    valid.then_some(header.capacity)
+}
+
+#[derive(Debug)]
+struct RawRing<T> {
+    header: NonNull<RingHeader>,
+    records: NonNull<T>,
+    capacity: u32,
+}
+
+impl<T: Copy> RawRing<T> {
+    unsafe fn new(memory: NonNull<u8>, capacity: u32) -> Self {
+        Self {
+            header: memory.cast(),
+            records: unsafe { memory.add(RING_HEADER_SIZE) }.cast(),
+            capacity,
+        }
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { self.header.as_ref() }
+    }
+
+    fn read(&self, counter: u32) -> T {
+        unsafe { self.records.add(slot(counter, self.capacity)).read_volatile() }
+    }
+
+    fn write(&self, counter: u32, record: T) {
+        unsafe { self.records.add(slot(counter, self.capacity)).write_volatile(record) };
+    }
+}
+
+/// The side writing records.
+#[derive(Debug)]
+pub struct Producer<T> {
+    ring: RawRing<T>,
+    head: u32,
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+
+impl<T: Copy> Producer<T> {
+    /// # Safety
+    /// `memory` must hold a ring of `capacity` `T` records set up by [`init`], staying valid for the
+    /// producer's lifetime, and there must be no other producer. `T` must be valid for any bit pattern.
+    pub unsafe fn new(memory: NonNull<u8>, capacity: u32) -> Self {
+        let ring = unsafe { RawRing::new(memory, capacity) };
+        let head = ring.header().head.load(Ordering::Relaxed);
+        Self { ring, head }
+    }
+
+    pub fn push(&mut self, record: T) -> Result<(), RingError> {
+        let header = self.ring.header();
+        let tail = header.tail.load(Ordering::Acquire);
+        if used(self.head, tail, self.ring.capacity)? == self.ring.capacity {
+            header.overflows.fetch_add(1, Ordering::Relaxed);
+            return Err(RingError::Full);
+        }
+
+        self.ring.write(self.head, record);
+        self.head = self.head.wrapping_add(1);
+        header.head.store(self.head, Ordering::Release);
+        Ok(())
+    }
+
+    /// Counter of the next record to write.
+    pub const fn head(&self) -> u32 {
+        self.head
+    }
+
+    /// Records published and not consumed yet.
+    pub fn unconsumed(&self) -> Result<u32, RingError> {
+        used(self.head, self.ring.header().tail.load(Ordering::Acquire), self.ring.capacity)
+    }
+
+    /// Records refused so far as the ring was full.
+    pub fn overflows(&self) -> u32 {
+        self.ring.header().overflows.load(Ordering::Relaxed)
+    }
+}
+
+/// The side reading records. Records are looked at with [`Consumer::peek`] and handed back with
+/// [`Consumer::consume`], so a consumer can leave those it couldn't deal with for later.
+#[derive(Debug)]
+pub struct Consumer<T> {
+    ring: RawRing<T>,
+    tail: u32,
+    /// Records published as of the last [`Consumer::available`].
+    available: u32,
+}
+
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+impl<T: Copy> Consumer<T> {
+    /// # Safety
+    /// Same as [`Producer::new`], with no other consumer.
+    pub unsafe fn new(memory: NonNull<u8>, capacity: u32) -> Self {
+        let ring = unsafe { RawRing::new(memory, capacity) };
+        let tail = ring.header().tail.load(Ordering::Relaxed);
+        Self { ring, tail, available: 0 }
+    }
+
+    /// Records published and not consumed yet.
+    pub fn available(&mut self) -> Result<u32, RingError> {
+        let head = self.ring.header().head.load(Ordering::Acquire);
+        self.available = used(head, self.tail, self.ring.capacity)?;
+        Ok(self.available)
+    }
+
+    /// The record `index` places past the oldest, if it was available.
+    pub fn peek(&self, index: u32) -> Option<T> {
+        (index < self.available).then(|| self.ring.read(self.tail.wrapping_add(index)))
+    }
+
+    /// Hands the slots of the oldest `count` available records back to the producer.
+    pub fn consume(&mut self, count: u32) {
+        let count = count.min(self.available);
+        self.available -= count;
+        self.tail = self.tail.wrapping_add(count);
+        self.ring.header().tail.store(self.tail, Ordering::Release);
+    }
+
+    pub fn pop(&mut self) -> Result<T, RingError> {
+        if self.available()? == 0 {
+            return Err(RingError::Empty);
+        }
+
+        let record = self.ring.read(self.tail);
+        self.consume(1);
+        Ok(record)
+    }
+}
+
+const _: () = {
+    assert!(RING_HEADER_SIZE == 192);
+    assert!(core::mem::offset_of!(RingHeader, head) % 64 == 0);
+    assert!(core::mem::offset_of!(RingHeader, tail) % 64 == 0);
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+    use std::thread;
+
+    /// Memory for a ring, aligned for the header and any record up to 8 bytes.
+    struct Memory {
+        words: Vec<u64>,
+        base: NonNull<u8>,
+    }
+
+    impl Memory {
+        fn ring<T>(capacity: u32) -> Self {
+            let mut words = vec![0; ring_size(capacity, size_of::<T>()).div_ceil(8)];
+            let base = NonNull::new(words.as_mut_ptr()).unwrap().cast();
+            unsafe { init::<T>(base, capacity) };
+            Self { words, base }
+        }
+
+        const fn as_ptr(&self) -> NonNull<u8> {
+            self.base
+        }
+
+        fn len(&self) -> usize {
+            self.words.len() * 8
+        }
+
+        fn header(&self) -> &RingHeader {
+            unsafe { self.base.cast::<RingHeader>().as_ref() }
+        }
+    }
+
+    fn sides(memory: &Memory, capacity: u32) -> (Producer<u32>, Consumer<u32>) {
+        unsafe { (Producer::new(memory.as_ptr(), capacity), Consumer::new(memory.as_ptr(), capacity)) }
+    }
+
+    /// A ring whose counters are both at `start`.
+    fn ring_at(capacity: u32, start: u32) -> Memory {
+        let memory = Memory::ring::<u32>(capacity);
+        memory.header().head.store(start, Ordering::Relaxed);
+        memory.header().tail.store(start, Ordering::Relaxed);
+        memory
+    }
+
+    #[test]
+    fn counters_wrap_and_the_peer_is_checked() {
+        assert_eq!(used(2, u32::MAX - 1, 4), Ok(4));
+        assert_eq!(used(3, u32::MAX - 1, 4), Err(RingError::Corrupt));
+        assert_eq!(used(5, 9, 4), Err(RingError::Corrupt));
+        assert_eq!(slot(u32::MAX, 4), 3);
+        assert_eq!(slot(0, 4), 0);
+        assert!(is_valid_capacity(256) && is_valid_capacity(MAX_RING_CAPACITY));
+        assert!(!is_valid_capacity(0) && !is_valid_capacity(48) && !is_valid_capacity(MAX_RING_CAPACITY * 2));
+    }
+
+    #[test]
+    fn records_arrive_in_order_across_the_wrap() {
+        let memory = ring_at(4, u32::MAX - 5);
+        let (mut producer, mut consumer) = sides(&memory, 4);
+        for record in 0..20 {
+            producer.push(record).unwrap();
+            if record % 3 == 2 {
+                while consumer.pop().is_ok() {}
+            }
+        }
+
+        let mut expected = 18;
+        while let Ok(record) = consumer.pop() {
+            assert_eq!(record, expected);
+            expected += 1;
+        }
+        assert_eq!(expected, 20);
+    }
+
+    #[test]
+    fn a_full_ring_refuses_and_counts_records() {
+        let memory = Memory::ring::<u32>(2);
+        let (mut producer, mut consumer) = sides(&memory, 2);
+        assert_eq!(consumer.pop(), Err(RingError::Empty));
+        producer.push(1).unwrap();
+        producer.push(2).unwrap();
+        assert_eq!(producer.push(3), Err(RingError::Full));
+        assert_eq!(producer.push(4), Err(RingError::Full));
+        assert_eq!(producer.overflows(), 2);
+
+        assert_eq!(producer.unconsumed(), Ok(2));
+        assert_eq!(consumer.pop(), Ok(1));
+        assert_eq!(producer.unconsumed(), Ok(1));
+        producer.push(5).unwrap();
+        assert_eq!(producer.head(), 3);
+        assert_eq!(consumer.pop(), Ok(2));
+        assert_eq!(consumer.pop(), Ok(5));
+        assert_eq!(consumer.pop(), Err(RingError::Empty));
+    }
+
+    #[test]
+    fn peeked_records_stay_until_consumed() {
+        let memory = Memory::ring::<u32>(4);
+        let (mut producer, mut consumer) = sides(&memory, 4);
+        for record in 10..13 {
+            producer.push(record).unwrap();
+        }
+
+        // Nothing is visible before `available`
+        assert_eq!(consumer.peek(0), None);
+        assert_eq!(consumer.available(), Ok(3));
+        assert_eq!((consumer.peek(0), consumer.peek(2), consumer.peek(3)), (Some(10), Some(12), None));
+
+        consumer.consume(2);
+        assert_eq!(memory.header().tail.load(Ordering::Relaxed), 2);
+        assert_eq!((consumer.peek(0), consumer.peek(1)), (Some(12), None));
+        // Consuming more than was available hands back only those
+        consumer.consume(5);
+        assert_eq!(memory.header().tail.load(Ordering::Relaxed), 3);
+        assert_eq!(consumer.available(), Ok(0));
+    }
+
+    #[test]
+    fn garbage_counters_are_reported_not_followed() {
+        let memory = Memory::ring::<u32>(4);
+        let (mut producer, mut consumer) = sides(&memory, 4);
+
+        memory.header().head.store(9, Ordering::Relaxed);
+        assert_eq!(consumer.available(), Err(RingError::Corrupt));
+        assert_eq!(consumer.pop(), Err(RingError::Corrupt));
+
+        memory.header().tail.store(7, Ordering::Relaxed);
+        assert_eq!(producer.push(1), Err(RingError::Corrupt));
+        assert_eq!(producer.unconsumed(), Err(RingError::Corrupt));
+        assert_eq!(producer.overflows(), 0);
+    }
+
+    #[test]
+    fn validate_checks_the_header_against_the_memory() {
+        let memory = Memory::ring::<u32>(8);
+        assert_eq!(unsafe { validate::<u32>(memory.as_ptr(), memory.len()) }, Some(8));
+        // Too short for the records, or even the header
+        assert_eq!(unsafe { validate::<u32>(memory.as_ptr(), ring_size(8, 4) - 1) }, None);
+        assert_eq!(unsafe { validate::<u32>(memory.as_ptr(), RING_HEADER_SIZE - 1) }, None);
+        // Records of another size
+        assert_eq!(unsafe { validate::<u64>(memory.as_ptr(), memory.len()) }, None);
+
+        let memory = Memory::ring::<u32>(8);
+        unsafe { (*memory.as_ptr().cast::<RingHeader>().as_ptr()).capacity = 6 };
+        assert_eq!(unsafe { validate::<u32>(memory.as_ptr(), memory.len()) }, None);
+    }
+
+    #[test]
+    fn threads_exchange_every_record_in_order() {
+        const RECORDS: u64 = 200_000;
+
+        let memory = Memory::ring::<u64>(16);
+        let (mut producer, mut consumer) =
+            unsafe { (Producer::<u64>::new(memory.as_ptr(), 16), Consumer::<u64>::new(memory.as_ptr(), 16)) };
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                let mut record = 0;
+                while record < RECORDS {
+                    match producer.push(record) {
+                        Ok(()) => record += 1,
+                        Err(RingError::Full) => thread::yield_now(),
+                        Err(error) => panic!("{error:?}"),
+                    }
+                }
+            });
+
+            let mut expected = 0;
+            while expected < RECORDS {
+                match consumer.pop() {
+                    Ok(record) => {
+                        assert_eq!(record, expected);
+                        expected += 1;
+                    }
+                    Err(RingError::Empty) => thread::yield_now(),
+                    Err(error) => panic!("{error:?}"),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,256 @@
+//! Captured and injected strokes exchanged through rings in memory shared with one client.
+//!
+//! `ControlMapRing` is a direct I/O request whose output buffer holds a capture ring and an
+//! injection ring, laid out as in [`crate::ring`] at the offsets in [`crate::protocol`]. The driver
+//! sets the rings up and keeps the request pending while they are in use, so the I/O manager keeps
+//! the client's pages locked and the driver reaches them through their system address. While they
+//! are mapped, captured strokes are written into the capture ring instead of the capture queue, and
+//! passed through when it's full, after which the client's event is set. `ControlSubmitRing` injects
+//! the records the client wrote into the injection ring, up to the write limit. One handle at a
+//! time maps the rings. They go, completing the request, when it's cancelled or the handle closed.
+//!
+//! The watchdog takes the rings away from a client that leaves a keyboard's strokes unread past its
+//! deadline, and the escape chord from any client. What the client hadn't consumed is then handed to
+//! the class drivers, from the driver's own copy kept by [`CaptureRing`], and the request completed
+//! with an error status, after which captured strokes go to the capture queue again.
+//!
+//! The event handle can only be looked up in the client's process, so `ControlMapRing` reserves the
+//! rings in the caller's context and maps them once the request reaches the queue.
+//!
+//! Lock order: `SHARED_RING` may be held while taking `ATTACHED`, never the other way around.
+
+use core::ptr::NonNull;
+use wdk_sys::{
+    NTSTATUS, STATUS_CANCELLED, STATUS_DATA_ERROR, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_DEVICE_REQUEST, STATUS_INVALID_DEVICE_STATE,
+    STATUS_SHARING_VIOLATION, STATUS_SUCCESS, WDFFILEOBJECT, WDFREQUEST,
+};
+use crate::{batching, control, log_debug, log_info, log_warn};
+use crate::capture_ring::CaptureRing;
+use crate::device::forward_captured;
+use crate::framework::{Error, ErrorCode, Request, Result};
+use crate::framework::event::UserEvent;
+use crate::framework::spin_lock::SpinLock;
+use crate::protocol::{
+    DeviceStroke, RingMapRequest, SHARED_RING_ALIGNMENT, SHARED_RING_CAPACITY, SHARED_RING_CAPTURE_OFFSET, SHARED_RING_INJECT_OFFSET,
+    SHARED_RING_SIZE, TimedStroke, WriteResponse,
+};
+use crate::ring::{self, Consumer};
+
+const _: () = assert!(ring::is_valid_capacity(SHARED_RING_CAPACITY));
+
+/// The two sides the driver takes, over the client's buffer.
+struct Rings {
+    capture: CaptureRing,
+    inject: Consumer<DeviceStroke>,
+}
+
+impl Rings {
+    /// # Safety
+    /// `base` must be aligned on `SHARED_RING_ALIGNMENT` and valid for `SHARED_RING_SIZE` bytes
+    /// for as long as the rings are used.
+    unsafe fn new(base: NonNull<u8>) -> Result<Self> {
+        unsafe {
+            let capture_memory = base.add(SHARED_RING_CAPTURE_OFFSET);
+            let inject_memory = base.add(SHARED_RING_INJECT_OFFSET);
+            ring::init::<TimedStroke>(capture_memory, SHARED_RING_CAPACITY);
+            ring::init::<DeviceStroke>(inject_memory, SHARED_RING_CAPACITY);
+            Ok(Self {
+                capture: CaptureRing::new(capture_memory)
+                    .ok_or_else(|| Error::from_nt_status(STATUS_INSUFFICIENT_RESOURCES, ErrorCode::RingAllocationFailed))?,
+                inject: Consumer::new(inject_memory, SHARED_RING_CAPACITY),
+            })
+        }
+    }
+}
+
+struct SharedRing {
+    event: UserEvent,
+    /// The handle that mapped the rings.
+    owner: WDFFILEOBJECT,
+    /// The pending `ControlMapRing`, whose output buffer holds the rings.
+    request: WDFREQUEST,
+    /// `None` until the request reached the queue.
+    rings: Option<Rings>,
+}
+
+// The owner is only compared against, the request only completed
+unsafe impl Send for SharedRing {}
+
+static SHARED_RING: SpinLock<Option<SharedRing>> = SpinLock::new(None);
+
+/// Writes a captured stroke into the capture ring. `None` when no ring is mapped, `Some(false)` when
+/// the client fell behind and the stroke should be passed through.
+pub(crate) fn push(stroke: TimedStroke) -> Option<bool> {
+    let mut shared = SHARED_RING.lock();
+    let rings = shared.as_mut()?.rings.as_mut()?;
+    Some(rings.capture.push(stroke).is_ok())
+}
+
+/// When the oldest stroke of `device_id` the client hasn't consumed from the capture ring was captured.
+pub(crate) fn oldest_timestamp(device_id: u32) -> Option<u64> {
+    SHARED_RING.lock().as_ref()?.rings.as_ref()?.capture.oldest_timestamp(device_id)
+}
+
+/// Tells the client there are captured strokes to read.
+pub(crate) fn signal() {
+    if let Some(ring) = SHARED_RING.lock().as_ref().filter(|ring| ring.rings.is_some()) {
+        ring.event.set();
+    }
+}
+
+fn already_mapped() -> Error {
+    Error::from_nt_status(STATUS_SHARING_VIOLATION, ErrorCode::RingAlreadyMapped)
+}
+
+fn not_mapped() -> Error {
+    Error::from_nt_status(STATUS_INVALID_DEVICE_STATE, ErrorCode::RingNotMapped)
+}
+
+/// Reserves the rings for `request`, looking up the client's event. Runs in the client's context.
+/// A reservation of the same handle that never got mapped, as its request was cancelled in the
+/// queue, is replaced.
+pub(crate) fn reserve(request: &mut Request) -> Result<()> {
+    let RingMapRequest { event } = request.read_input::<RingMapRequest>()?;
+    // Rings are torn down along with the handle that mapped them
+    let owner = request.file_object()
+        .map(|file_object| file_object.handle())
+        .ok_or_else(|| Error::from_nt_status(STATUS_INVALID_DEVICE_REQUEST, ErrorCode::RingNotMapped))?;
+    let event = UserEvent::from_handle(event)?;
+
+    let ring = SharedRing { event, owner, request: request.handle(), rings: None };
+    let previous = {
+        let mut shared = SHARED_RING.lock();
+        if shared.as_ref().is_some_and(|reserved| reserved.owner != owner || reserved.rings.is_some()) {
+            return Err(already_mapped());
+        }
+        shared.replace(ring)
+    };
+    // Dereferences the stale event outside the lock
+    drop(previous);
+    Ok(())
+}
+
+/// Sets up the rings reserved for `request` in its output buffer and leaves the request pending.
+/// On failure the reservation goes, and the caller completes the request.
+pub(crate) fn map(request: &mut Request) -> Result<()> {
+    let handle = request.handle();
+    let res = request.output_direct_buffer().and_then(|(base, len)| {
+        if len < SHARED_RING_SIZE {
+            return Err(Error::buffer_too_small(ErrorCode::IoctlOutputTooSmall, SHARED_RING_SIZE, len));
+        }
+        if base.as_ptr() as usize % SHARED_RING_ALIGNMENT != 0 {
+            return Err(Error::invalid_buffer(ErrorCode::RingBufferMisaligned));
+        }
+
+        // Allocates the copy of the capture ring, so before taking the lock
+        let rings = unsafe { Rings::new(base) }?;
+        let mut shared = SHARED_RING.lock();
+        let ring = shared.as_mut()
+            .filter(|ring| ring.request == handle && ring.rings.is_none())
+            .ok_or_else(not_mapped)?;
+        // Once marked, the request may be cancelled, which needs the lock to find the rings
+        request.mark_cancelable(Some(on_map_cancelled))?;
+        ring.rings = Some(rings);
+        ring.event.set();
+        Ok(())
+    });
+
+    if res.is_err() {
+        let stale = {
+            let mut shared = SHARED_RING.lock();
+            if shared.as_ref().is_some_and(|ring| ring.request == handle) { shared.take() } else { None }
+        };
+        drop(stale);
+    } else {
+        log_info!("Shared rings mapped");
+    }
+    res
+}
+
+/// Injects the records waiting in the injection ring, up to the write limit, handing their slots
+/// back to the client. How many were injected is returned in the output buffer, if there's room for it.
+pub(crate) fn submit(request: &mut Request) -> Result<usize> {
+    let sender = request.file_object().map(|file_object| file_object.handle());
+    let mut shared = SHARED_RING.lock();
+    let rings = shared.as_mut()
+        .filter(|ring| Some(ring.owner) == sender)
+        .and_then(|ring| ring.rings.as_mut())
+        .ok_or_else(not_mapped)?;
+
+    let available = rings.inject.available()
+        .map_err(|_| Error::from_nt_status(STATUS_DATA_ERROR, ErrorCode::RingCorrupt))?;
+    let count = (available as usize).min(batching::write_limit());
+    let inject = &rings.inject;
+    let injected = control::inject_records(count, |index| inject.peek(index as u32))?;
+    rings.inject.consume(injected as u32);
+    drop(shared);
+
+    Ok(request.write_output(&WriteResponse { injected: injected as u32 }).unwrap_or(0))
+}
+
+/// Drops the rings if the pending `ControlMapRing` is cancelled. The request is completed whether
+/// or not it still held them, as `unmap` may have taken them meanwhile.
+extern "C" fn on_map_cancelled(request: WDFREQUEST) {
+    let ring = {
+        let mut shared = SHARED_RING.lock();
+        if shared.as_ref().is_some_and(|ring| ring.request == request) { shared.take() } else { None }
+    };
+    // Nothing writes into the buffer anymore, so the I/O manager may unlock it
+    if let Some(ring) = ring {
+        log_ring_closed(&ring, "cancelled");
+    }
+
+    let mut request = Request::new(unsafe { request.as_mut().expect("Request is null") });
+    request.complete(STATUS_CANCELLED);
+}
+
+/// Drops the rings and completes their request if `file_object` mapped them.
+pub(crate) fn unmap(file_object: WDFFILEOBJECT) {
+    let ring = {
+        let mut shared = SHARED_RING.lock();
+        if shared.as_ref().is_some_and(|ring| ring.owner == file_object) { shared.take() } else { None }
+    };
+    let Some(ring) = ring else {
+        return;
+    };
+
+    // A reservation's request hasn't reached `map` yet, which fails it
+    if ring.rings.is_some() {
+        // Before the buffer may be unlocked
+        log_ring_closed(&ring, "unmapped");
+        let mut request = Request::new(unsafe { ring.request.as_mut().expect("Request is null") });
+        if request.unmark_cancelable() {
+            request.complete(STATUS_SUCCESS);
+        }
+    }
+}
+
+/// Takes the rings away from their client, whichever handle mapped them, completing the request with
+/// `status` and handing the strokes the client hadn't consumed to their class drivers. Returns how
+/// many of those were consumed.
+pub(crate) fn revoke(status: NTSTATUS, how: &str) -> usize {
+    let ring = {
+        let mut shared = SHARED_RING.lock();
+        if shared.as_ref().is_some_and(|ring| ring.rings.is_some()) { shared.take() } else { None }
+    };
+    let Some(ring) = ring else {
+        return 0;
+    };
+
+    // The ring's tail is read before the buffer may be unlocked
+    log_ring_closed(&ring, how);
+    let capture = &ring.rings.as_ref().expect("Rings are mapped").capture;
+    let forwarded = capture.unread().filter(|stroke| forward_captured(stroke)).count();
+    log_warn!("Forwarded {forwarded} captured strokes the shared ring client hadn't read");
+
+    let mut request = Request::new(unsafe { ring.request.as_mut().expect("Request is null") });
+    if request.unmark_cancelable() {
+        request.complete(status);
+    }
+    forwarded
+}
+
+fn log_ring_closed(ring: &SharedRing, how: &str) {
+    let overflows = ring.rings.as_ref().map_or(0, |rings| rings.capture.overflows());
+    log_debug!("Shared rings {how}, {overflows} captured strokes passed through while the capture ring was full");
+}
@@ -0,0 +1,98 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::protocol::KeyboardStatistics;
+
+/// Per-device counters, updated from `service_callback` at `DISPATCH_LEVEL` without locking.
+#[derive(Debug, Default)]
+pub struct DeviceStatistics {
+    strokes_seen: AtomicU64,
+    strokes_passed: AtomicU64,
+    strokes_captured: AtomicU64,
+    strokes_dropped: AtomicU64,
+    strokes_injected: AtomicU64,
+    partial_consumptions: AtomicU64,
+    connects: AtomicU32,
+    disconnects: AtomicU32,
+    last_input_time: AtomicU64,
+    strokes_blocked: AtomicU64,
+    stalls: AtomicU64,
+    keys_released: AtomicU64,
+}
+
+impl DeviceStatistics {
+    pub fn on_input(&self, strokes: usize, time: u64) {
+        self.strokes_seen.fetch_add(strokes as u64, Ordering::Relaxed);
+        self.last_input_time.store(time, Ordering::Relaxed);
+    }
+
+    pub fn on_passed(&self, strokes: usize) {
+        self.strokes_passed.fetch_add(strokes as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_captured(&self, strokes: usize) {
+        self.strokes_captured.fetch_add(strokes as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_blocked(&self, strokes: usize) {
+        self.strokes_blocked.fetch_add(strokes as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_stall(&self) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_injected(&self, strokes: usize) {
+        self.strokes_injected.fetch_add(strokes as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_keys_released(&self, keys: usize) {
+        self.keys_released.fetch_add(keys as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_partial_consumption(&self) {
+        self.partial_consumptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_connect(&self) {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_disconnect(&self) {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> KeyboardStatistics {
+        KeyboardStatistics {
+            strokes_seen: self.strokes_seen.load(Ordering::Relaxed),
+            strokes_passed: self.strokes_passed.load(Ordering::Relaxed),
+            strokes_captured: self.strokes_captured.load(Ordering::Relaxed),
+            strokes_dropped: self.strokes_dropped.load(Ordering::Relaxed),
+            strokes_injected: self.strokes_injected.load(Ordering::Relaxed),
+            partial_consumptions: self.partial_consumptions.load(Ordering::Relaxed),
+            connects: self.connects.load(Ordering::Relaxed),
+            disconnects: self.disconnects.load(Ordering::Relaxed),
+            last_input_time: self.last_input_time.load(Ordering::Relaxed),
+            strokes_blocked: self.strokes_blocked.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+            keys_released: self.keys_released.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in [
+            &self.strokes_seen,
+            &self.strokes_passed,
+            &self.strokes_captured,
+            &self.strokes_dropped,
+            &self.strokes_injected,
+            &self.partial_consumptions,
+            &self.last_input_time,
+            &self.strokes_blocked,
+            &self.stalls,
+            &self.keys_released,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.connects.store(0, Ordering::Relaxed);
+        self.disconnects.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,262 @@
+//! Targets restricting block rules and capture filters to some keyboards.
+//!
+//! Targets live in a small table, loaded from the `Targets` registry value at start and replaced
+//! through `ControlSetTargets`. Block rules and capture filters refer to an entry by its index plus
+//! one, 0 standing for every keyboard. A target picks keyboards by hardware ID glob, by the
+//! `UnitId` of their strokes or by instance, the id the raw PDOs and the control device use.
+
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU16, Ordering};
+use nt_string::nt_unicode_str;
+use wdk_sys::{WDFDRIVER, WDFFILEOBJECT__};
+use crate::{DeviceContext, log_debug, log_error, log_info};
+use crate::framework::registry::RegistryKey;
+use crate::framework::spin_lock::SpinLock;
+use crate::framework::utils::read_from_buffer;
+use crate::protocol::{DeviceTarget, FILTER_KEY_NONE, MAX_TARGETS, TARGET_HARDWARE_ID, TARGET_INSTANCE, TARGET_UNIT_ID};
+
+/// Whether `text` matches `pattern`, where `*` matches any run of code units and `?` any single one.
+/// ASCII letters match regardless of case, like hardware IDs compare. Both stop at the first NUL.
+pub fn glob_matches(pattern: &[u16], text: &[u16]) -> bool {
+    let pattern_len = nul_terminated_len(pattern);
+    let text_len = nul_terminated_len(text);
+
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*`: the pattern after it, and the text it consumed up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text_len {
+        if p < pattern_len && pattern[p] == '*' as u16 {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if p < pattern_len && (pattern[p] == '?' as u16 || ascii_eq_ignore_case(pattern[p], text[t])) {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    while p < pattern_len && pattern[p] == '*' as u16 {
+        p += 1;
+    }
+    p == pattern_len
+}
+
+fn nul_terminated_len(s: &[u16]) -> usize {
+    s.iter().position(|&c| c == 0).unwrap_or(s.len())
+}
+
+fn ascii_eq_ignore_case(a: u16, b: u16) -> bool {
+    let lower = |c: u16| if (u16::from(b'A')..=u16::from(b'Z')).contains(&c) { c + 0x20 } else { c };
+    lower(a) == lower(b)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Targets {
+    targets: [DeviceTarget; MAX_TARGETS],
+    len: usize,
+}
+
+impl Targets {
+    const fn new() -> Self {
+        Self {
+            targets: [DeviceTarget::EMPTY; MAX_TARGETS],
+            len: 0,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[DeviceTarget] {
+        &self.targets[..self.len]
+    }
+
+    /// Returns `false`, leaving the targets unchanged, if there are more than [`MAX_TARGETS`].
+    pub fn set(&mut self, targets: &[DeviceTarget]) -> bool {
+        if targets.len() > MAX_TARGETS {
+            return false;
+        }
+
+        self.targets[..targets.len()].copy_from_slice(targets);
+        self.len = targets.len();
+        true
+    }
+}
+
+pub static TARGETS: SpinLock<Targets> = SpinLock::new(Targets::new());
+
+/// Capture filter per target, set through `ControlSetFilter` with a non-zero `target`.
+pub static TARGET_FILTERS: [AtomicU16; MAX_TARGETS] = [const { AtomicU16::new(FILTER_KEY_NONE) }; MAX_TARGETS];
+
+/// The handle that set each target filter; closing it resets the filter.
+pub static TARGET_FILTER_OWNERS: [AtomicPtr<WDFFILEOBJECT__>; MAX_TARGETS] = [const { AtomicPtr::new(null_mut()) }; MAX_TARGETS];
+
+pub fn any_target_filter() -> bool {
+    TARGET_FILTERS.iter().any(|filter| filter.load(Ordering::Relaxed) != FILTER_KEY_NONE)
+}
+
+/// The targets a keyboard falls under, one bit per table index. Unit id targets depend on the stroke.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TargetMatch {
+    device: u32,
+    unit_ids: [Option<u16>; MAX_TARGETS],
+}
+
+impl TargetMatch {
+    /// Evaluates the current targets against a keyboard; done once per batch.
+    pub fn for_device(context: &DeviceContext) -> Self {
+        let mut target_match = Self::default();
+        for (i, target) in TARGETS.lock().as_slice().iter().enumerate() {
+            match target.kind {
+                TARGET_HARDWARE_ID if glob_matches(&target.hardware_id, &context.hardware_id) => target_match.device |= 1 << i,
+                TARGET_INSTANCE if target.value == context.instance => target_match.device |= 1 << i,
+                TARGET_UNIT_ID => target_match.unit_ids[i] = u16::try_from(target.value).ok(),
+                _ => {}
+            }
+        }
+        target_match
+    }
+
+    /// Bit `i` is set when the stroke falls under target `i`.
+    pub fn mask(&self, unit_id: u16) -> u32 {
+        self.unit_ids.iter().enumerate()
+            .filter(|(_, target)| **target == Some(unit_id))
+            .fold(self.device, |mask, (i, _)| mask | 1 << i)
+    }
+
+    /// Whether some stroke of the keyboard may fall under one of the targets in `mask`.
+    pub fn covers(&self, mask: u32) -> bool {
+        self.unit_ids.iter().enumerate()
+            .filter(|(_, target)| target.is_some())
+            .fold(self.device, |covered, (i, _)| covered | 1 << i) & mask != 0
+    }
+
+    /// The capture filters of the targets in `mask`.
+    pub fn filter(mask: u32) -> u16 {
+        TARGET_FILTERS.iter().enumerate()
+            .filter(|(i, _)| mask & 1 << i != 0)
+            .fold(FILTER_KEY_NONE, |filter, (_, target_filter)| filter | target_filter.load(Ordering::Relaxed))
+    }
+}
+
+/// Applies the `Targets` `REG_BINARY` value from the driver's `Parameters` key, an array of [`DeviceTarget`], if present.
+pub(crate) fn load_from_registry(driver: WDFDRIVER) {
+    let mut buffer = [0u8; MAX_TARGETS * core::mem::size_of::<DeviceTarget>()];
+    let length = RegistryKey::open_driver_parameters_for_read(driver)
+        .and_then(|key| key.query_binary(nt_unicode_str!("Targets"), &mut buffer));
+
+    let length = match length {
+        Ok(length) if length % core::mem::size_of::<DeviceTarget>() == 0 => length,
+        Ok(_) => {
+            log_error!("Ignoring Targets registry value of invalid size");
+            return;
+        }
+        Err(_) => {
+            log_debug!("No Targets registry value");
+            return;
+        }
+    };
+
+    let mut targets = [DeviceTarget::EMPTY; MAX_TARGETS];
+    let count = length / core::mem::size_of::<DeviceTarget>();
+    for (i, target) in targets[..count].iter_mut().enumerate() {
+        *target = read_from_buffer(&buffer, i * core::mem::size_of::<DeviceTarget>()).unwrap_or(DeviceTarget::EMPTY);
+    }
+
+    TARGETS.lock().set(&targets[..count]);
+    log_info!("Loaded {count} targets from the registry");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `s` as UTF-16, NUL padded to `N` code units like the hardware IDs in device contexts.
+    fn utf16<const N: usize>(s: &str) -> [u16; N] {
+        let mut out = [0u16; N];
+        for (out, unit) in out.iter_mut().zip(s.encode_utf16()) {
+            *out = unit;
+        }
+        out
+    }
+
+    const ID: &str = "HID\\VID_046D&PID_C31C&REV_6400&MI_00";
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_matches(&utf16::<64>(pattern), &utf16::<64>(text))
+    }
+
+    #[test]
+    fn literal_and_case_insensitive_matches() {
+        assert!(matches(ID, ID));
+        assert!(matches("hid\\vid_046d&pid_c31c&rev_6400&mi_00", ID));
+        assert!(!matches("HID\\VID_046D", ID));
+        assert!(!matches(ID, "HID\\VID_046D"));
+        // Only ASCII letters fold
+        assert!(!matches("É", "é"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("HID\\VID_046D&PID_C31C*", ID));
+        assert!(matches("*&MI_0?", ID));
+        assert!(matches("HID\\*PID_C31C*MI_00", ID));
+        assert!(matches("*", ID));
+        assert!(matches("**", ID));
+        assert!(matches("*00", ID));
+        assert!(matches("HID\\VID_????&PID_C31C*", ID));
+        assert!(!matches("HID\\VID_046D&PID_C31D*", ID));
+        assert!(!matches("*&MI_0??", ID));
+        assert!(!matches("*01", ID));
+    }
+
+    #[test]
+    fn stars_backtrack() {
+        // The first `_00` doesn't end the text, the star must take more
+        assert!(matches("*_00", "A_00B_00"));
+        assert!(matches("A*B*C", "AxxBxxBxxC"));
+        assert!(!matches("A*B*C", "AxxCxxB"));
+    }
+
+    #[test]
+    fn empty_patterns_and_texts() {
+        assert!(!matches("", ID));
+        assert!(matches("", ""));
+        assert!(matches("*", ""));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn matching_stops_at_nul() {
+        assert!(glob_matches(&utf16::<8>("AB"), &['A' as u16, 'B' as u16, 0, 'C' as u16]));
+        assert!(glob_matches(&['A' as u16, 0, 'B' as u16], &utf16::<4>("A")));
+        // Without a NUL the whole slice counts
+        assert!(glob_matches(&['A' as u16, '*' as u16], &['A' as u16, 'B' as u16, 'C' as u16]));
+    }
+
+    #[test]
+    fn unit_id_targets_depend_on_the_stroke() {
+        let mut target_match = TargetMatch { device: 0b001, ..TargetMatch::default() };
+        target_match.unit_ids[2] = Some(1);
+
+        assert_eq!(target_match.mask(0), 0b001);
+        assert_eq!(target_match.mask(1), 0b101);
+        assert!(target_match.covers(0b100));
+        assert!(target_match.covers(0b001));
+        assert!(!target_match.covers(0b010));
+        assert!(!TargetMatch::default().covers(u32::MAX));
+    }
+
+    #[test]
+    fn setting_too_many_targets_changes_nothing() {
+        let mut targets = Targets::new();
+        assert!(targets.set(&[DeviceTarget::EMPTY; 2]));
+        assert!(!targets.set(&[DeviceTarget::EMPTY; MAX_TARGETS + 1]));
+        assert_eq!(targets.as_slice().len(), 2);
+        assert!(targets.set(&[]));
+        assert!(targets.as_slice().is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+//! Keeps a capturing client that stopped reading from freezing its keyboards.
+//!
+//! Each keyboard has a deadline for captured strokes, the `CaptureTimeout` registry value unless
+//! set through `ControlSetCaptureTimeout`. A periodic timer checks the oldest stroke waiting from
+//! each keyboard, in the capture queue or unread in the shared capture ring; once one is overdue,
+//! the keyboard is marked stalled, everything it has waiting is handed to its class driver unchanged
+//! and clients waiting for notifications are told. An overdue stroke in the shared ring also takes
+//! the rings away from their client. A stalled keyboard passes its strokes through until a client
+//! sets its filter again.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use nt_string::nt_unicode_str;
+use wdk_sys::{STATUS_IO_TIMEOUT, WDFDRIVER, WDFTIMER};
+use crate::{control, log_debug, log_info, log_warn, shared_ring};
+use crate::capture::CAPTURED;
+use crate::device::forward_captured;
+use crate::framework::registry::RegistryKey;
+use crate::framework::time::{Clock, PerformanceCounterClock};
+use crate::instances::{ATTACHED, MAX_INSTANCES};
+use crate::protocol::{CAPTURE_TIMEOUT_DEFAULT, CAPTURE_TIMEOUT_NEVER, NOTIFICATION_STALLED};
+
+/// How often the timer checks the deadlines.
+pub const CHECK_PERIOD_MS: u32 = 50;
+
+static DEFAULT_TIMEOUT_MS: AtomicU32 = AtomicU32::new(1000);
+
+/// Whether a stroke captured at `captured_at` is overdue at `now`, both in ticks of `frequency` per second.
+/// A stroke timestamped after `now`, by a processor racing with the check, isn't.
+pub const fn is_overdue(captured_at: u64, now: u64, frequency: u64, timeout_ms: u32) -> bool {
+    if timeout_ms == CAPTURE_TIMEOUT_NEVER {
+        return false;
+    }
+
+    let timeout_ticks = frequency as u128 * timeout_ms as u128 / 1000;
+    now.saturating_sub(captured_at) as u128 >= timeout_ticks
+}
+
+/// A keyboard's own timeout, or the default if it has none.
+pub fn effective_timeout(timeout_ms: u32) -> u32 {
+    if timeout_ms == CAPTURE_TIMEOUT_DEFAULT {
+        DEFAULT_TIMEOUT_MS.load(Ordering::Relaxed)
+    } else {
+        timeout_ms
+    }
+}
+
+pub struct Watchdog<C: Clock> {
+    clock: C,
+}
+
+impl<C: Clock> Watchdog<C> {
+    pub const fn new(clock: C) -> Self {
+        Self { clock }
+    }
+
+    /// Stalls every keyboard whose oldest captured stroke is overdue, returning how many were.
+    pub fn check(&self) -> usize {
+        let mut deadlines = [(0u32, 0u32); MAX_INSTANCES];
+        let mut count = 0;
+        {
+            let attached = ATTACHED.lock();
+            for instance in attached.iter() {
+                let timeout = attached.with_device(instance.id, |device| device.context().capture_timeout.load(Ordering::Relaxed));
+                deadlines[count] = (instance.id, effective_timeout(timeout.unwrap_or(CAPTURE_TIMEOUT_DEFAULT)));
+                count += 1;
+            }
+        }
+
+        let now = self.clock.now();
+        let frequency = self.clock.frequency();
+        let mut stalled = 0;
+        for &(device_id, timeout_ms) in &deadlines[..count] {
+            let overdue = |oldest: Option<u64>| oldest.is_some_and(|captured_at| is_overdue(captured_at, now, frequency, timeout_ms));
+            let queued = overdue(CAPTURED.lock().oldest_timestamp(device_id));
+            let shared = overdue(shared_ring::oldest_timestamp(device_id));
+            if queued || shared {
+                stall(device_id, shared);
+                stalled += 1;
+            }
+        }
+        stalled
+    }
+}
+
+/// Marks a keyboard stalled and hands everything it has waiting to its class driver, taking the
+/// shared rings away from their client if `revoke_ring`.
+fn stall(device_id: u32, revoke_ring: bool) {
+    // Marked first, so strokes arriving meanwhile pass through instead of queueing up behind
+    ATTACHED.lock().with_device(device_id, |device| {
+        device.context().stalled.store(true, Ordering::Relaxed);
+        device.context().statistics.on_stall();
+    });
+
+    let mut forwarded = 0;
+    loop {
+        let stroke = CAPTURED.lock().pop_device(device_id);
+        let Some(stroke) = stroke else {
+            break;
+        };
+        forwarded += usize::from(forward_captured(&stroke));
+    }
+    if revoke_ring {
+        shared_ring::revoke(STATUS_IO_TIMEOUT, "revoked from a client that stopped reading");
+    }
+
+    log_warn!("Captured strokes of device {device_id} weren't read in time, forwarded {forwarded} and passing through");
+    control::notify(NOTIFICATION_STALLED, device_id);
+}
+
+pub(crate) extern "C" fn on_timer(_timer: WDFTIMER) {
+    Watchdog::new(PerformanceCounterClock).check();
+}
+
+/// Applies the `CaptureTimeout` value from the driver's `Parameters` key, in milliseconds, if present.
+pub(crate) fn load_from_registry(driver: WDFDRIVER) {
+    let timeout = RegistryKey::open_driver_parameters_for_read(driver)
+        .and_then(|key| key.query_u32(nt_unicode_str!("CaptureTimeout")));
+
+    match timeout {
+        Ok(CAPTURE_TIMEOUT_DEFAULT) | Err(_) => log_debug!("No CaptureTimeout registry value, keeping the default"),
+        Ok(timeout) => {
+            DEFAULT_TIMEOUT_MS.store(timeout, Ordering::Relaxed);
+            log_info!("Capture timeout set to {timeout} ms from the registry");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FREQUENCY: u64 = 10_000_000;
+
+    #[test]
+    fn overdue_once_the_timeout_elapsed() {
+        assert!(!is_overdue(0, 9_999_999, FREQUENCY, 1000));
+        assert!(is_overdue(0, 10_000_000, FREQUENCY, 1000));
+        assert!(is_overdue(0, 20_000_000, FREQUENCY, 1000));
+        assert!(is_overdue(5, 5 + FREQUENCY / 20, FREQUENCY, CHECK_PERIOD_MS));
+        assert!(!is_overdue(5, 4 + FREQUENCY / 20, FREQUENCY, CHECK_PERIOD_MS));
+    }
+
+    #[test]
+    fn never_overdue_without_a_timeout() {
+        assert!(!is_overdue(0, u64::MAX, FREQUENCY, CAPTURE_TIMEOUT_NEVER));
+        assert!(!is_overdue(0, u64::MAX, u64::MAX, CAPTURE_TIMEOUT_NEVER));
+    }
+
+    #[test]
+    fn strokes_captured_after_the_clock_was_read_are_not_overdue() {
+        assert!(!is_overdue(100, 50, FREQUENCY, 1));
+        assert!(!is_overdue(u64::MAX, 0, FREQUENCY, 1));
+    }
+
+    #[test]
+    fn long_timeouts_on_fast_counters_do_not_overflow() {
+        assert!(!is_overdue(0, u64::MAX / 2, u64::MAX / 2, u32::MAX - 1));
+        assert!(is_overdue(0, u64::MAX, u64::MAX / 1000, 1000));
+    }
+
+    #[test]
+    fn keyboards_without_a_timeout_use_the_default() {
+        assert_eq!(effective_timeout(CAPTURE_TIMEOUT_DEFAULT), DEFAULT_TIMEOUT_MS.load(Ordering::Relaxed));
+        assert_eq!(effective_timeout(250), 250);
+        assert_eq!(effective_timeout(CAPTURE_TIMEOUT_NEVER), CAPTURE_TIMEOUT_NEVER);
+    }
+}